@@ -0,0 +1,43 @@
+create type tier as ENUM (
+    'CHALLENGER',
+    'GRANDMASTER',
+    'MASTER',
+    'DIAMOND',
+    'EMERALD',
+    'PLATINUM',
+    'GOLD',
+    'SILVER',
+    'BRONZE',
+    'IRON'
+);
+
+create type division as ENUM (
+    'I',
+    'II',
+    'III',
+    'IV'
+);
+
+
+create table if not exists users (
+  puuid varchar(255) primary key,
+  created_at timestamptz not null default CURRENT_TIMESTAMP,
+  updated_at timestamptz not null default CURRENT_TIMESTAMP,
+  game_name varchar(255) not null,
+  tag_line varchar(255) not null,
+  -- api key the puuid was encrypted for, puuids differ per key
+  riot_key_id text
+);
+
+-- postgres has no "on update", keep updated_at current with a trigger
+create or replace function set_updated_at() returns trigger as $$
+begin
+  new.updated_at = CURRENT_TIMESTAMP;
+  return new;
+end;
+$$ language plpgsql;
+
+create trigger users_set_updated_at
+  before update on users
+  for each row execute function set_updated_at();
+
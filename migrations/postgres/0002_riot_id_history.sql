@@ -0,0 +1,16 @@
+alter table users
+  add column profile_icon_id integer,
+  add column summoner_level bigint,
+  add column platform varchar(8);
+
+-- every riot id a puuid has been seen with, so renamed players can be followed
+create table if not exists riot_id_history (
+  puuid varchar(255) not null references users (puuid) on delete cascade,
+  game_name varchar(255) not null,
+  tag_line varchar(255) not null,
+  first_seen_at timestamptz not null,
+  last_seen_at timestamptz not null,
+  primary key (puuid, game_name, tag_line)
+);
+
+create index if not exists riot_id_history_riot_id on riot_id_history (lower(game_name), lower(tag_line));
@@ -0,0 +1,17 @@
+-- full matchlist downloads back to a horizon, resumable from cursor
+create table if not exists backfills (
+  puuid varchar(255) primary key references users (puuid) on delete cascade,
+  large_region varchar(16) not null,
+  -- matchlist window in seconds since the epoch, end_time is fixed at creation so offsets stay stable
+  horizon bigint not null,
+  end_time bigint not null,
+  -- matchlist offset of the next page
+  cursor integer not null default 0,
+  matches_stored integer not null default 0,
+  -- game_start_timestamp of the oldest match gone through, milliseconds since the epoch
+  covered_until bigint,
+  status varchar(16) not null,
+  error text,
+  created_at timestamptz not null,
+  updated_at timestamptz not null
+);
@@ -0,0 +1,26 @@
+-- background work, claimed by the workers with skip locked
+create table if not exists jobs (
+  id bigserial primary key,
+  kind varchar(32) not null,
+  payload jsonb not null,
+  -- higher runs first
+  priority integer not null default 0,
+  attempts integer not null default 0,
+  run_after timestamptz not null,
+  -- set while a worker runs the job
+  locked_at timestamptz,
+  last_error text,
+  -- set once a job ran out of attempts, it is kept for inspection
+  failed_at timestamptz,
+  created_at timestamptz not null
+);
+
+-- jobs whose lock timed out are claimed too, so locked ones are indexed
+create index if not exists jobs_claim on jobs (priority desc, run_after) where failed_at is null;
+
+-- pending jobs of a player, polled while their profile is open
+create index if not exists jobs_puuid on jobs ((payload ->> 'puuid')) where failed_at is null;
+
+-- at most one queued or running job per kind and payload
+create unique index if not exists jobs_pending on jobs (kind, payload)
+  where failed_at is null;
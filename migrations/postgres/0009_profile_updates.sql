@@ -0,0 +1,2 @@
+-- last update asked for from the profile, updates are rate limited per player
+alter table users add column if not exists update_requested_at timestamptz;
@@ -0,0 +1,2 @@
+-- last time riot id, summoner and ranks were fetched, profiles older than that are refreshed
+alter table users add column if not exists refreshed_at timestamptz;
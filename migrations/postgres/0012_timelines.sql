@@ -0,0 +1,50 @@
+-- match-v5 timelines, normalized into per minute participant frames and the events worth querying
+create table if not exists match_timelines (
+  match_id varchar(32) primary key references matches (match_id) on delete cascade,
+  -- milliseconds between frames
+  frame_interval bigint not null,
+  ingested_at timestamptz not null
+);
+
+create table if not exists match_frames (
+  match_id varchar(32) not null references match_timelines (match_id) on delete cascade,
+  participant_id integer not null,
+  minute integer not null,
+  current_gold integer not null,
+  total_gold integer not null,
+  xp integer not null,
+  level integer not null,
+  minions_killed integer not null,
+  jungle_minions_killed integer not null,
+  position_x integer,
+  position_y integer,
+  primary key (match_id, participant_id, minute)
+);
+
+-- kills, objectives, item purchases and skill ups
+create table if not exists match_events (
+  id bigserial primary key,
+  match_id varchar(32) not null references match_timelines (match_id) on delete cascade,
+  -- milliseconds since the start of the game
+  game_time bigint not null,
+  event_type varchar(32) not null,
+  -- killer, buyer or leveler, 0 for minions and turrets
+  participant_id integer,
+  victim_id integer,
+  assisting_participant_ids jsonb,
+  -- team of a lost building, killer_team_id the team taking an objective
+  team_id integer,
+  killer_team_id integer,
+  item_id integer,
+  skill_slot integer,
+  monster_type varchar(32),
+  monster_sub_type varchar(32),
+  building_type varchar(32),
+  lane_type varchar(32),
+  tower_type varchar(32),
+  position_x integer,
+  position_y integer
+);
+
+create index if not exists match_events_participant on match_events (match_id, participant_id, event_type);
+create index if not exists match_events_item on match_events (event_type, item_id);
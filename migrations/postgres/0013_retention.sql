@@ -0,0 +1,11 @@
+-- set when the retention policy drops the raw json of a match, raw is then json null
+alter table matches add column if not exists raw_pruned_at timestamptz;
+
+create index if not exists matches_raw_kept on matches (ingested_at) where raw_pruned_at is null;
+create index if not exists match_timelines_ingested on match_timelines (ingested_at);
+
+-- players deleted on request, their matches are stored again without them
+create table if not exists deleted_users (
+  puuid varchar(255) primary key,
+  deleted_at timestamptz not null
+);
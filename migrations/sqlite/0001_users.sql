@@ -0,0 +1,18 @@
+-- sqlite has no enum types, the tier and division enums of the postgres schema are left out
+create table if not exists users (
+  puuid varchar(255) primary key,
+  created_at timestamptz not null default CURRENT_TIMESTAMP,
+  updated_at timestamptz not null default CURRENT_TIMESTAMP,
+  game_name varchar(255) not null,
+  tag_line varchar(255) not null,
+  -- api key the puuid was encrypted for, puuids differ per key
+  riot_key_id text
+);
+
+-- recursive triggers are off, the update inside does not fire it again
+create trigger if not exists users_set_updated_at
+  after update on users
+  for each row when new.updated_at = old.updated_at
+begin
+  update users set updated_at = CURRENT_TIMESTAMP where puuid = new.puuid;
+end;
@@ -0,0 +1,18 @@
+-- one row per distinct rank state, repeated identical fetches only move last_seen_at
+create table if not exists rank_snapshots (
+  id integer primary key autoincrement,
+  puuid varchar(255) not null references users (puuid) on delete cascade,
+  queue_type varchar(32) not null,
+  tier varchar(16) not null,
+  division varchar(4) not null,
+  league_points integer not null,
+  wins integer not null,
+  losses integer not null,
+  first_seen_at timestamptz not null,
+  last_seen_at timestamptz not null
+);
+
+create index if not exists rank_snapshots_puuid_queue on rank_snapshots (puuid, queue_type, first_seen_at);
+
+-- time of the latest league-v4 fetch, the snapshots last seen then are the current ranks
+alter table users add column ranks_fetched_at timestamptz;
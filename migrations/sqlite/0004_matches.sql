@@ -0,0 +1,104 @@
+-- matches as sent by riot, normalized for statistics; raw keeps everything for reprocessing
+create table if not exists matches (
+  match_id varchar(32) primary key,
+  platform_id varchar(8) not null,
+  queue_id integer not null,
+  map_id integer not null,
+  game_mode varchar(32) not null,
+  game_type varchar(32) not null,
+  game_version varchar(32) not null,
+  -- riot timestamps are milliseconds since the epoch, durations seconds
+  game_creation bigint not null,
+  game_start_timestamp bigint not null,
+  game_end_timestamp bigint,
+  game_duration bigint not null,
+  end_of_game_result varchar(32) not null,
+  raw text not null,
+  ingested_at timestamptz not null
+);
+
+create index if not exists matches_queue on matches (queue_id, game_creation);
+-- exports read matches in game_creation order, SQLite a page at a time
+create index if not exists matches_creation on matches (game_creation, match_id);
+
+create table if not exists match_teams (
+  match_id varchar(32) not null references matches (match_id) on delete cascade,
+  team_id integer not null,
+  win boolean not null,
+  baron_kills integer not null,
+  champion_kills integer not null,
+  dragon_kills integer not null,
+  horde_kills integer not null,
+  inhibitor_kills integer not null,
+  rift_herald_kills integer not null,
+  tower_kills integer not null,
+  first_baron boolean not null,
+  first_blood boolean not null,
+  first_dragon boolean not null,
+  first_inhibitor boolean not null,
+  first_rift_herald boolean not null,
+  first_tower boolean not null,
+  primary key (match_id, team_id)
+);
+
+create table if not exists match_bans (
+  match_id varchar(32) not null references matches (match_id) on delete cascade,
+  team_id integer not null,
+  pick_turn integer not null,
+  champion_id integer not null,
+  primary key (match_id, team_id, pick_turn)
+);
+
+create table if not exists match_participants (
+  match_id varchar(32) not null references matches (match_id) on delete cascade,
+  participant_id integer not null,
+  puuid varchar(255) not null,
+  team_id integer not null,
+  riot_id_game_name varchar(255) not null,
+  riot_id_tagline varchar(255) not null,
+  champion_id integer not null,
+  champion_name varchar(32) not null,
+  champ_level integer not null,
+  team_position varchar(16) not null,
+  individual_position varchar(16) not null,
+  win boolean not null,
+  kills integer not null,
+  deaths integer not null,
+  assists integer not null,
+  total_minions_killed integer not null,
+  neutral_minions_killed integer not null,
+  gold_earned integer not null,
+  total_damage_dealt_to_champions integer not null,
+  total_damage_taken integer not null,
+  vision_score integer not null,
+  wards_placed integer not null,
+  wards_killed integer not null,
+  item0 integer not null,
+  item1 integer not null,
+  item2 integer not null,
+  item3 integer not null,
+  item4 integer not null,
+  item5 integer not null,
+  item6 integer not null,
+  summoner1_id integer not null,
+  summoner2_id integer not null,
+  double_kills integer not null,
+  triple_kills integer not null,
+  quadra_kills integer not null,
+  penta_kills integer not null,
+  time_played integer not null,
+  all_in_pings integer not null,
+  assist_me_pings integer not null,
+  command_pings integer not null,
+  enemy_missing_pings integer not null,
+  enemy_vision_pings integer not null,
+  get_back_pings integer not null,
+  hold_pings integer not null,
+  need_vision_pings integer not null,
+  on_my_way_pings integer not null,
+  push_pings integer not null,
+  vision_cleared_pings integer not null,
+  primary key (match_id, participant_id)
+);
+
+create index if not exists match_participants_puuid on match_participants (puuid);
@@ -0,0 +1,9 @@
+-- how far the matchlist of a puuid has been synced, queue_id -1 is all queues
+create table if not exists match_sync_state (
+  puuid varchar(255) not null references users (puuid) on delete cascade,
+  queue_id integer not null,
+  -- game_start_timestamp of the newest synced match, milliseconds since the epoch
+  latest_game_start bigint,
+  last_synced_at timestamptz not null,
+  primary key (puuid, queue_id)
+);
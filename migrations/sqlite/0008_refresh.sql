@@ -0,0 +1,12 @@
+-- players refreshed on a schedule instead of only when their profile is viewed
+alter table users add column tracked_at timestamptz;
+alter table users add column next_refresh_at timestamptz;
+
+create index if not exists users_next_refresh on users (next_refresh_at)
+  where tracked_at is not null;
+
+-- riot requests spent on scheduled refreshes per utc day
+create table if not exists refresh_quota (
+  day date primary key,
+  requests integer not null
+);
@@ -0,0 +1,2 @@
+-- last time riot id, summoner and ranks were fetched, profiles older than that are refreshed
+alter table users add column refreshed_at timestamptz;
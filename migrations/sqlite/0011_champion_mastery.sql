@@ -0,0 +1,23 @@
+-- latest champion-mastery-v4 state per puuid and champion
+create table if not exists champion_masteries (
+  puuid varchar(255) not null references users (puuid) on delete cascade,
+  champion_id integer not null,
+  champion_level integer not null,
+  champion_points integer not null,
+  -- milliseconds since the epoch
+  last_play_time bigint not null,
+  updated_at timestamptz not null,
+  primary key (puuid, champion_id)
+);
+
+-- mastery of a champion at every refresh its points changed, the first row of a champion is its baseline
+create table if not exists champion_mastery_snapshots (
+  id integer primary key autoincrement,
+  puuid varchar(255) not null references users (puuid) on delete cascade,
+  champion_id integer not null,
+  champion_level integer not null,
+  champion_points integer not null,
+  taken_at timestamptz not null
+);
+
+create index if not exists champion_mastery_snapshots_puuid on champion_mastery_snapshots (puuid, champion_id, taken_at);
@@ -0,0 +1,159 @@
+use chrono::{Datelike, NaiveDate, TimeZone, Utc};
+use std::{env, str::FromStr};
+
+use crate::jobs::{self, JobKind};
+use crate::models::Backfill;
+use crate::repository::Store;
+use crate::riot_api::{self, LargeRegion, MatchlistQuery, RiotApiError};
+use crate::riot_client::RiotClient;
+
+const PAGE_SIZE: u32 = 100;
+
+/// How far back backfills go, in seconds since the epoch. BACKFILL_HORIZON
+/// takes a date such as "2025-01-08", the default is the start of the year,
+/// which is about when a season starts.
+pub fn horizon() -> i64 {
+    let configured = env::var("BACKFILL_HORIZON")
+        .ok()
+        .and_then(|date| NaiveDate::parse_from_str(&date, "%Y-%m-%d").ok());
+    let date = configured.unwrap_or(NaiveDate::from_ymd_opt(Utc::now().year(), 1, 1).unwrap());
+    Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap())
+        .timestamp()
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BackfillError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Riot api error: {0}")]
+    Riot(#[from] RiotApiError),
+    #[error("Backfill of {0} has an unknown region")]
+    UnknownRegion(String),
+}
+
+/// Queues the backfill of a PUUID unless it is done. A failed backfill
+/// resumes where it stopped.
+pub async fn ensure_backfill(
+    store: &Store,
+    large_region: &LargeRegion,
+    puuid: &str,
+) -> Result<(), sqlx::Error> {
+    store
+        .matches
+        .create_backfill(puuid, large_region, horizon())
+        .await?;
+    if let Some(backfill) = store.matches.backfill(puuid).await? {
+        if backfill.status != "done" {
+            jobs::enqueue(
+                store,
+                &JobKind::Backfill {
+                    puuid: backfill.puuid,
+                },
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Queues the backfills that were running before their jobs existed.
+pub async fn resume_backfills(store: &Store) -> Result<(), sqlx::Error> {
+    for backfill in store.matches.running_backfills().await? {
+        jobs::enqueue(
+            store,
+            &JobKind::Backfill {
+                puuid: backfill.puuid,
+            },
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Goes through one matchlist page from `cursor`, storing unknown matches and
+/// saving progress after each one. Returns whether pages are left.
+pub async fn run_page(
+    store: &Store,
+    riot_client: &RiotClient,
+    puuid: &str,
+) -> Result<bool, BackfillError> {
+    let Some(mut backfill) = store.matches.backfill(puuid).await? else {
+        return Ok(false);
+    };
+    if backfill.status == "done" {
+        return Ok(false);
+    }
+    let Ok(large_region) = LargeRegion::from_str(&backfill.large_region) else {
+        let error = BackfillError::UnknownRegion(puuid.to_string());
+        backfill.status = "failed".to_string();
+        backfill.error = Some(error.to_string());
+        store.matches.record_backfill_progress(&backfill).await?;
+        return Err(error);
+    };
+    backfill.status = "running".to_string();
+    backfill.error = None;
+    match store_page(store, riot_client, &large_region, &mut backfill).await {
+        Ok(more) => Ok(more),
+        Err(BackfillError::Riot(e)) => {
+            backfill.status = "failed".to_string();
+            backfill.error = Some(e.to_string());
+            store.matches.record_backfill_progress(&backfill).await?;
+            Err(e.into())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+async fn store_page(
+    store: &Store,
+    riot_client: &RiotClient,
+    large_region: &LargeRegion,
+    backfill: &mut Backfill,
+) -> Result<bool, BackfillError> {
+    let page = riot_api::match_v5_matchlist(
+        riot_client,
+        large_region,
+        &backfill.puuid,
+        &MatchlistQuery {
+            start_time: Some(backfill.horizon),
+            end_time: Some(backfill.end_time),
+            start: backfill.cursor as u32,
+            count: PAGE_SIZE,
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    let unknown = store.matches.unknown_match_ids(&page).await?;
+    for match_id in &page {
+        // The page is gone through again on the next run, known matches are skipped
+        if jobs::shutting_down() {
+            return Ok(true);
+        }
+        if !unknown.contains(match_id) {
+            if let Some(game_start) = store.matches.game_start_timestamp(match_id).await? {
+                backfill.covered_until = Some(game_start);
+            }
+            continue;
+        }
+        let (lol_match, raw) = riot_api::match_v5_match(riot_client, large_region, match_id).await?;
+        store.matches.ingest_match(&lol_match, &raw).await?;
+        jobs::enqueue_timeline(store, *large_region, match_id).await?;
+        backfill.matches_stored += 1;
+        backfill.covered_until = Some(lol_match.info.game_start_timestamp);
+        store.matches.record_backfill_progress(backfill).await?;
+    }
+
+    backfill.cursor += page.len() as i32;
+    if (page.len() as u32) < PAGE_SIZE {
+        backfill.status = "done".to_string();
+        store.matches.record_backfill_progress(backfill).await?;
+        println!(
+            "backfill of {} done, {} match(es) stored",
+            backfill.puuid, backfill.matches_stored
+        );
+        return Ok(false);
+    }
+    store.matches.record_backfill_progress(backfill).await?;
+    Ok(true)
+}
@@ -0,0 +1,213 @@
+use actix_web::rt::time::Instant;
+use std::{collections::HashMap, fmt::Write, sync::Mutex, time::Duration};
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0} is unavailable, circuit open")]
+pub struct CircuitOpen(pub String);
+
+#[derive(Default)]
+struct HostState {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    /// Set while a single request checks whether an open host recovered
+    probe_started: Option<Instant>,
+}
+
+/// Tracks failures per Riot routing host. After `failure_threshold` failures
+/// in a row the host is considered down and requests fail fast, until one
+/// probe request per `cooldown` succeeds again.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    hosts: Mutex<HashMap<String, HostState>>,
+    /// Current time, tests drive the breaker with a fake one
+    clock: Box<dyn Fn() -> Instant + Send + Sync>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+            hosts: Mutex::new(HashMap::new()),
+            clock: Box::new(Instant::now),
+        }
+    }
+
+    /// Whether a request to `host` may be sent.
+    pub fn allow(&self, host: &str) -> bool {
+        let mut hosts = self.hosts.lock().unwrap();
+        let Some(state) = hosts.get_mut(host) else {
+            return true;
+        };
+        let Some(opened_at) = state.opened_at else {
+            return true;
+        };
+        let now = (self.clock)();
+        if now.duration_since(opened_at) < self.cooldown {
+            return false;
+        }
+        // A probe that never reported back (cancelled) does not block forever
+        if state
+            .probe_started
+            .is_some_and(|probe_started| now.duration_since(probe_started) < self.cooldown)
+        {
+            return false;
+        }
+        state.probe_started = Some(now);
+        true
+    }
+
+    pub fn record_success(&self, host: &str) {
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(state) = hosts.remove(host) {
+            if state.opened_at.is_some() {
+                println!("riot: {} is back, circuit closed", host);
+            }
+        }
+    }
+
+    pub fn record_failure(&self, host: &str) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let state = hosts.entry(host.to_string()).or_default();
+        state.consecutive_failures += 1;
+        state.probe_started = None;
+        if state.consecutive_failures >= self.failure_threshold {
+            if state.opened_at.is_none() {
+                println!(
+                    "riot: {} failed {} times in a row, circuit opened",
+                    host, state.consecutive_failures
+                );
+            }
+            state.opened_at = Some((self.clock)());
+        }
+    }
+
+    pub fn is_open(&self, host: &str) -> bool {
+        self.hosts
+            .lock()
+            .unwrap()
+            .get(host)
+            .is_some_and(|state| state.opened_at.is_some())
+    }
+
+    /// Hosts with an open circuit in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let mut metrics = String::new();
+        let _ = writeln!(
+            metrics,
+            "# HELP riot_circuit_open Whether requests to a Riot host fail fast"
+        );
+        let _ = writeln!(metrics, "# TYPE riot_circuit_open gauge");
+        for (host, state) in self.hosts.lock().unwrap().iter() {
+            let _ = writeln!(
+                metrics,
+                "riot_circuit_open{{host=\"{}\"}} {}",
+                host,
+                state.opened_at.is_some() as u8
+            );
+        }
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    const HOST: &str = "euw1.api.riotgames.com";
+    const COOLDOWN: Duration = Duration::from_secs(30);
+
+    /// A breaker opening after 3 failures, and the time it sees.
+    fn breaker() -> (CircuitBreaker, Arc<Mutex<Instant>>) {
+        let now = Arc::new(Mutex::new(Instant::now()));
+        let clock = now.clone();
+        let breaker = CircuitBreaker {
+            clock: Box::new(move || *clock.lock().unwrap()),
+            ..CircuitBreaker::new(3, COOLDOWN)
+        };
+        (breaker, now)
+    }
+
+    fn advance(now: &Mutex<Instant>, by: Duration) {
+        *now.lock().unwrap() += by;
+    }
+
+    #[test]
+    fn opens_after_failures_in_a_row() {
+        let (breaker, _) = breaker();
+        breaker.record_failure(HOST);
+        breaker.record_failure(HOST);
+        // A success in between starts the count over
+        breaker.record_success(HOST);
+        breaker.record_failure(HOST);
+        breaker.record_failure(HOST);
+        assert!(breaker.allow(HOST));
+        assert!(!breaker.is_open(HOST));
+        breaker.record_failure(HOST);
+        assert!(breaker.is_open(HOST));
+        assert!(!breaker.allow(HOST));
+        // Other hosts are not affected
+        assert!(breaker.allow("europe.api.riotgames.com"));
+    }
+
+    #[test]
+    fn lets_a_single_probe_through_after_the_cooldown() {
+        let (breaker, now) = breaker();
+        for _ in 0..3 {
+            breaker.record_failure(HOST);
+        }
+        advance(&now, COOLDOWN - Duration::from_secs(1));
+        assert!(!breaker.allow(HOST));
+        advance(&now, Duration::from_secs(1));
+        assert!(breaker.allow(HOST));
+        // Everyone else keeps failing fast while the probe runs
+        assert!(!breaker.allow(HOST));
+        assert!(breaker.is_open(HOST));
+    }
+
+    #[test]
+    fn a_successful_probe_closes_the_circuit() {
+        let (breaker, now) = breaker();
+        for _ in 0..3 {
+            breaker.record_failure(HOST);
+        }
+        advance(&now, COOLDOWN);
+        assert!(breaker.allow(HOST));
+        breaker.record_success(HOST);
+        assert!(!breaker.is_open(HOST));
+        assert!(breaker.allow(HOST));
+        assert!(breaker.allow(HOST));
+    }
+
+    #[test]
+    fn a_failed_probe_reopens_the_circuit_for_another_cooldown() {
+        let (breaker, now) = breaker();
+        for _ in 0..3 {
+            breaker.record_failure(HOST);
+        }
+        advance(&now, COOLDOWN);
+        assert!(breaker.allow(HOST));
+        breaker.record_failure(HOST);
+        assert!(!breaker.allow(HOST));
+        advance(&now, COOLDOWN - Duration::from_secs(1));
+        assert!(!breaker.allow(HOST));
+        advance(&now, Duration::from_secs(1));
+        assert!(breaker.allow(HOST));
+    }
+
+    #[test]
+    fn a_probe_that_never_reports_back_is_replaced_after_the_cooldown() {
+        let (breaker, now) = breaker();
+        for _ in 0..3 {
+            breaker.record_failure(HOST);
+        }
+        advance(&now, COOLDOWN);
+        assert!(breaker.allow(HOST));
+        advance(&now, COOLDOWN - Duration::from_secs(1));
+        assert!(!breaker.allow(HOST));
+        advance(&now, Duration::from_secs(1));
+        assert!(breaker.allow(HOST));
+    }
+}
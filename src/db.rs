@@ -0,0 +1,64 @@
+use sqlx::{
+    postgres::PgPoolOptions,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    PgPool, SqlitePool,
+};
+use std::{str::FromStr, time::Duration};
+
+/// The database the server stores everything in, Postgres or SQLite depending
+/// on the scheme of DATABASE_URL.
+#[derive(Clone)]
+pub enum Db {
+    Postgres(PgPool),
+    Sqlite(SqlitePool),
+}
+
+/// Runs `$body` with `$pool` bound to the pool of whichever database `$db` is,
+/// as in `with_pool!(db, |pool| query.fetch_all(pool).await)`. The body is
+/// compiled once per database, so its SQL has to work on both.
+macro_rules! with_pool {
+    ($db:expr, |$pool:ident| $body:expr) => {
+        match $db {
+            $crate::db::Db::Postgres($pool) => $body,
+            $crate::db::Db::Sqlite($pool) => $body,
+        }
+    };
+}
+pub(crate) use with_pool;
+
+impl Db {
+    /// Connects to a `postgres://` or `sqlite:` url, a SQLite file is created
+    /// when missing. Postgres is given 5 seconds to answer.
+    pub async fn connect(url: &str) -> Result<Db, sqlx::Error> {
+        if url.starts_with("sqlite:") {
+            let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+            // SQLite allows a single writer, one connection saves the workers
+            // from failing on a locked database. It also keeps `sqlite::memory:`
+            // one database rather than one per connection. Queries queue for
+            // the connection, so they get longer than 5 seconds to acquire it.
+            let pool = SqlitePoolOptions::new()
+                .max_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+                .acquire_timeout(Duration::from_secs(30))
+                .connect_with(options)
+                .await?;
+            Ok(Db::Sqlite(pool))
+        } else {
+            let pool = PgPoolOptions::new()
+                .acquire_timeout(Duration::from_secs(5))
+                .connect(url)
+                .await?;
+            Ok(Db::Postgres(pool))
+        }
+    }
+
+    /// Applies the migrations of the database's dialect, embedded at compile
+    /// time from `migrations/postgres` and `migrations/sqlite`.
+    pub async fn migrate(&self) -> Result<(), sqlx::migrate::MigrateError> {
+        match self {
+            Db::Postgres(pool) => sqlx::migrate!("./migrations/postgres").run(pool).await,
+            Db::Sqlite(pool) => sqlx::migrate!("./migrations/sqlite").run(pool).await,
+        }
+    }
+}
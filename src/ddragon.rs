@@ -0,0 +1,534 @@
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    env, fs,
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+const DEFAULT_DDRAGON_DIR: &str = "static/ddragon";
+const ENV_FILE: &str = ".env";
+
+#[derive(Debug, thiserror::Error)]
+pub enum DdragonError {
+    #[error("Io error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Download error: {0}")]
+    DownloadError(#[from] reqwest::Error),
+    #[error("Could not figure out the dragontail version of {0}, pass it explicitly")]
+    UnknownVersion(String),
+    #[error("{0} is not a Data Dragon version like 15.7.1")]
+    InvalidVersion(String),
+    #[error("Archive does not contain a {0}/ directory")]
+    VersionMissingInArchive(String),
+    #[error("Archive entry {0} is not a plain file or directory inside the version")]
+    UnsafeArchiveEntry(PathBuf),
+    #[error("Usage: {0}")]
+    Usage(&'static str),
+}
+
+const USAGE: &str = "lolepic ddragon install <dragontail.tgz | url> [version] [--no-activate]
+       lolepic ddragon prune <keep>
+       lolepic ddragon list";
+
+/// Directory holding one sub directory per installed Data Dragon version.
+pub fn ddragon_dir() -> PathBuf {
+    PathBuf::from(env::var("DDRAGON_DIR").unwrap_or(DEFAULT_DDRAGON_DIR.to_string()))
+}
+
+/// Compares Data Dragon versions ("15.7.1") numerically instead of lexically.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let parse = |v: &str| -> Vec<u32> { v.split('.').map(|p| p.parse().unwrap_or(0)).collect() };
+    parse(a).cmp(&parse(b))
+}
+
+fn is_version(name: &str) -> bool {
+    !name.is_empty()
+        && name.split('.').count() >= 2
+        && name
+            .split('.')
+            .all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Installed versions, oldest first.
+pub fn installed_versions(dir: &Path) -> Vec<String> {
+    let mut versions: Vec<String> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter(|name| is_version(name))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    versions.sort_by(|a, b| compare_versions(a, b));
+    versions
+}
+
+#[derive(Deserialize)]
+struct ChampionFile {
+    data: HashMap<String, ChampionEntry>,
+}
+
+#[derive(Deserialize)]
+struct ChampionEntry {
+    key: String,
+    name: String,
+    image: ImageEntry,
+}
+
+#[derive(Deserialize)]
+struct ImageEntry {
+    full: String,
+}
+
+/// Static data of one installed Data Dragon version.
+pub struct DdragonVersion {
+    pub version: String,
+    /// Champion id to image file name, since `championName` in match data does
+    /// not always match the Data Dragon file name (FiddleSticks).
+    pub champion_images: HashMap<String, String>,
+    /// Champion id to display name
+    pub champion_names: HashMap<String, String>,
+}
+
+impl DdragonVersion {
+    fn load(dir: &Path, version: &str) -> DdragonVersion {
+        let champion_json = dir
+            .join(version)
+            .join("data")
+            .join("en_US")
+            .join("champion.json");
+        let champions: Vec<ChampionEntry> = fs::read_to_string(&champion_json)
+            .ok()
+            .and_then(|contents| serde_json::from_str::<ChampionFile>(&contents).ok())
+            .map(|file| file.data.into_values().collect())
+            .unwrap_or_default();
+        let champion_names = champions
+            .iter()
+            .map(|champion| (champion.key.clone(), champion.name.clone()))
+            .collect();
+        let champion_images = champions
+            .into_iter()
+            .map(|champion| (champion.key, champion.image.full))
+            .collect();
+        DdragonVersion {
+            version: version.to_string(),
+            champion_images,
+            champion_names,
+        }
+    }
+}
+
+/// Every installed Data Dragon version, so matches can be rendered with the
+/// assets of the patch they were played on.
+pub struct StaticData {
+    pub active: DdragonVersion,
+    versions: Vec<DdragonVersion>,
+}
+
+impl StaticData {
+    pub fn load(dir: &Path, active_version: &str) -> StaticData {
+        let versions: Vec<DdragonVersion> = installed_versions(dir)
+            .iter()
+            .filter(|version| version.as_str() != active_version)
+            .map(|version| DdragonVersion::load(dir, version))
+            .collect();
+        println!(
+            "ddragon: active {}, {} other version(s) installed",
+            active_version,
+            versions.len()
+        );
+        StaticData {
+            active: DdragonVersion::load(dir, active_version),
+            versions,
+        }
+    }
+
+    /// Picks the installed version closest to a match `game_version` ("15.7.612.1234"):
+    /// the newest version not newer than the game's patch, else the oldest installed.
+    pub fn for_game_version(&self, game_version: &str) -> &DdragonVersion {
+        let patch = |v: &str| -> (u32, u32) {
+            let mut parts = v.split('.').map(|p| p.parse().unwrap_or(0));
+            (parts.next().unwrap_or(0), parts.next().unwrap_or(0))
+        };
+        let game_patch = patch(game_version);
+        let mut candidates: Vec<&DdragonVersion> =
+            self.versions.iter().chain([&self.active]).collect();
+        candidates.sort_by(|a, b| compare_versions(&a.version, &b.version));
+        candidates
+            .iter()
+            .rev()
+            .find(|candidate| patch(&candidate.version) <= game_patch)
+            .or(candidates.first())
+            .copied()
+            .unwrap_or(&self.active)
+    }
+}
+
+fn version_from_source(source: &str) -> Option<String> {
+    let file_name = source.rsplit('/').next()?;
+    let version = file_name
+        .strip_prefix("dragontail-")?
+        .trim_end_matches(".tgz")
+        .trim_end_matches(".tar.gz");
+    is_version(version).then(|| version.to_string())
+}
+
+/// Local path of the archive, downloads are streamed to `download` first so
+/// the archive is never held in memory.
+async fn fetch_archive(source: &str, download: &Path) -> Result<PathBuf, DdragonError> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        println!("ddragon: downloading {}", source);
+        let mut response = reqwest::get(source).await?.error_for_status()?;
+        let mut file = io::BufWriter::new(fs::File::create(download)?);
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk)?;
+        }
+        file.flush()?;
+        Ok(download.to_path_buf())
+    } else {
+        Ok(PathBuf::from(source))
+    }
+}
+
+/// Unpacks `<version>/` from a dragontail archive into `dir/<version>`.
+/// The archive is extracted next to the target first so a failed install never
+/// leaves a half written version behind.
+fn unpack(archive: impl Read, dir: &Path, version: &str) -> Result<(), DdragonError> {
+    let partial = dir.join(format!(".{}.partial", version));
+    if partial.exists() {
+        fs::remove_dir_all(&partial)?;
+    }
+    fs::create_dir_all(&partial)?;
+
+    let prefix = Path::new(version);
+    let mut found = false;
+    let mut tar = tar::Archive::new(GzDecoder::new(archive));
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let path = path.strip_prefix("./").unwrap_or(&path);
+        if let Ok(relative) = path.strip_prefix(prefix) {
+            found = true;
+            if relative.as_os_str().is_empty() {
+                continue;
+            }
+            // Nothing may end up outside of the version, through `..` or a link
+            let plain =
+                entry.header().entry_type().is_file() || entry.header().entry_type().is_dir();
+            if !plain
+                || !relative
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)))
+            {
+                fs::remove_dir_all(&partial)?;
+                return Err(DdragonError::UnsafeArchiveEntry(path.to_path_buf()));
+            }
+            let target = partial.join(relative);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            entry.unpack(&target)?;
+        }
+    }
+    if !found {
+        fs::remove_dir_all(&partial)?;
+        return Err(DdragonError::VersionMissingInArchive(version.to_string()));
+    }
+
+    let target = dir.join(version);
+    if target.exists() {
+        fs::remove_dir_all(&target)?;
+    }
+    fs::rename(&partial, &target)?;
+    Ok(())
+}
+
+/// Points DDRAGON_VERSION in the .env file at `version`.
+fn activate(version: &str) -> Result<(), DdragonError> {
+    let contents = fs::read_to_string(ENV_FILE).unwrap_or_default();
+    let mut replaced = false;
+    let mut lines: Vec<String> = contents
+        .lines()
+        .map(|line| {
+            if line.starts_with("DDRAGON_VERSION=") {
+                replaced = true;
+                format!("DDRAGON_VERSION={}", version)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect();
+    if !replaced {
+        lines.push(format!("DDRAGON_VERSION={}", version));
+    }
+    fs::write(ENV_FILE, lines.join("\n") + "\n")?;
+    Ok(())
+}
+
+/// Removes all but the `keep` newest versions. The active version is never removed.
+pub fn prune(dir: &Path, keep: usize, active: Option<&str>) -> Result<Vec<String>, DdragonError> {
+    let versions = installed_versions(dir);
+    let mut removed = Vec::new();
+    let old_count = versions.len().saturating_sub(keep);
+    for version in versions.into_iter().take(old_count) {
+        if Some(version.as_str()) == active {
+            continue;
+        }
+        fs::remove_dir_all(dir.join(&version))?;
+        removed.push(version);
+    }
+    Ok(removed)
+}
+
+/// Versions kept by `install`, all of them when DDRAGON_KEEP_VERSIONS is
+/// unset: old matches are rendered with the version of their patch.
+fn keep_versions() -> Option<usize> {
+    env::var("DDRAGON_KEEP_VERSIONS")
+        .ok()
+        .and_then(|keep| keep.parse().ok())
+}
+
+async fn install(args: &[String]) -> Result<(), DdragonError> {
+    let no_activate = args.iter().any(|arg| arg == "--no-activate");
+    let positional: Vec<&String> = args.iter().filter(|arg| !arg.starts_with("--")).collect();
+    let source = positional.first().ok_or(DdragonError::Usage(USAGE))?;
+    let version = match positional.get(1) {
+        // Used as a path below
+        Some(version) if is_version(version) => version.to_string(),
+        Some(version) => return Err(DdragonError::InvalidVersion(version.to_string())),
+        None => version_from_source(source)
+            .ok_or_else(|| DdragonError::UnknownVersion(source.to_string()))?,
+    };
+
+    let dir = ddragon_dir();
+    fs::create_dir_all(&dir)?;
+    let download = dir.join(format!(".{}.tgz.partial", version));
+    let archive = fetch_archive(source, &download).await;
+    let unpacked = archive.and_then(|archive| {
+        println!("ddragon: unpacking {} into {}", version, dir.display());
+        unpack(fs::File::open(archive)?, &dir, &version)
+    });
+    if download.exists() {
+        fs::remove_file(&download)?;
+    }
+    unpacked?;
+
+    let active = if no_activate {
+        env::var("DDRAGON_VERSION").ok()
+    } else {
+        activate(&version)?;
+        println!(
+            "ddragon: DDRAGON_VERSION={} written to {}",
+            version, ENV_FILE
+        );
+        Some(version.clone())
+    };
+    if let Some(keep) = keep_versions() {
+        for removed in prune(&dir, keep, active.as_deref())? {
+            println!("ddragon: pruned {}", removed);
+        }
+    }
+    Ok(())
+}
+
+/// Entry point for `lolepic ddragon ...`.
+pub async fn run_cli(args: &[String]) -> Result<(), DdragonError> {
+    let dir = ddragon_dir();
+    match args.first().map(String::as_str) {
+        Some("install") => install(&args[1..]).await,
+        Some("prune") => {
+            let keep = match args.get(1) {
+                Some(keep) => keep.parse().map_err(|_| DdragonError::Usage(USAGE))?,
+                None => keep_versions().ok_or(DdragonError::Usage(USAGE))?,
+            };
+            let active = env::var("DDRAGON_VERSION").ok();
+            for removed in prune(&dir, keep, active.as_deref())? {
+                println!("ddragon: pruned {}", removed);
+            }
+            Ok(())
+        }
+        Some("list") => {
+            let active = env::var("DDRAGON_VERSION").ok();
+            for version in installed_versions(&dir) {
+                let marker = if Some(&version) == active.as_ref() {
+                    "*"
+                } else {
+                    " "
+                };
+                println!("{} {}", marker, version);
+            }
+            Ok(())
+        }
+        _ => Err(DdragonError::Usage(USAGE)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// An empty directory of its own for each test.
+    fn scratch_dir() -> PathBuf {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dir = env::temp_dir().join(format!(
+            "lolepic-ddragon-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A gzipped tar of `entries`, written as is so unsafe paths make it in.
+    fn archive(entries: &[(&str, tar::EntryType, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+        for (path, entry_type, contents) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_entry_type(*entry_type);
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            if entry_type.is_symlink() {
+                header.set_link_name("/etc/passwd").unwrap();
+            }
+            header.set_cksum();
+            builder.append(&header, *contents).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn unpack_moves_the_version_in_place_once_complete() {
+        let dir = scratch_dir();
+        let archive = archive(&[
+            ("15.7.1/", tar::EntryType::Directory, b""),
+            ("15.7.1/data/champion.json", tar::EntryType::Regular, b"{}"),
+            ("img/champion/Ahri.png", tar::EntryType::Regular, b"png"),
+        ]);
+        unpack(&archive[..], &dir, "15.7.1").unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("15.7.1/data/champion.json")).unwrap(),
+            "{}"
+        );
+        // Only the version is unpacked, and nothing partial is left behind
+        assert!(!dir.join("img").exists());
+        assert!(!dir.join(".15.7.1.partial").exists());
+        assert_eq!(installed_versions(&dir), ["15.7.1"]);
+    }
+
+    #[test]
+    fn unpack_rejects_entries_leaving_the_version() {
+        for entry in [
+            ("15.7.1/../../escaped", tar::EntryType::Regular),
+            ("15.7.1/link", tar::EntryType::Symlink),
+        ] {
+            let dir = scratch_dir();
+            let archive = archive(&[
+                ("15.7.1/data/champion.json", tar::EntryType::Regular, b"{}"),
+                (entry.0, entry.1, b""),
+            ]);
+            let result = unpack(&archive[..], &dir, "15.7.1");
+            assert!(
+                matches!(result, Err(DdragonError::UnsafeArchiveEntry(_))),
+                "{}",
+                entry.0
+            );
+            assert!(!dir.join("15.7.1").exists(), "{}", entry.0);
+            assert!(!dir.join(".15.7.1.partial").exists(), "{}", entry.0);
+            assert!(
+                !dir.parent().unwrap().join("escaped").exists(),
+                "{}",
+                entry.0
+            );
+        }
+    }
+
+    #[test]
+    fn unpack_needs_the_version_in_the_archive() {
+        let dir = scratch_dir();
+        let archive = archive(&[("15.6.1/data/champion.json", tar::EntryType::Regular, b"{}")]);
+        let result = unpack(&archive[..], &dir, "15.7.1");
+        assert!(matches!(
+            result,
+            Err(DdragonError::VersionMissingInArchive(_))
+        ));
+        assert!(installed_versions(&dir).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn install_rejects_explicit_versions_that_are_not_versions() {
+        let args = ["dragontail.tgz".to_string(), "../15.7.1".to_string()];
+        let result = install(&args).await;
+        assert!(matches!(result, Err(DdragonError::InvalidVersion(_))));
+    }
+
+    #[test]
+    fn versions_are_read_from_dragontail_file_names() {
+        assert_eq!(
+            version_from_source("https://ddragon.leagueoflegends.com/cdn/dragontail-15.8.1.tgz"),
+            Some("15.8.1".to_string())
+        );
+        assert_eq!(
+            version_from_source("/tmp/dragontail-12.6.1.tar.gz"),
+            Some("12.6.1".to_string())
+        );
+        assert_eq!(version_from_source("dragontail-latest.tgz"), None);
+        assert_eq!(version_from_source("dragontail-../15.8.1.tgz"), None);
+        assert_eq!(version_from_source("archive.tgz"), None);
+    }
+
+    fn installed(active: &str, others: &[&str]) -> StaticData {
+        let version = |version: &str| DdragonVersion {
+            version: version.to_string(),
+            champion_images: HashMap::new(),
+            champion_names: HashMap::new(),
+        };
+        StaticData {
+            active: version(active),
+            versions: others.iter().map(|other| version(other)).collect(),
+        }
+    }
+
+    #[test]
+    fn matches_use_the_newest_version_not_newer_than_their_patch() {
+        let static_data = installed("15.10.1", &["15.7.1", "15.8.1", "15.9.1"]);
+        let picked = |game_version| static_data.for_game_version(game_version).version.clone();
+        assert_eq!(picked("15.8.612.1234"), "15.8.1");
+        // 15.10 is newer than 15.9 although it sorts before it as text
+        assert_eq!(picked("15.9.700.1"), "15.9.1");
+        assert_eq!(picked("15.10.1.1"), "15.10.1");
+        assert_eq!(picked("16.1.1.1"), "15.10.1");
+        // A patch missing in between falls back to the one before it
+        let static_data = installed("15.10.1", &["15.7.1"]);
+        assert_eq!(static_data.for_game_version("15.9.1.1").version, "15.7.1");
+    }
+
+    #[test]
+    fn matches_older_than_every_version_use_the_oldest() {
+        let static_data = installed("15.10.1", &["15.7.1", "15.8.1"]);
+        assert_eq!(static_data.for_game_version("14.23.1.1").version, "15.7.1");
+        let static_data = installed("15.10.1", &[]);
+        assert_eq!(static_data.for_game_version("14.23.1.1").version, "15.10.1");
+    }
+
+    #[test]
+    fn prune_keeps_the_newest_versions_and_the_active_one() {
+        let dir = scratch_dir();
+        for version in ["15.10.1", "15.9.1", "15.8.1", "15.7.1", "not-a-version"] {
+            fs::create_dir_all(dir.join(version)).unwrap();
+        }
+        let removed = prune(&dir, 2, Some("15.7.1")).unwrap();
+        assert_eq!(removed, ["15.8.1"]);
+        assert_eq!(installed_versions(&dir), ["15.7.1", "15.9.1", "15.10.1"]);
+        assert!(dir.join("not-a-version").exists());
+    }
+}
@@ -0,0 +1,165 @@
+use actix_web::{rt, web};
+use futures::{
+    channel::mpsc,
+    stream::{self, BoxStream},
+    SinkExt, StreamExt,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, Write},
+    str::FromStr,
+};
+use strum::EnumString;
+
+use crate::models::ExportFilter;
+use crate::repository::Store;
+
+/// Bytes gathered before a chunk of the response is sent.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Chunks encoded ahead of a slow client, the database is read no further.
+const CHUNKS_AHEAD: usize = 4;
+
+const USAGE: &str = "lolepic export <matches | participants> <ndjson | csv> [region=EUW1] [queue=420] [patch=15.7] [from=2025-01-01] [to=2025-01-31]";
+
+#[derive(Debug, Clone, Copy, EnumString, Deserialize)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ExportKind {
+    Matches,
+    Participants,
+}
+
+#[derive(Debug, Clone, Copy, EnumString, Deserialize)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    Ndjson,
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExportError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Usage: {0}")]
+    Usage(&'static str),
+}
+
+/// One line per row. CSV starts with a header line, even without rows.
+fn encode_rows<'a, T>(
+    rows: BoxStream<'a, Result<T, sqlx::Error>>,
+    format: ExportFormat,
+) -> BoxStream<'a, Result<Vec<u8>, sqlx::Error>>
+where
+    T: Serialize + Default + Send + 'a,
+{
+    let header = match format {
+        ExportFormat::Ndjson => None,
+        ExportFormat::Csv => {
+            // Serde only tells the column names along with a row
+            let line = csv_line(&T::default(), true);
+            let end = line
+                .iter()
+                .position(|&byte| byte == b'\n')
+                .map_or(0, |end| end + 1);
+            Some(line[..end].to_vec())
+        }
+    };
+    let lines = rows.map(move |row| {
+        row.map(|row| match format {
+            ExportFormat::Ndjson => {
+                let mut line = serde_json::to_vec(&row).expect("rows always serialize");
+                line.push(b'\n');
+                line
+            }
+            ExportFormat::Csv => csv_line(&row, false),
+        })
+    });
+    stream::iter(header.map(Ok)).chain(lines).boxed()
+}
+
+fn csv_line<T: Serialize>(row: &T, header: bool) -> Vec<u8> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(header)
+        .from_writer(Vec::new());
+    writer.serialize(row).expect("rows always serialize");
+    writer.into_inner().expect("writing to a vec cannot fail")
+}
+
+fn encoded<'a>(
+    store: &'a Store,
+    kind: ExportKind,
+    format: ExportFormat,
+    filter: &ExportFilter,
+) -> BoxStream<'a, Result<Vec<u8>, sqlx::Error>> {
+    match kind {
+        ExportKind::Matches => encode_rows(store.matches.export_matches(filter), format),
+        ExportKind::Participants => encode_rows(store.matches.export_participants(filter), format),
+    }
+}
+
+/// Streams an export as the body of a response. Rows are read from the
+/// database as the client takes them, a client going away stops the export.
+pub fn stream(
+    store: Store,
+    kind: ExportKind,
+    format: ExportFormat,
+    filter: ExportFilter,
+) -> mpsc::Receiver<Result<web::Bytes, sqlx::Error>> {
+    let (mut sender, receiver) = mpsc::channel(CHUNKS_AHEAD);
+    rt::spawn(async move {
+        let mut lines = encoded(&store, kind, format, &filter);
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        while let Some(line) = lines.next().await {
+            match line {
+                Ok(line) => chunk.extend(line),
+                Err(e) => {
+                    println!("export: {:?} failed: {:?}", kind, e);
+                    let _ = sender.send(Err(e)).await;
+                    return;
+                }
+            }
+            if chunk.len() >= CHUNK_SIZE {
+                let full = std::mem::replace(&mut chunk, Vec::with_capacity(CHUNK_SIZE));
+                if sender.send(Ok(full.into())).await.is_err() {
+                    return;
+                }
+            }
+        }
+        if !chunk.is_empty() {
+            let _ = sender.send(Ok(chunk.into())).await;
+        }
+    });
+    receiver
+}
+
+/// `lolepic export ...`, writes an export to stdout. Filters are given as
+/// `name=value` like in the query string of `/export`.
+pub async fn run_cli(store: &Store, args: &[String]) -> Result<(), ExportError> {
+    let (Some(kind), Some(format)) = (args.first(), args.get(1)) else {
+        return Err(ExportError::Usage(USAGE));
+    };
+    let kind = ExportKind::from_str(kind).map_err(|_| ExportError::Usage(USAGE))?;
+    let format = ExportFormat::from_str(format).map_err(|_| ExportError::Usage(USAGE))?;
+    let filter = web::Query::<ExportFilter>::from_query(&args[2..].join("&"))
+        .map_err(|_| ExportError::Usage(USAGE))?
+        .into_inner();
+    let mut out = io::BufWriter::new(io::stdout());
+    let mut lines = encoded(store, kind, format, &filter);
+    while let Some(line) = lines.next().await {
+        out.write_all(&line?)?;
+    }
+    out.flush()?;
+    Ok(())
+}
@@ -0,0 +1,383 @@
+use actix_web::rt::{self, task::JoinHandle, time::Instant};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use crate::backfill::{self, BackfillError};
+use crate::match_sync::{self, SyncError};
+use crate::models::Job;
+use crate::repository::Store;
+use crate::retention;
+use crate::riot_api::{self, LargeRegion, Region, RiotApiError};
+use crate::riot_client::RiotClient;
+use crate::riot_scheduler::Priority;
+
+/// Syncs run before backfills, someone is usually looking at the profile.
+const SYNC_PRIORITY: i32 = 10;
+const PROFILE_PRIORITY: i32 = 5;
+const BACKFILL_PRIORITY: i32 = 0;
+/// Timelines are only read for statistics, they can wait the longest.
+const TIMELINE_PRIORITY: i32 = -10;
+const PRUNE_PRIORITY: i32 = -20;
+/// A job failing this many times in a row is given up on.
+const MAX_ATTEMPTS: i32 = 5;
+/// Delay before the first retry, doubled after every failure.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+/// How often an idle worker looks for new jobs.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// A job whose lock was not renewed for this long is taken over, its worker
+/// is gone. Other instances sharing the database keep their jobs meanwhile.
+const JOB_LOCK_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// How often a running job renews its lock.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Work done outside request handlers, stored as the payload of a job.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum JobKind {
+    SyncMatches {
+        large_region: LargeRegion,
+        puuid: String,
+        queue: Option<i32>,
+    },
+    Backfill {
+        puuid: String,
+    },
+    FetchTimeline {
+        large_region: LargeRegion,
+        match_id: String,
+    },
+    /// Riot id, summoner, ranks and champion mastery
+    RefreshProfile {
+        region: Region,
+        puuid: String,
+    },
+    /// Applies the retention policy
+    Prune,
+}
+
+impl JobKind {
+    fn name(&self) -> &'static str {
+        match self {
+            JobKind::SyncMatches { .. } => "sync_matches",
+            JobKind::Backfill { .. } => "backfill",
+            JobKind::FetchTimeline { .. } => "fetch_timeline",
+            JobKind::RefreshProfile { .. } => "refresh_profile",
+            JobKind::Prune => "prune",
+        }
+    }
+
+    fn priority(&self) -> i32 {
+        match self {
+            JobKind::SyncMatches { .. } => SYNC_PRIORITY,
+            JobKind::Backfill { .. } => BACKFILL_PRIORITY,
+            JobKind::FetchTimeline { .. } => TIMELINE_PRIORITY,
+            JobKind::RefreshProfile { .. } => PROFILE_PRIORITY,
+            JobKind::Prune => PRUNE_PRIORITY,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum JobError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Riot api error: {0}")]
+    Riot(#[from] RiotApiError),
+    #[error("Invalid job payload: {0}")]
+    Payload(#[from] serde_json::Error),
+    #[error("{0}")]
+    Sync(#[from] SyncError),
+    #[error("{0}")]
+    Backfill(#[from] BackfillError),
+}
+
+/// What a job asks of its worker once it returns.
+pub enum Outcome {
+    Done,
+    /// Long jobs work in steps and hand their worker back between them, the
+    /// job is queued again to do the next step.
+    Again,
+}
+
+/// Queues a job unless the same one is already queued or running.
+pub async fn enqueue(store: &Store, job: &JobKind) -> Result<(), sqlx::Error> {
+    let payload = serde_json::to_value(job).expect("job payloads always serialize");
+    store
+        .jobs
+        .enqueue_job(job.name(), &payload, job.priority())
+        .await?;
+    Ok(())
+}
+
+/// Queues the jobs bringing a profile up to date: its profile, ranks and new
+/// matches. Deleted players are not updated.
+pub async fn enqueue_update(store: &Store, region: Region, puuid: &str) -> Result<(), sqlx::Error> {
+    if store.users.is_deleted(puuid).await? {
+        return Ok(());
+    }
+    enqueue(
+        store,
+        &JobKind::RefreshProfile {
+            region,
+            puuid: puuid.to_string(),
+        },
+    )
+    .await?;
+    enqueue(
+        store,
+        &JobKind::SyncMatches {
+            large_region: region.large_region(),
+            puuid: puuid.to_string(),
+            queue: None,
+        },
+    )
+    .await
+}
+
+/// Whether an update queued by [`enqueue_update`] is still going.
+pub async fn updating(store: &Store, puuid: &str) -> Result<bool, sqlx::Error> {
+    store
+        .jobs
+        .has_pending_jobs(puuid, &["refresh_profile", "sync_matches"])
+        .await
+}
+
+/// Queues the download of the timeline of a stored match.
+pub async fn enqueue_timeline(
+    store: &Store,
+    large_region: LargeRegion,
+    match_id: &str,
+) -> Result<(), sqlx::Error> {
+    enqueue(
+        store,
+        &JobKind::FetchTimeline {
+            large_region,
+            match_id: match_id.to_string(),
+        },
+    )
+    .await
+}
+
+/// Whether the server is stopping, long jobs check it between steps.
+pub fn shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::Relaxed)
+}
+
+/// Starts `JOB_WORKERS` workers, 4 by default.
+pub async fn start_workers(store: &Store, riot_client: &RiotClient) -> Vec<JoinHandle<()>> {
+    let workers: usize = env::var("JOB_WORKERS")
+        .ok()
+        .and_then(|workers| workers.parse().ok())
+        .unwrap_or(4);
+    (0..workers)
+        .map(|_| {
+            let store = store.clone();
+            let riot_client = riot_client.with_priority(Priority::Background);
+            rt::spawn(async move { work(&store, &riot_client).await })
+        })
+        .collect()
+}
+
+/// Lets the workers finish their current job, for at most `grace`. Jobs still
+/// running after that are picked up again once their lock times out.
+pub async fn shutdown(workers: Vec<JoinHandle<()>>, grace: Duration) {
+    SHUTTING_DOWN.store(true, Ordering::Relaxed);
+    let deadline = Instant::now() + grace;
+    for worker in workers {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if rt::time::timeout(remaining, worker).await.is_err() {
+            println!("jobs: a worker did not stop in time");
+        }
+    }
+}
+
+async fn work(store: &Store, riot_client: &RiotClient) {
+    while !shutting_down() {
+        let locked_before = Utc::now() - JOB_LOCK_TIMEOUT;
+        match store.jobs.claim_job(locked_before).await {
+            Ok(Some(job)) => run(store, riot_client, job).await,
+            Ok(None) => rt::time::sleep(POLL_INTERVAL).await,
+            Err(e) => {
+                println!("jobs: could not claim a job: {:?}", e);
+                rt::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+async fn run(store: &Store, riot_client: &RiotClient, job: Job) {
+    let heartbeat = rt::spawn({
+        let store = store.clone();
+        let id = job.id;
+        async move {
+            loop {
+                rt::time::sleep(HEARTBEAT_INTERVAL).await;
+                if let Err(e) = store.jobs.heartbeat_job(id).await {
+                    println!("jobs: could not renew the lock of job {}: {:?}", id, e);
+                }
+            }
+        }
+    });
+    let result = match serde_json::from_value::<JobKind>(job.payload.clone()) {
+        Ok(kind) => perform(store, riot_client, kind).await,
+        Err(e) => Err(e.into()),
+    };
+    heartbeat.abort();
+    let recorded = match result {
+        Ok(Outcome::Done) => store.jobs.complete_job(job.id).await,
+        Ok(Outcome::Again) => store.jobs.requeue_job(job.id).await,
+        Err(e) => {
+            // A payload that does not deserialize never will
+            let retryable = !matches!(e, JobError::Payload(_));
+            let retry = (retryable && job.attempts < MAX_ATTEMPTS).then(|| {
+                let delay = RETRY_DELAY
+                    .saturating_mul(2u32.saturating_pow(job.attempts as u32 - 1))
+                    .min(MAX_RETRY_DELAY);
+                Utc::now() + delay
+            });
+            match retry {
+                Some(_) => println!("jobs: {} {} failed, will retry: {}", job.kind, job.id, e),
+                None => println!("jobs: {} {} failed for good: {}", job.kind, job.id, e),
+            }
+            store.jobs.fail_job(job.id, &e.to_string(), retry).await
+        }
+    };
+    if let Err(e) = recorded {
+        println!("jobs: could not record the end of job {}: {:?}", job.id, e);
+    }
+}
+
+async fn perform(
+    store: &Store,
+    riot_client: &RiotClient,
+    job: JobKind,
+) -> Result<Outcome, JobError> {
+    match job {
+        JobKind::SyncMatches {
+            large_region,
+            puuid,
+            queue,
+        } => {
+            match match_sync::sync_matches(store, riot_client, &large_region, &puuid, queue).await {
+                Ok(0) | Err(SyncError::AlreadySyncing(_)) => {}
+                Ok(stored) => println!("match sync: {} new match(es) of {}", stored, puuid),
+                Err(e) => return Err(e.into()),
+            }
+            Ok(Outcome::Done)
+        }
+        JobKind::Backfill { puuid } => {
+            match backfill::run_page(store, riot_client, &puuid).await? {
+                true => Ok(Outcome::Again),
+                false => Ok(Outcome::Done),
+            }
+        }
+        JobKind::FetchTimeline {
+            large_region,
+            match_id,
+        } => {
+            if !store.matches.has_timeline(&match_id).await? {
+                let timeline =
+                    riot_api::match_v5_timeline(riot_client, &large_region, &match_id).await?;
+                store.matches.ingest_timeline(&timeline).await?;
+            }
+            Ok(Outcome::Done)
+        }
+        JobKind::RefreshProfile { region, puuid } => {
+            // Queued before the player was deleted
+            if store.users.is_deleted(&puuid).await? {
+                return Ok(Outcome::Done);
+            }
+            let account =
+                riot_api::account_v1_by_puuid(riot_client, &region.large_region(), &puuid).await?;
+            let key_id = riot_client.key_for_puuid(&puuid);
+            store
+                .users
+                .upsert_account(&account, key_id.as_deref())
+                .await?;
+            let summoner = riot_api::summoner_v4(riot_client, &region, &puuid).await?;
+            store.users.upsert_summoner(&region, &summoner).await?;
+            let ranks = riot_api::league_v4(riot_client, &region, &puuid).await?;
+            store.ranks.record_ranks(&puuid, &ranks).await?;
+            let masteries = riot_api::champion_mastery_v4(riot_client, &region, &puuid).await?;
+            store.users.record_masteries(&puuid, &masteries).await?;
+            store.users.mark_refreshed(&puuid).await?;
+            Ok(Outcome::Done)
+        }
+        JobKind::Prune => {
+            retention::prune(store).await?;
+            Ok(Outcome::Done)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    use crate::circuit_breaker::CircuitBreaker;
+    use crate::riot_scheduler::{RiotKey, RiotRatelimits, RiotScheduler};
+
+    fn riot_client() -> RiotClient {
+        RiotClient::new(
+            reqwest::Client::new(),
+            RiotScheduler::new(vec![RiotKey::new("key", RiotRatelimits::default())]),
+            CircuitBreaker::new(5, Duration::from_secs(30)),
+            env::temp_dir().join("lolepic-jobs-cache"),
+        )
+    }
+
+    #[actix_web::test]
+    async fn jobs_whose_payload_does_not_deserialize_fail_for_good_at_once() {
+        let store = Store::memory();
+        let riot_client = riot_client();
+        let payload = json!({"kind": "record_ranks", "puuid": "puuid0"});
+        store
+            .jobs
+            .enqueue_job("record_ranks", &payload, 0)
+            .await
+            .unwrap();
+        let job = store.jobs.claim_job(Utc::now()).await.unwrap().unwrap();
+        assert_eq!(job.attempts, 1);
+        run(&store, &riot_client, job).await;
+
+        // Failed for good, so the same job can be queued again
+        assert!(store
+            .jobs
+            .enqueue_job("record_ranks", &payload, 0)
+            .await
+            .unwrap());
+    }
+
+    #[actix_web::test]
+    async fn profiles_of_players_deleted_while_queued_are_not_refreshed() {
+        let store = Store::memory();
+        let riot_client = riot_client();
+        let job = JobKind::RefreshProfile {
+            region: Region::Euw1,
+            puuid: "puuid0".to_string(),
+        };
+        store.users.delete_user("puuid0").await.unwrap();
+        // Queued jobs go with the player, one already running does not
+        enqueue(&store, &job).await.unwrap();
+        let claimed = store.jobs.claim_job(Utc::now()).await.unwrap().unwrap();
+        run(&store, &riot_client, claimed).await;
+
+        // Done without asking Riot, a failed request would have requeued it
+        let payload = serde_json::to_value(&job).unwrap();
+        assert!(store
+            .jobs
+            .enqueue_job(job.name(), &payload, job.priority())
+            .await
+            .unwrap());
+        assert!(store.users.user_by_puuid("puuid0").await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,308 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fmt::Write;
+
+use crate::models::RankSnapshot;
+use crate::queues;
+
+const WIDTH: f64 = 600.0;
+const HEIGHT: f64 = 200.0;
+const LEFT: f64 = 110.0;
+const RIGHT: f64 = 10.0;
+const TOP: f64 = 10.0;
+const BOTTOM: f64 = 10.0;
+
+const TIERS: [&str; 10] = [
+    "IRON",
+    "BRONZE",
+    "SILVER",
+    "GOLD",
+    "PLATINUM",
+    "EMERALD",
+    "DIAMOND",
+    "MASTER",
+    "GRANDMASTER",
+    "CHALLENGER",
+];
+const DIVISIONS: [&str; 4] = ["IV", "III", "II", "I"];
+/// Master, Grandmaster and Challenger share one LP ladder starting here
+const APEX_POINTS: i32 = 7 * 400;
+
+/// Tier and division as comparable indices, apex tiers have no divisions.
+fn rank_step(tier: &str, division: &str) -> Option<(usize, usize)> {
+    let tier_index = TIERS.iter().position(|t| *t == tier)?;
+    if tier_index >= 7 {
+        return Some((tier_index, 0));
+    }
+    let division_index = DIVISIONS.iter().position(|d| *d == division)?;
+    Some((tier_index, division_index))
+}
+
+/// Position on the ladder, 100 points per division below Master.
+fn ladder_points(snapshot: &RankSnapshot) -> Option<i32> {
+    let (tier_index, division_index) = rank_step(&snapshot.tier, &snapshot.division)?;
+    if tier_index >= 7 {
+        return Some(APEX_POINTS + snapshot.league_points);
+    }
+    Some(tier_index as i32 * 400 + division_index as i32 * 100 + snapshot.league_points)
+}
+
+fn ladder_label(points: i32) -> String {
+    if points >= APEX_POINTS {
+        return format!("{} LP", points - APEX_POINTS);
+    }
+    format!(
+        "{} {}",
+        TIERS[(points / 400) as usize],
+        DIVISIONS[((points % 400) / 100) as usize]
+    )
+}
+
+/// LP progression of one ranked queue.
+#[derive(Serialize)]
+pub struct LpChart {
+    pub queue_name: String,
+    pub svg: String,
+}
+
+/// One chart per queue, ranked queues in the order of [`queues::QUEUES`].
+pub fn lp_charts(snapshots: &[RankSnapshot]) -> Vec<LpChart> {
+    let mut queue_types: Vec<&str> = Vec::new();
+    for snapshot in snapshots {
+        if !queue_types.contains(&snapshot.queue_type.as_str()) {
+            queue_types.push(&snapshot.queue_type);
+        }
+    }
+    queue_types.sort_by_key(|queue_type| {
+        queues::QUEUES
+            .iter()
+            .position(|queue| queue.league_queue_type == Some(*queue_type))
+            .unwrap_or(usize::MAX)
+    });
+    queue_types
+        .into_iter()
+        .filter_map(|queue_type| {
+            let queue_snapshots: Vec<&RankSnapshot> = snapshots
+                .iter()
+                .filter(|snapshot| snapshot.queue_type == queue_type)
+                .collect();
+            Some(LpChart {
+                queue_name: queues::by_league_queue_type(queue_type)
+                    .map(|queue| queue.name.to_string())
+                    .unwrap_or(queue_type.to_string()),
+                svg: lp_chart(&queue_snapshots)?,
+            })
+        })
+        .collect()
+}
+
+/// Line chart of ladder position over time with a marker at every
+/// promotion and demotion. Snapshots must be oldest first.
+pub fn lp_chart(snapshots: &[&RankSnapshot]) -> Option<String> {
+    let ranked: Vec<(&RankSnapshot, i32)> = snapshots
+        .iter()
+        .filter_map(|snapshot| Some((*snapshot, ladder_points(snapshot)?)))
+        .collect();
+    let mut points: Vec<(DateTime<Utc>, i32)> = Vec::new();
+    for (snapshot, ladder) in &ranked {
+        points.push((snapshot.first_seen_at, *ladder));
+        if snapshot.last_seen_at > snapshot.first_seen_at {
+            points.push((snapshot.last_seen_at, *ladder));
+        }
+    }
+    let start = points.first()?.0;
+    let end = points.last()?.0;
+    let span = (end - start).num_seconds().max(1) as f64;
+    let low = points
+        .iter()
+        .map(|(_, ladder)| *ladder)
+        .min()?
+        .div_euclid(100)
+        * 100;
+    let mut high = (points.iter().map(|(_, ladder)| *ladder).max()? + 99).div_euclid(100) * 100;
+    if high <= low {
+        high = low + 100;
+    }
+    let x = |at: DateTime<Utc>| {
+        if points.len() == 1 {
+            return LEFT + (WIDTH - LEFT - RIGHT) / 2.0;
+        }
+        LEFT + (at - start).num_seconds() as f64 / span * (WIDTH - LEFT - RIGHT)
+    };
+    let y = |ladder: i32| {
+        HEIGHT - BOTTOM - (ladder - low) as f64 / (high - low) as f64 * (HEIGHT - TOP - BOTTOM)
+    };
+
+    let mut svg = String::new();
+    let _ = write!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {w} {h}" width="{w}" height="{h}" font-size="11">"#,
+        w = WIDTH,
+        h = HEIGHT
+    );
+    // Grid line per division, per tier when the range is too wide to read
+    let step = if high - low <= 800 { 100 } else { 400 };
+    let mut line = (low + step - 1).div_euclid(step) * step;
+    while line <= high {
+        let _ = write!(
+            svg,
+            r#"<line x1="{x1}" x2="{x2}" y1="{y:.1}" y2="{y:.1}" stroke="lightgray"/><text x="{tx}" y="{ty:.1}" text-anchor="end">{label}</text>"#,
+            x1 = LEFT,
+            x2 = WIDTH - RIGHT,
+            y = y(line),
+            tx = LEFT - 5.0,
+            ty = y(line) + 4.0,
+            label = ladder_label(line)
+        );
+        line += step;
+    }
+    let polyline: Vec<String> = points
+        .iter()
+        .map(|(at, ladder)| format!("{:.1},{:.1}", x(*at), y(*ladder)))
+        .collect();
+    let _ = write!(
+        svg,
+        r#"<polyline points="{}" fill="none" stroke="currentColor" stroke-width="2"/>"#,
+        polyline.join(" ")
+    );
+    for pair in ranked.windows(2) {
+        let (previous, (snapshot, ladder)) = (pair[0].0, pair[1]);
+        let (Some(before), Some(after)) = (
+            rank_step(&previous.tier, &previous.division),
+            rank_step(&snapshot.tier, &snapshot.division),
+        ) else {
+            continue;
+        };
+        if before == after {
+            continue;
+        }
+        let (mx, my) = (x(snapshot.first_seen_at), y(ladder));
+        // Triangle pointing up for promotions, down for demotions
+        let (tip, color, what) = if after > before {
+            (-6.0, "green", "Promoted")
+        } else {
+            (6.0, "red", "Demoted")
+        };
+        let shape = format!(
+            "{:.1},{:.1} {:.1},{:.1} {:.1},{:.1}",
+            mx,
+            my + tip,
+            mx - 5.0,
+            my - tip / 2.0,
+            mx + 5.0,
+            my - tip / 2.0
+        );
+        let _ = write!(
+            svg,
+            r#"<polygon points="{}" fill="{}"><title>{} to {} {} on {}</title></polygon>"#,
+            shape,
+            color,
+            what,
+            snapshot.tier,
+            snapshot.division,
+            snapshot.first_seen_at.format("%Y-%m-%d")
+        );
+    }
+    svg.push_str("</svg>");
+    Some(svg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn snapshot(tier: &str, division: &str, league_points: i32, day: u32) -> RankSnapshot {
+        let seen_at = Utc.with_ymd_and_hms(2025, 4, day, 12, 0, 0).unwrap();
+        RankSnapshot {
+            id: day as i64,
+            queue_type: "RANKED_SOLO_5x5".to_string(),
+            tier: tier.to_string(),
+            division: division.to_string(),
+            league_points,
+            wins: 0,
+            losses: 0,
+            first_seen_at: seen_at,
+            last_seen_at: seen_at,
+        }
+    }
+
+    fn chart(snapshots: &[RankSnapshot]) -> String {
+        lp_chart(&snapshots.iter().collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn ranks_are_placed_on_one_ladder() {
+        assert_eq!(ladder_points(&snapshot("IRON", "IV", 0, 1)), Some(0));
+        assert_eq!(ladder_points(&snapshot("GOLD", "II", 50, 1)), Some(1450));
+        // Apex tiers continue from Diamond I with their LP
+        assert_eq!(ladder_points(&snapshot("DIAMOND", "I", 99, 1)), Some(2799));
+        assert_eq!(ladder_points(&snapshot("MASTER", "I", 120, 1)), Some(2920));
+        assert_eq!(
+            ladder_points(&snapshot("GRANDMASTER", "", 500, 1)),
+            Some(3300)
+        );
+        assert_eq!(ladder_points(&snapshot("GOLD", "V", 0, 1)), None);
+        assert_eq!(ladder_label(1450), "GOLD II");
+        assert_eq!(ladder_label(2920), "120 LP");
+    }
+
+    #[test]
+    fn the_chart_spans_the_whole_divisions_and_period_it_covers() {
+        let svg = chart(&[
+            snapshot("GOLD", "IV", 0, 1),
+            snapshot("GOLD", "IV", 80, 2),
+            snapshot("GOLD", "III", 50, 3),
+        ]);
+        // GOLD IV 0 at the bottom left, GOLD III 50 three quarters up on the right
+        assert!(
+            svg.contains(r#"points="110.0,190.0 350.0,118.0 590.0,55.0""#),
+            "{svg}"
+        );
+        for label in ["GOLD IV", "GOLD III", "GOLD II"] {
+            assert!(svg.contains(&format!(">{}</text>", label)), "{svg}");
+        }
+        assert!(!svg.contains("GOLD I<"), "{svg}");
+    }
+
+    #[test]
+    fn a_single_snapshot_is_centered() {
+        let svg = chart(&[snapshot("SILVER", "I", 40, 1)]);
+        assert!(svg.contains(r#"points="350.0,118.0""#), "{svg}");
+    }
+
+    #[test]
+    fn promotions_and_demotions_are_marked() {
+        let svg = chart(&[
+            snapshot("GOLD", "I", 90, 1),
+            snapshot("GOLD", "I", 99, 2),
+            snapshot("PLATINUM", "IV", 10, 3),
+            snapshot("GOLD", "I", 75, 4),
+        ]);
+        assert_eq!(svg.matches("<polygon").count(), 2, "{svg}");
+        assert!(
+            svg.contains(r#"fill="green"><title>Promoted to PLATINUM IV on 2025-04-03</title>"#),
+            "{svg}"
+        );
+        assert!(
+            svg.contains(r#"fill="red"><title>Demoted to GOLD I on 2025-04-04</title>"#),
+            "{svg}"
+        );
+    }
+
+    #[test]
+    fn wide_ranges_get_a_grid_line_per_tier() {
+        let svg = chart(&[snapshot("IRON", "IV", 0, 1), snapshot("GOLD", "IV", 0, 2)]);
+        for label in ["IRON IV", "BRONZE IV", "SILVER IV", "GOLD IV"] {
+            assert!(svg.contains(&format!(">{}</text>", label)), "{svg}");
+        }
+        assert!(!svg.contains(">IRON III</text>"), "{svg}");
+    }
+
+    #[test]
+    fn unknown_ranks_are_left_out() {
+        let snapshots = [snapshot("UNRANKED", "", 0, 1)];
+        assert!(lp_chart(&snapshots.iter().collect::<Vec<_>>()).is_none());
+        assert!(lp_charts(&snapshots).is_empty());
+    }
+}
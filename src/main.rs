@@ -1,39 +1,69 @@
-use actix_web::{get, post, web, web::Redirect, App, HttpResponse, HttpServer, Responder};
-use riot_api::{LargeRegion, LeagueV4, MatchV5Match, Region};
-use std::{env, str::FromStr};
+use actix_web::rt::time::{timeout, Instant};
+use actix_web::{
+    get, http::header, post, web, web::Redirect, App, HttpRequest, HttpResponse, HttpServer,
+    Responder,
+};
+use riot_api::{ChampionMasteryV4, LargeRegion, LeagueV4, MatchV5Match, Region, RiotApiError};
+use chrono::{TimeDelta, Utc};
+use std::{collections::HashMap, env, fmt, str::FromStr, time::Duration};
 use strum::IntoEnumIterator;
 extern crate dotenv;
 use dotenv::dotenv;
-use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
 use lazy_static::lazy_static;
-use nonzero_ext::nonzero;
 use serde::Deserialize;
 use std::sync::Arc;
 use tera::Tera;
 
+mod backfill;
+mod circuit_breaker;
+mod db;
+mod ddragon;
+mod export;
+mod jobs;
+mod lp_chart;
+mod mastery;
+mod match_sync;
+mod memory;
+mod models;
+mod queues;
+mod refresh;
+mod repository;
+mod retention;
 mod riot_api;
+mod riot_client;
+mod riot_scheduler;
+use circuit_breaker::CircuitBreaker;
+use db::Db;
+use ddragon::StaticData;
+use export::{ExportFormat, ExportKind};
+use repository::Store;
 pub use riot_api::{AccountV1, SummonerV4};
+use jobs::JobKind;
+use riot_client::RiotClient;
+use riot_scheduler::{RiotKey, RiotRatelimits, RiotScheduler};
 
 lazy_static! {
     pub static ref TEMPLATES: Tera = {
         let source = "src/templates/**/*";
-        let tera = Tera::new(source).unwrap();
-        tera
+        Tera::new(source).unwrap()
     };
 }
-#[derive(Clone)]
-pub struct RiotRatelimiters {
-    short_ratelimit: Arc<DefaultDirectRateLimiter>,
-    long_ratelimit: Arc<DefaultDirectRateLimiter>,
-}
 pub struct AppState {
-    reqwest_client: Arc<reqwest::Client>,
-    riot_ratelimiters: RiotRatelimiters,
+    store: Store,
+    riot_client: RiotClient,
+    static_data: Arc<StaticData>,
 }
 
-#[get("/")]
-async fn index() -> impl Responder {
+/// Context every template is rendered with.
+fn template_context(data: &AppState) -> tera::Context {
     let mut context = tera::Context::new();
+    context.insert("ddragon_version", &data.static_data.active.version);
+    context
+}
+
+#[get("/")]
+async fn index(data: web::Data<AppState>) -> impl Responder {
+    let mut context = template_context(&data);
     context.insert("user", "Me moi");
     let mut regions = Vec::new();
     for r in Region::iter() {
@@ -63,133 +93,413 @@ async fn user_loopup(web::Form(form): web::Form<UserForm>) -> impl Responder {
     Redirect::to(format!("/user/{}/{}/{}", form.region, name, tag)).see_other()
 }
 
-#[get("/user/{region}/{name}/{tag}")]
-async fn user(
-    path: web::Path<(String, String, String)>,
-    data: web::Data<AppState>,
-) -> impl Responder {
-    let (region_as_str, name, tag) = path.into_inner();
-    //println!("user: {} - {} - {}", region_as_str, name, tag);
-    let region;
-    match Region::from_str(&region_as_str) {
-        Ok(success) => region = success,
+/// Budget for all Riot calls made while rendering a profile.
+const USER_DEADLINE: Duration = Duration::from_secs(10);
+
+/// How far back the LP charts on a profile go.
+const RANK_HISTORY_DAYS: i64 = 90;
+
+/// Stored profiles older than this are updated in the background when viewed.
+const PROFILE_MAX_AGE: TimeDelta = TimeDelta::minutes(15);
+
+/// Weeks of mastery gains listed on a profile.
+const MASTERY_WEEKS: u32 = 8;
+
+/// How recent the gains of the champions a player is grinding are.
+const GRINDING_DAYS: i64 = 14;
+
+/// Matches listed on a profile.
+const RECENT_MATCHES: i64 = 5;
+
+/// Time between two updates of a player asked for from the profile.
+const UPDATE_COOLDOWN: TimeDelta = TimeDelta::minutes(2);
+
+/// Flattens a Riot call raced against the page deadline, logging why it is missing.
+fn within_deadline<T, E: fmt::Debug>(
+    what: &str,
+    result: Result<Result<T, RiotApiError>, E>,
+) -> Option<T> {
+    match result {
+        Ok(Ok(success)) => Some(success),
+        Ok(Err(e)) => {
+            println!("{} failed: {:?}", what, e);
+            None
+        }
         Err(_) => {
-            let mut context = tera::Context::new();
-            context.insert("error_message", "Region doesnt exists");
-            let page_contents = TEMPLATES.render("error.html", &context).unwrap();
-            return HttpResponse::Ok().body(page_contents);
+            println!("{} missed the deadline", what);
+            None
         }
     }
-    let large_region = match region {
-        Region::Br1 => LargeRegion::Americas,
-        Region::Eun1 => LargeRegion::Europe,
-        Region::Euw1 => LargeRegion::Europe,
-        Region::Jp1 => LargeRegion::Asia,
-        Region::Kr => LargeRegion::Asia,
-        Region::La1 => LargeRegion::Americas,
-        Region::La2 => LargeRegion::Americas,
-        Region::Na1 => LargeRegion::Americas,
-        Region::Oc1 => LargeRegion::Sea,
-        Region::Tr1 => LargeRegion::Europe,
-        Region::Ru => LargeRegion::Europe,
-        Region::Ph2 => LargeRegion::Sea,
-        Region::Sg2 => LargeRegion::Sea,
-        Region::Th2 => LargeRegion::Sea,
-        Region::Tw2 => LargeRegion::Asia,
-        Region::Vn2 => LargeRegion::Asia,
-    };
-    println!("user: {} - {} - {} - {}", large_region, region, name, tag);
+}
 
-    let account_v1: AccountV1;
-    match riot_api::account_v1(
-        data.reqwest_client.clone(),
-        data.riot_ratelimiters.clone(),
-        &large_region,
-        &name,
-        &tag,
-    )
-    .await
-    {
-        Ok(success) => {
-            account_v1 = success;
-        }
-        Err(_err) => {
-            let mut context = tera::Context::new();
-            context.insert("error_message", "Riot won't answer");
-            let page_contents = TEMPLATES.render("error.html", &context).unwrap();
-            return HttpResponse::Ok().body(page_contents);
+/// Profile url of a riot id, percent encoded.
+fn profile_path(region: &str, name: &str, tag: &str) -> String {
+    let mut url = reqwest::Url::parse("http://localhost/user").unwrap();
+    url.path_segments_mut().unwrap().extend([region, name, tag]);
+    url.path().to_string()
+}
+
+/// Where the owner of a riot id that no longer resolves went, if we have seen
+/// them before.
+async fn renamed_profile(
+    data: &AppState,
+    region: &Region,
+    large_region: &LargeRegion,
+    name: &str,
+    tag: &str,
+) -> Option<String> {
+    let known_user = match data.store.users.user_by_former_riot_id(name, tag).await {
+        Ok(known_user) => known_user?,
+        Err(e) => {
+            println!("user_by_former_riot_id failed: {:?}", e);
+            return None;
         }
+    };
+    // The stored riot id may be outdated too, so ask Riot for the current one
+    let account = riot_api::account_v1_by_puuid(&data.riot_client, large_region, &known_user.puuid)
+        .await
+        .ok()?;
+    if account.game_name.eq_ignore_ascii_case(name) && account.tag_line.eq_ignore_ascii_case(tag) {
+        return None;
+    }
+    let key_id = data.riot_client.key_for_puuid(&account.puuid);
+    if let Err(e) = data
+        .store
+        .users
+        .upsert_account(&account, key_id.as_deref())
+        .await
+    {
+        println!("upsert_account failed: {:?}", e);
     }
+    let platform = known_user.platform.unwrap_or(region.to_string());
+    Some(profile_path(
+        &platform,
+        &account.game_name,
+        &account.tag_line,
+    ))
+}
 
-    let summoner_v4: SummonerV4;
-    match riot_api::summoner_v4(
-        data.reqwest_client.clone(),
-        data.riot_ratelimiters.clone(),
-        &region,
-        &account_v1.puuid,
+/// Fetches a player missing from the database: account, summoner and ranks,
+/// within [`USER_DEADLINE`]. Returns the stored user and whether it was
+/// fetched, a renamed player we already know is not. Deleted players are not
+/// found. Errors are the page to answer with.
+async fn look_up_player(
+    data: &AppState,
+    region: &Region,
+    large_region: &LargeRegion,
+    name: &str,
+    tag: &str,
+) -> Result<(models::User, bool), HttpResponse> {
+    let deadline = Instant::now() + USER_DEADLINE;
+    let account_v1: AccountV1 = match timeout(
+        USER_DEADLINE,
+        riot_api::account_v1(&data.riot_client, large_region, name, tag),
     )
     .await
     {
-        Ok(success) => {
-            summoner_v4 = success;
-        }
-        Err(_err) => {
-            let mut context = tera::Context::new();
-            context.insert("error_message", "Riot is confusing");
+        Ok(Ok(success)) => success,
+        _ => {
+            if !data.riot_client.unavailable(large_region) {
+                if let Some(location) =
+                    renamed_profile(data, region, large_region, name, tag).await
+                {
+                    return Err(HttpResponse::SeeOther()
+                        .insert_header((header::LOCATION, location))
+                        .finish());
+                }
+            }
+            let mut context = template_context(data);
+            if data.riot_client.unavailable(large_region) {
+                context.insert("error_message", "Riot is unavailable, try again later");
+            } else {
+                context.insert("error_message", "Riot won't answer");
+            }
             let page_contents = TEMPLATES.render("error.html", &context).unwrap();
-            return HttpResponse::Ok().body(page_contents);
+            return Err(HttpResponse::Ok().body(page_contents));
         }
+    };
+    let database_error = |e: sqlx::Error| {
+        println!("look_up_player failed: {:?}", e);
+        let mut context = template_context(data);
+        context.insert("error_message", "Could not save the player, try again later");
+        let page_contents = TEMPLATES.render("error.html", &context).unwrap();
+        HttpResponse::Ok().body(page_contents)
+    };
+    // Deleted players stay deleted, looking them up does not store them again
+    if data
+        .store
+        .users
+        .is_deleted(&account_v1.puuid)
+        .await
+        .map_err(database_error)?
+    {
+        let mut context = template_context(data);
+        context.insert("error_message", "Player not found");
+        let page_contents = TEMPLATES.render("error.html", &context).unwrap();
+        return Err(HttpResponse::NotFound().body(page_contents));
     }
-
-    let league_v4s: Vec<LeagueV4>;
-    match riot_api::league_v4(
-        data.reqwest_client.clone(),
-        data.riot_ratelimiters.clone(),
-        &region,
-        &account_v1.puuid,
-    )
-    .await
+    let key_id = data.riot_client.key_for_puuid(&account_v1.puuid);
+    data.store
+        .users
+        .upsert_account(&account_v1, key_id.as_deref())
+        .await
+        .map_err(database_error)?;
+    // A riot id we did not know can belong to a player we know by PUUID
+    if let Some(known_user) = data
+        .store
+        .users
+        .user_by_puuid(&account_v1.puuid)
+        .await
+        .map_err(database_error)?
+        .filter(|known_user| known_user.refreshed_at.is_some())
     {
-        Ok(success) => {
-            league_v4s = success;
+        return Ok((known_user, false));
+    }
+
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    let (summoner_v4, league_v4s, masteries) = futures::join!(
+        timeout(
+            remaining,
+            riot_api::summoner_v4(&data.riot_client, region, &account_v1.puuid)
+        ),
+        timeout(
+            remaining,
+            riot_api::league_v4(&data.riot_client, region, &account_v1.puuid)
+        ),
+        timeout(
+            remaining,
+            riot_api::champion_mastery_v4(&data.riot_client, region, &account_v1.puuid)
+        ),
+    );
+    let summoner_v4: Option<SummonerV4> = within_deadline("summoner_v4", summoner_v4);
+    let league_v4s: Option<Vec<LeagueV4>> = within_deadline("league_v4", league_v4s);
+    let masteries: Option<Vec<ChampionMasteryV4>> =
+        within_deadline("champion_mastery_v4", masteries);
+    if let Some(masteries) = &masteries {
+        data.store
+            .users
+            .record_masteries(&account_v1.puuid, masteries)
+            .await
+            .map_err(database_error)?;
+    }
+    if let Some(summoner_v4) = &summoner_v4 {
+        data.store
+            .users
+            .upsert_summoner(region, summoner_v4)
+            .await
+            .map_err(database_error)?;
+    }
+    if let Some(league_v4s) = &league_v4s {
+        data.store
+            .ranks
+            .record_ranks(&account_v1.puuid, league_v4s)
+            .await
+            .map_err(database_error)?;
+        if summoner_v4.is_some() {
+            data.store
+                .users
+                .mark_refreshed(&account_v1.puuid)
+                .await
+                .map_err(database_error)?;
         }
-        Err(_err) => {
-            let mut context = tera::Context::new();
-            context.insert("error_message", "Riot is confusing");
+    }
+    match data.store.users.user_by_puuid(&account_v1.puuid).await {
+        Ok(Some(looked_up)) => Ok((looked_up, true)),
+        Ok(None) => Err(database_error(sqlx::Error::RowNotFound)),
+        Err(e) => Err(database_error(e)),
+    }
+}
+
+#[derive(Deserialize)]
+struct UserQuery {
+    queue: Option<String>,
+}
+
+#[get("/user/{region}/{name}/{tag}")]
+async fn user(
+    path: web::Path<(String, String, String)>,
+    query: web::Query<UserQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let (region_as_str, name, tag) = path.into_inner();
+    let queue_filter: Option<i32> = query.queue.as_deref().and_then(|q| q.parse().ok());
+    //println!("user: {} - {} - {}", region_as_str, name, tag);
+    let region = match Region::from_str(&region_as_str) {
+        Ok(success) => success,
+        Err(_) => {
+            let mut context = template_context(&data);
+            context.insert("error_message", "Region doesnt exists");
             let page_contents = TEMPLATES.render("error.html", &context).unwrap();
             return HttpResponse::Ok().body(page_contents);
         }
+    };
+    let large_region = region.large_region();
+    println!("user: {} - {} - {} - {}", large_region, region, name, tag);
+
+    // Known players render from the database right away, only new ones wait on Riot
+    let stored = match data.store.users.user_by_riot_id(&name, &tag).await {
+        Ok(stored) => stored.filter(|stored| stored.refreshed_at.is_some()),
+        Err(e) => {
+            println!("user_by_riot_id failed: {:?}", e);
+            None
+        }
+    };
+    let (known_user, looked_up) = match stored {
+        Some(stored) => (stored, false),
+        None => match look_up_player(&data, &region, &large_region, &name, &tag).await {
+            Ok(looked_up) => looked_up,
+            Err(response) => return response,
+        },
+    };
+    let puuid = known_user.puuid.clone();
+
+    // Stale profiles show what is stored while an update runs in the background
+    let stale = known_user
+        .refreshed_at
+        .is_none_or(|refreshed_at| Utc::now() - refreshed_at > PROFILE_MAX_AGE);
+    let queued = if looked_up {
+        jobs::enqueue(
+            &data.store,
+            &JobKind::SyncMatches {
+                large_region,
+                puuid: puuid.clone(),
+                queue: None,
+            },
+        )
+        .await
+    } else if stale {
+        jobs::enqueue_update(&data.store, region, &puuid).await
+    } else {
+        Ok(())
+    };
+    if let Err(e) = queued {
+        println!("enqueue update failed: {:?}", e);
+    }
+    let updating = jobs::updating(&data.store, &puuid)
+        .await
+        .unwrap_or_else(|e| {
+            println!("updating failed: {:?}", e);
+            false
+        });
+    if let Err(e) = backfill::ensure_backfill(&data.store, &large_region, &puuid).await {
+        println!("ensure_backfill failed: {:?}", e);
     }
 
-    let matches: Vec<String>;
-    match riot_api::match_v5_matchlist(
-        data.reqwest_client.clone(),
-        data.riot_ratelimiters.clone(),
-        &large_region,
-        &account_v1.puuid,
-    )
-    .await
+    let ranks = data
+        .store
+        .ranks
+        .current_ranks(&puuid)
+        .await
+        .unwrap_or_else(|e| {
+            println!("current_ranks failed: {:?}", e);
+            Vec::new()
+        });
+    let matches = data
+        .store
+        .matches
+        .recent_match_ids(&puuid, queue_filter, RECENT_MATCHES)
+        .await
+        .unwrap_or_else(|e| {
+            println!("recent_match_ids failed: {:?}", e);
+            Vec::new()
+        });
+    let lp_charts = match data
+        .store
+        .ranks
+        .rank_history(&puuid, RANK_HISTORY_DAYS)
+        .await
     {
-        Ok(success) => {
-            matches = success;
-        }
-        Err(_err) => {
-            let mut context = tera::Context::new();
-            context.insert("error_message", "Riot is confusing");
-            let page_contents = TEMPLATES.render("error.html", &context).unwrap();
-            return HttpResponse::Ok().body(page_contents);
+        Ok(rank_history) => lp_chart::lp_charts(&rank_history),
+        Err(e) => {
+            println!("rank_history failed: {:?}", e);
+            Vec::new()
         }
-    }
+    };
+    let now = Utc::now();
+    let mastery_gains = data
+        .store
+        .users
+        .mastery_gains(&puuid, now - TimeDelta::weeks(MASTERY_WEEKS as i64))
+        .await
+        .unwrap_or_else(|e| {
+            println!("mastery_gains failed: {:?}", e);
+            Vec::new()
+        });
+    let weekly_mastery = mastery::weekly_gains(&mastery_gains, now, MASTERY_WEEKS);
+    let grinding = mastery::grinding(&mastery_gains, now - TimeDelta::days(GRINDING_DAYS), 5);
+    let queue_stats = data
+        .store
+        .matches
+        .queue_stats(&puuid)
+        .await
+        .unwrap_or_else(|e| {
+            println!("queue_stats failed: {:?}", e);
+            Vec::new()
+        });
+    let queue_names: HashMap<i32, &str> = queue_stats
+        .iter()
+        .map(|stats| (stats.queue_id, queues::by_id(stats.queue_id).name))
+        .collect();
+    let rank_queue_names: HashMap<&str, &str> = ranks
+        .iter()
+        .map(|rank| {
+            let queue_name = queues::by_league_queue_type(&rank.queue_type)
+                .map(|queue| queue.name)
+                .unwrap_or(&rank.queue_type);
+            (rank.queue_type.as_str(), queue_name)
+        })
+        .collect();
+    let account = AccountV1 {
+        puuid: puuid.clone(),
+        game_name: known_user.game_name.clone(),
+        tag_line: known_user.tag_line.clone(),
+    };
+    let former_riot_ids = data
+        .store
+        .users
+        .former_riot_ids(&account)
+        .await
+        .unwrap_or_else(|e| {
+            println!("former_riot_ids failed: {:?}", e);
+            Vec::new()
+        });
 
-    let mut context = tera::Context::new();
+    let mut context = template_context(&data);
     context.insert("region", &region);
     context.insert("large_region", &large_region);
-    context.insert("name", &name);
-    context.insert("tag", &tag);
-    context.insert("profile_icon_id", &summoner_v4.profile_icon_id);
-    context.insert("lvl", &summoner_v4.summoner_level);
-    context.insert("league_v4s", &league_v4s);
+    context.insert("name", &known_user.game_name);
+    context.insert("tag", &known_user.tag_line);
+    context.insert("puuid", &puuid);
+    context.insert("former_riot_ids", &former_riot_ids);
+    context.insert("tracked", &known_user.tracked_at.is_some());
+    context.insert("profile_path", &profile_path(&region_as_str, &name, &tag));
+    context.insert("updating", &updating);
+    if stale {
+        if let Some(refreshed_at) = known_user.refreshed_at {
+            context.insert("updated_minutes_ago", &(Utc::now() - refreshed_at).num_minutes());
+        }
+    }
+    context.insert("profile_unavailable", &known_user.profile_icon_id.is_none());
+    context.insert("profile_icon_id", &known_user.profile_icon_id);
+    context.insert("lvl", &known_user.summoner_level);
+    context.insert("ranks_unavailable", &known_user.refreshed_at.is_none());
+    context.insert("ranks", &ranks);
+    context.insert("rank_queue_names", &rank_queue_names);
+    context.insert("lp_charts", &lp_charts);
+    context.insert("queue_stats", &queue_stats);
+    context.insert("queue_names", &queue_names);
+    context.insert("mastery_gained", &!mastery_gains.is_empty());
+    context.insert("weekly_mastery", &weekly_mastery);
+    context.insert("grinding", &grinding);
+    context.insert("champion_images", &data.static_data.active.champion_images);
+    context.insert("champion_names", &data.static_data.active.champion_names);
     context.insert("matches", &matches);
+    context.insert("queues", &queues::filterable());
+    context.insert("queue_filter", &queue_filter);
+    context.insert(
+        "riot_unavailable",
+        &(data.riot_client.unavailable(&region) || data.riot_client.unavailable(&large_region)),
+    );
 
     match TEMPLATES.render("user.html", &context) {
         Ok(page_contents) => HttpResponse::Ok().body(page_contents),
@@ -200,43 +510,66 @@ async fn user(
     }
 }
 
+/// A match from the database, fetched from Riot and stored on first view.
+async fn load_match(
+    data: &AppState,
+    large_region: &LargeRegion,
+    match_id: &str,
+) -> Result<MatchV5Match, RiotApiError> {
+    match data.store.matches.stored_match(match_id).await {
+        Ok(Some(raw)) => match MatchV5Match::deserialize(&raw) {
+            Ok(stored) => return Ok(stored),
+            Err(e) => println!("stored match {} unreadable: {:?}", match_id, e),
+        },
+        Ok(None) => {}
+        Err(e) => println!("stored_match failed: {:?}", e),
+    }
+    let (fetched, raw) =
+        riot_api::match_v5_match(&data.riot_client, large_region, match_id).await?;
+    if let Err(e) = data.store.matches.ingest_match(&fetched, &raw).await {
+        println!("ingest_match failed: {:?}", e);
+    } else if let Err(e) = jobs::enqueue_timeline(&data.store, *large_region, match_id).await {
+        println!("enqueue_timeline failed: {:?}", e);
+    }
+    Ok(fetched)
+}
+
 #[get("/match/{large_region}/{match_id}")]
 async fn lol_match(path: web::Path<(String, String)>, data: web::Data<AppState>) -> impl Responder {
     let (large_region_as_str, match_id) = path.into_inner();
-    let large_region;
-    match LargeRegion::from_str(&large_region_as_str) {
-        Ok(success) => large_region = success,
+    let large_region = match LargeRegion::from_str(&large_region_as_str) {
+        Ok(success) => success,
         Err(_) => {
-            let mut context = tera::Context::new();
+            let mut context = template_context(&data);
             context.insert("error_message", "Large region doesnt exists");
             let page_contents = TEMPLATES.render("error.html", &context).unwrap();
             return HttpResponse::Ok().body(page_contents);
         }
-    }
+    };
     println!("{:?}", match_id);
 
-    let lol_match: MatchV5Match;
-    match riot_api::match_v5_match(
-        data.reqwest_client.clone(),
-        data.riot_ratelimiters.clone(),
-        &large_region,
-        &match_id,
-    )
-    .await
-    {
-        Ok(success) => {
-            lol_match = success;
-        }
+    let lol_match: MatchV5Match = match load_match(&data, &large_region, &match_id).await {
+        Ok(success) => success,
         Err(_err) => {
-            let mut context = tera::Context::new();
-            context.insert("error_message", "Riot is confusing");
+            let mut context = template_context(&data);
+            if data.riot_client.unavailable(&large_region) {
+                context.insert("error_message", "Riot is unavailable, try again later");
+            } else {
+                context.insert("error_message", "Riot is confusing");
+            }
             let page_contents = TEMPLATES.render("error.html", &context).unwrap();
             return HttpResponse::Ok().body(page_contents);
         }
-    }
+    };
 
-    let mut context = tera::Context::new();
+    let ddragon = data
+        .static_data
+        .for_game_version(&lol_match.info.game_version);
+    let mut context = template_context(&data);
+    context.insert("ddragon_version", &ddragon.version);
+    context.insert("champion_images", &ddragon.champion_images);
     context.insert("match_id", &match_id);
+    context.insert("queue", queues::by_id(lol_match.info.queue_id));
     context.insert("lol_match", &lol_match);
 
     match TEMPLATES.render("match.html", &context) {
@@ -248,26 +581,315 @@ async fn lol_match(path: web::Path<(String, String)>, data: web::Data<AppState>)
     }
 }
 
+/// Progress bar of a backfill, polled by the profile page until it is done.
+#[get("/backfill/{puuid}")]
+async fn backfill_progress(path: web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    let puuid = path.into_inner();
+    let backfill = match data.store.matches.backfill(&puuid).await {
+        Ok(Some(backfill)) => backfill,
+        Ok(None) => return HttpResponse::Ok().finish(),
+        Err(e) => {
+            println!("backfill failed: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let mut context = template_context(&data);
+    context.insert("percent", &backfill.percent());
+    context.insert("backfill", &backfill);
+    match TEMPLATES.render("backfill.html", &context) {
+        Ok(page_contents) => HttpResponse::Ok().body(page_contents),
+        Err(e) => {
+            println!("{:?}", e);
+            HttpResponse::NotFound().finish()
+        }
+    }
+}
+
+/// Lets requests with `Authorization: Bearer <$token_var>` through. Without
+/// the env var set the endpoint is disabled and answers 404.
+fn require_token(req: &HttpRequest, token_var: &str) -> Result<(), HttpResponse> {
+    let Some(token) = env::var(token_var).ok().filter(|token| !token.is_empty()) else {
+        return Err(HttpResponse::NotFound().finish());
+    };
+    let given = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    // Compares every byte, so the time taken does not tell how much matched
+    let same = given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |differences, (a, b)| differences | (a ^ b))
+            == 0;
+    if !same {
+        return Err(HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+            .finish());
+    }
+    Ok(())
+}
+
+/// Starts or stops refreshing a player on a schedule. Tracking spends the
+/// refresh quota and keeps matches from the retention policy, so it takes
+/// ADMIN_TOKEN.
+#[post("/track/{puuid}/{tracked}")]
+async fn track(
+    req: HttpRequest,
+    path: web::Path<(String, bool)>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    if let Err(response) = require_token(&req, "ADMIN_TOKEN") {
+        return response;
+    }
+    let (puuid, tracked) = path.into_inner();
+    if let Err(e) = data.store.users.set_tracked(&puuid, tracked).await {
+        println!("set_tracked failed: {:?}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::NoContent().finish()
+}
+
+/// Update button of a profile in `state` "idle", "running" or "cooldown".
+fn render_update(
+    data: &AppState,
+    region: &Region,
+    puuid: &str,
+    state: &str,
+    wait_secs: i64,
+) -> HttpResponse {
+    let mut context = template_context(data);
+    context.insert("region", region);
+    context.insert("puuid", puuid);
+    context.insert("state", state);
+    context.insert("wait_secs", &wait_secs);
+    match TEMPLATES.render("update.html", &context) {
+        Ok(page_contents) => HttpResponse::Ok().body(page_contents),
+        Err(e) => {
+            println!("{:?}", e);
+            HttpResponse::NotFound().finish()
+        }
+    }
+}
+
+/// Queues a sync of the ranks and matches of a player, at most once per
+/// [`UPDATE_COOLDOWN`].
+#[post("/update/{region}/{puuid}")]
+async fn update_profile(
+    path: web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let (region_as_str, puuid) = path.into_inner();
+    let Ok(region) = Region::from_str(&region_as_str) else {
+        return HttpResponse::BadRequest().finish();
+    };
+    match data
+        .store
+        .users
+        .request_update(&puuid, UPDATE_COOLDOWN)
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            let requested_at = data
+                .store
+                .users
+                .user_by_puuid(&puuid)
+                .await
+                .ok()
+                .flatten()
+                .and_then(|known_user| known_user.update_requested_at)
+                .unwrap_or(Utc::now());
+            let wait = requested_at + UPDATE_COOLDOWN - Utc::now();
+            return render_update(&data, &region, &puuid, "cooldown", wait.num_seconds().max(1));
+        }
+        Err(e) => {
+            println!("request_update failed: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+    if let Err(e) = jobs::enqueue_update(&data.store, region, &puuid).await {
+        println!("enqueue_update failed: {:?}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    render_update(&data, &region, &puuid, "running", 0)
+}
+
+/// Polled while an update runs. Once it is done the profile is told to
+/// reload through the profile-updated event.
+#[get("/update/{region}/{puuid}")]
+async fn update_status(
+    path: web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let (region_as_str, puuid) = path.into_inner();
+    let Ok(region) = Region::from_str(&region_as_str) else {
+        return HttpResponse::BadRequest().finish();
+    };
+    match jobs::updating(&data.store, &puuid).await {
+        Ok(true) => render_update(&data, &region, &puuid, "running", 0),
+        Ok(false) => {
+            let mut response = render_update(&data, &region, &puuid, "idle", 0);
+            response.headers_mut().insert(
+                header::HeaderName::from_static("hx-trigger"),
+                header::HeaderValue::from_static("profile-updated"),
+            );
+            response
+        }
+        Err(e) => {
+            println!("updating failed: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/metrics")]
+async fn metrics(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(
+            data.riot_client.scheduler().metrics() + &data.riot_client.circuit_breaker().metrics(),
+        )
+}
+
+/// Streams stored matches or their participants, see "Export" in the readme.
+#[get("/export/{kind}.{format}")]
+async fn bulk_export(
+    req: HttpRequest,
+    path: web::Path<(ExportKind, ExportFormat)>,
+    filter: web::Query<models::ExportFilter>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    if let Err(response) = require_token(&req, "EXPORT_TOKEN") {
+        return response;
+    }
+    let (kind, format) = path.into_inner();
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"{}.{}\"",
+                req.match_info().query("kind"),
+                req.match_info().query("format")
+            ),
+        ))
+        .streaming(export::stream(data.store.clone(), kind, format, filter.into_inner()))
+}
+
+/// Connects to DATABASE_URL and applies the migrations, or keeps everything in
+/// memory for `memory:`. Exits when the database is unusable.
+async fn open_store() -> Store {
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set in .env");
+    if database_url == "memory:" {
+        println!("DATABASE_URL is memory:, nothing is persisted");
+        Store::memory()
+    } else {
+        let db = match Db::connect(&database_url).await {
+            Ok(db) => db,
+            Err(e) => {
+                eprintln!("could not connect to the database at DATABASE_URL: {}", e);
+                std::process::exit(1);
+            }
+        };
+        if let Err(e) = db.migrate().await {
+            eprintln!("database migrations failed: {}", e);
+            std::process::exit(1);
+        }
+        Store::sql(db)
+    }
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv::from_filename(".env.secret").ok();
     dotenv().ok();
-    let _riot_api_key = env::var("RIOT_API_KEY").expect("RIOT_API_KEY not set in .env");
 
-    //let postgres_url = env::var("POSTGRES_URL").expect("POSTGRES_URL not set in .env");
-    //let postgres_pool = sqlx::PgPool::connect(&postgres_url).await?;
-    HttpServer::new(|| {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("ddragon") {
+        if let Err(e) = ddragon::run_cli(&args[1..]).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if args.first().map(String::as_str) == Some("export") {
+        if let Err(e) = export::run_cli(&open_store().await, &args[1..]).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if args.first().map(String::as_str) == Some("retention") {
+        if let Err(e) = retention::run_cli(&open_store().await, &args[1..]).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // RIOT_API_KEYS is a comma separated list, the first key is the primary one
+    let riot_api_keys = env::var("RIOT_API_KEYS")
+        .or(env::var("RIOT_API_KEY"))
+        .expect("RIOT_API_KEYS or RIOT_API_KEY not set in .env");
+    let ratelimits = RiotRatelimits::from_env();
+    let riot_keys: Vec<RiotKey> = riot_api_keys
+        .split(',')
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(|key| RiotKey::new(key, ratelimits))
+        .collect();
+    let ddragon_version = env::var("DDRAGON_VERSION").expect("DDRAGON_VERSION not set in .env");
+    if !ddragon::ddragon_dir().join(&ddragon_version).is_dir() {
+        println!(
+            "warning: ddragon {} is not installed, run `lolepic ddragon install`",
+            ddragon_version
+        );
+    }
+    let static_data = Arc::new(StaticData::load(&ddragon::ddragon_dir(), &ddragon_version));
+    let http_cache_dir = env::var("HTTP_CACHE_DIR").unwrap_or("cache".to_string());
+    let env_secs = |name: &str, default: u64| {
+        Duration::from_secs(
+            env::var(name)
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .unwrap_or(default),
+        )
+    };
+    let reqwest_client = reqwest::Client::builder()
+        .connect_timeout(env_secs("RIOT_CONNECT_TIMEOUT_SECS", 3))
+        .timeout(env_secs("RIOT_TIMEOUT_SECS", 10))
+        .build()
+        .expect("could not build the http client");
+    let circuit_breaker = CircuitBreaker::new(
+        env::var("RIOT_BREAKER_FAILURES")
+            .ok()
+            .and_then(|failures| failures.parse().ok())
+            .unwrap_or(5),
+        env_secs("RIOT_BREAKER_COOLDOWN_SECS", 30),
+    );
+    let store = open_store().await;
+    let riot_client = RiotClient::new(
+        reqwest_client,
+        RiotScheduler::new(riot_keys),
+        circuit_breaker,
+        http_cache_dir.into(),
+    )
+    .with_key_pins(store.users.clone());
+    if let Err(e) = backfill::resume_backfills(&store).await {
+        println!("could not resume backfills: {:?}", e);
+    }
+    let workers = jobs::start_workers(&store, &riot_client).await;
+    refresh::spawn_scheduler(store.clone());
+    retention::spawn_scheduler(store.clone());
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(AppState {
-                reqwest_client: Arc::new(reqwest::Client::new()),
-                riot_ratelimiters: RiotRatelimiters {
-                    short_ratelimit: Arc::new(RateLimiter::direct(Quota::per_second(nonzero!(
-                        20u32
-                    )))),
-                    long_ratelimit: Arc::new(RateLimiter::direct(Quota::per_minute(nonzero!(
-                        120u32
-                    )))),
-                },
+                store: store.clone(),
+                riot_client: riot_client.clone(),
+                static_data: static_data.clone(),
             }))
             .service(
                 actix_files::Files::new("/static", "./static")
@@ -278,8 +900,16 @@ async fn main() -> std::io::Result<()> {
             .service(user_loopup)
             .service(user)
             .service(lol_match)
+            .service(backfill_progress)
+            .service(track)
+            .service(update_profile)
+            .service(update_status)
+            .service(metrics)
+            .service(bulk_export)
     })
     .bind(("0.0.0.0", 8080))?
     .run()
-    .await
+    .await;
+    jobs::shutdown(workers, env_secs("JOB_SHUTDOWN_GRACE_SECS", 10)).await;
+    server
 }
@@ -0,0 +1,112 @@
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc, Weekday};
+use serde::Serialize;
+use std::{cmp::Reverse, collections::HashMap};
+
+use crate::models::MasteryGain;
+
+/// Mastery points gained over a week starting on Monday.
+#[derive(Serialize)]
+pub struct WeeklyGain {
+    pub week: NaiveDate,
+    pub points: i64,
+}
+
+/// A champion by points gained recently.
+#[derive(Serialize)]
+pub struct Grind {
+    pub champion_id: i32,
+    pub points: i64,
+}
+
+/// Points gained per week, the latest `weeks` weeks up to `now`, newest
+/// first. Weeks without gains are listed with 0.
+pub fn weekly_gains(gains: &[MasteryGain], now: DateTime<Utc>, weeks: u32) -> Vec<WeeklyGain> {
+    let week_of = |at: DateTime<Utc>| at.date_naive().week(Weekday::Mon).first_day();
+    let mut points: HashMap<NaiveDate, i64> = HashMap::new();
+    for gain in gains {
+        *points.entry(week_of(gain.taken_at)).or_default() += gain.points as i64;
+    }
+    (0..weeks)
+        .map(|ago| {
+            let week = week_of(now - TimeDelta::weeks(ago as i64));
+            WeeklyGain {
+                week,
+                points: points.get(&week).copied().unwrap_or(0),
+            }
+        })
+        .collect()
+}
+
+/// Champions with the most points gained since `since`, at most `limit`.
+pub fn grinding(gains: &[MasteryGain], since: DateTime<Utc>, limit: usize) -> Vec<Grind> {
+    let mut points: HashMap<i32, i64> = HashMap::new();
+    for gain in gains.iter().filter(|gain| gain.taken_at >= since) {
+        *points.entry(gain.champion_id).or_default() += gain.points as i64;
+    }
+    let mut grinds: Vec<Grind> = points
+        .into_iter()
+        .filter(|(_, points)| *points > 0)
+        .map(|(champion_id, points)| Grind {
+            champion_id,
+            points,
+        })
+        .collect();
+    grinds.sort_by_key(|grind| Reverse(grind.points));
+    grinds.truncate(limit);
+    grinds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gain(champion_id: i32, taken_at: &str, points: i32) -> MasteryGain {
+        MasteryGain {
+            champion_id,
+            taken_at: taken_at.parse().unwrap(),
+            points,
+        }
+    }
+
+    #[test]
+    fn gains_are_summed_per_week_starting_on_monday() {
+        let gains = [
+            gain(1, "2025-02-20T12:00:00Z", 7),
+            gain(1, "2025-03-09T23:30:00Z", 40),
+            gain(2, "2025-03-10T00:30:00Z", 100),
+            gain(1, "2025-03-11T18:00:00Z", 20),
+        ];
+        let now = "2025-03-12T08:00:00Z".parse().unwrap();
+        let weeks: Vec<(String, i64)> = weekly_gains(&gains, now, 3)
+            .iter()
+            .map(|week| (week.week.to_string(), week.points))
+            .collect();
+        assert_eq!(
+            weeks,
+            [
+                ("2025-03-10".to_string(), 120),
+                ("2025-03-03".to_string(), 40),
+                ("2025-02-24".to_string(), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn grinding_ranks_champions_by_points_gained_since() {
+        let gains = [
+            gain(1, "2025-03-01T12:00:00Z", 900),
+            gain(1, "2025-03-10T12:00:00Z", 50),
+            gain(2, "2025-03-10T12:00:00Z", 300),
+            gain(3, "2025-03-11T12:00:00Z", 200),
+            gain(1, "2025-03-11T12:00:00Z", 60),
+            gain(4, "2025-03-11T12:00:00Z", 0),
+        ];
+        let since = "2025-03-05T00:00:00Z".parse().unwrap();
+        let grinds: Vec<(i32, i64)> = grinding(&gains, since, 2)
+            .iter()
+            .map(|grind| (grind.champion_id, grind.points))
+            .collect();
+        assert_eq!(grinds, [(2, 300), (3, 200)]);
+        assert_eq!(grinding(&gains, since, 5).len(), 3);
+    }
+}
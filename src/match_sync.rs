@@ -0,0 +1,126 @@
+use lazy_static::lazy_static;
+use std::{collections::HashSet, sync::Mutex};
+
+use crate::jobs;
+use crate::repository::Store;
+use crate::riot_api::{self, LargeRegion, MatchlistQuery, RiotApiError};
+use crate::riot_client::RiotClient;
+
+/// Sync state key of a sync over every queue.
+pub const ALL_QUEUES: i32 = -1;
+/// A player synced for the first time only gets their latest matches, older
+/// ones are left to a backfill.
+const INITIAL_SYNC_MATCHES: u32 = 20;
+/// Most match ids Riot returns per matchlist page.
+const MATCHLIST_PAGE: u32 = 100;
+
+lazy_static! {
+    static ref SYNCING: Mutex<HashSet<(String, i32)>> = Mutex::new(HashSet::new());
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SyncError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Riot api error: {0}")]
+    Riot(#[from] RiotApiError),
+    #[error("Matches of {0} are already being synced")]
+    AlreadySyncing(String),
+}
+
+/// Removes a sync from [`SYNCING`] when it ends, however it ends.
+struct SyncGuard((String, i32));
+
+impl SyncGuard {
+    fn new(puuid: &str, queue_id: i32) -> Result<Self, SyncError> {
+        let key = (puuid.to_string(), queue_id);
+        if !SYNCING.lock().unwrap().insert(key.clone()) {
+            return Err(SyncError::AlreadySyncing(puuid.to_string()));
+        }
+        Ok(SyncGuard(key))
+    }
+}
+
+impl Drop for SyncGuard {
+    fn drop(&mut self) {
+        SYNCING.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Downloads the matches of `puuid` played since its last sync and returns
+/// how many were stored. Matches already stored, for instance through another
+/// player, are not downloaded again.
+pub async fn sync_matches(
+    store: &Store,
+    riot_client: &RiotClient,
+    large_region: &LargeRegion,
+    puuid: &str,
+    queue: Option<i32>,
+) -> Result<usize, SyncError> {
+    let queue_id = queue.unwrap_or(ALL_QUEUES);
+    let _guard = SyncGuard::new(puuid, queue_id)?;
+    let latest_game_start = match store.matches.sync_state(puuid, queue_id).await? {
+        Some(latest_game_start) => Some(latest_game_start),
+        None => store.matches.latest_stored_game_start(puuid, queue).await?,
+    };
+
+    // startTime is inclusive, so the newest known match comes back too and is
+    // filtered out with the other known ones. Every match since the last sync
+    // is listed, the sync state moves past all of them.
+    let limit = match latest_game_start {
+        Some(_) => u32::MAX,
+        None => INITIAL_SYNC_MATCHES,
+    };
+    let mut match_ids: Vec<String> = Vec::new();
+    while (match_ids.len() as u32) < limit {
+        let count = (limit - match_ids.len() as u32).min(MATCHLIST_PAGE);
+        let page = riot_api::match_v5_matchlist(
+            riot_client,
+            large_region,
+            puuid,
+            &MatchlistQuery {
+                queue,
+                start_time: latest_game_start.map(|latest| latest / 1000),
+                start: match_ids.len() as u32,
+                count,
+                ..Default::default()
+            },
+        )
+        .await?;
+        let last_page = (page.len() as u32) < count;
+        match_ids.extend(page);
+        if last_page {
+            break;
+        }
+    }
+
+    // Oldest first, so a failed download never leaves a gap behind the sync state
+    let unknown = store.matches.unknown_match_ids(&match_ids).await?;
+    let mut newest = latest_game_start;
+    let mut stored = 0;
+    for match_id in unknown.iter().rev() {
+        let (lol_match, raw) =
+            match riot_api::match_v5_match(riot_client, large_region, match_id).await {
+                Ok(downloaded) => downloaded,
+                Err(e) => {
+                    store
+                        .matches
+                        .record_sync_state(puuid, queue_id, newest)
+                        .await?;
+                    return Err(e.into());
+                }
+            };
+        store.matches.ingest_match(&lol_match, &raw).await?;
+        jobs::enqueue_timeline(store, *large_region, match_id).await?;
+        newest = newest.max(Some(lol_match.info.game_start_timestamp));
+        stored += 1;
+    }
+    if let Some(newest_known) = store.matches.latest_stored_game_start(puuid, queue).await? {
+        newest = newest.max(Some(newest_known));
+    }
+    store
+        .matches
+        .record_sync_state(puuid, queue_id, newest)
+        .await?;
+    Ok(stored)
+}
@@ -0,0 +1,993 @@
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use futures::stream::{self, BoxStream, StreamExt};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    sync::{Mutex, MutexGuard},
+};
+
+use crate::models::{
+    self, Backfill, ExportFilter, Job, MasteryGain, MatchRow, ParticipantRow, QueueStats,
+    RankSnapshot, Reclaimed, RiotIdHistoryEntry, User,
+};
+use crate::repository::{JobRepository, MatchRepository, RankRepository, UserRepository};
+use crate::riot_api::{
+    AccountV1, ChampionMasteryV4, LargeRegion, LeagueV4, MatchV5Match, MatchV5Timeline, Region,
+    SummonerV4,
+};
+
+/// Repositories keeping everything in memory, for running the server without a
+/// database. Only what is read back is kept, a match for instance keeps its raw
+/// json and the columns of the exports.
+#[derive(Default)]
+pub struct MemoryStore(Mutex<Memory>);
+
+#[derive(Default)]
+struct Memory {
+    users: HashMap<String, User>,
+    riot_ids: HashMap<String, Vec<RiotIdHistoryEntry>>,
+    refresh_quota: HashMap<NaiveDate, i32>,
+    /// Points per PUUID and champion
+    masteries: HashMap<(String, i32), i32>,
+    mastery_snapshots: Vec<MasterySnapshot>,
+    rank_snapshots: Vec<(String, RankSnapshot)>,
+    /// Time of the latest league-v4 fetch per PUUID
+    ranks_fetched_at: HashMap<String, DateTime<Utc>>,
+    matches: HashMap<String, StoredMatch>,
+    timelines: HashMap<String, StoredTimeline>,
+    sync_states: HashMap<(String, i32), Option<i64>>,
+    backfills: HashMap<String, Backfill>,
+    jobs: Vec<QueuedJob>,
+    /// PUUIDs of players deleted on request
+    deleted_users: HashSet<String>,
+    /// Last id given to a rank snapshot or job
+    last_id: i64,
+}
+
+struct MasterySnapshot {
+    puuid: String,
+    champion_id: i32,
+    points: i32,
+    taken_at: DateTime<Utc>,
+}
+
+struct StoredMatch {
+    /// None once pruned
+    raw: Option<serde_json::Value>,
+    ingested_at: DateTime<Utc>,
+    row: MatchRow,
+    participants: Vec<ParticipantRow>,
+}
+
+struct StoredTimeline {
+    ingested_at: DateTime<Utc>,
+    /// Total gold and CS at 15 minutes per participant
+    at_15: HashMap<i32, (i32, i32)>,
+}
+
+struct QueuedJob {
+    job: Job,
+    priority: i32,
+    run_after: DateTime<Utc>,
+    locked_at: Option<DateTime<Utc>>,
+    failed: bool,
+}
+
+impl MemoryStore {
+    fn memory(&self) -> MutexGuard<'_, Memory> {
+        self.0.lock().unwrap()
+    }
+}
+
+impl Memory {
+    fn next_id(&mut self) -> i64 {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    fn update_user(&mut self, puuid: &str, update: impl FnOnce(&mut User)) {
+        if let Some(user) = self.users.get_mut(puuid) {
+            update(user);
+            user.updated_at = Utc::now();
+        }
+    }
+
+    /// Rows a deleted user can take with them, jobs included.
+    fn rows_of_users(&self) -> usize {
+        self.jobs.len()
+            + self.users.len()
+            + self.riot_ids.values().map(Vec::len).sum::<usize>()
+            + self.backfills.len()
+            + self.rank_snapshots.len()
+            + self.sync_states.len()
+            + self.masteries.len()
+            + self.mastery_snapshots.len()
+    }
+
+    fn tracked_played(&self, match_id: &str) -> bool {
+        self.matches.get(match_id).is_some_and(|stored| {
+            stored.participants.iter().any(|participant| {
+                self.users
+                    .get(&participant.puuid)
+                    .is_some_and(|user| user.tracked_at.is_some())
+            })
+        })
+    }
+
+    fn matches_of<'a>(
+        &'a self,
+        puuid: &'a str,
+    ) -> impl Iterator<Item = (&'a String, &'a StoredMatch)> {
+        self.matches.iter().filter(move |(_, stored)| {
+            stored
+                .participants
+                .iter()
+                .any(|participant| participant.puuid == puuid)
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl UserRepository for MemoryStore {
+    async fn upsert_account(
+        &self,
+        account: &AccountV1,
+        riot_key_id: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        let now = Utc::now();
+        let mut memory = self.memory();
+        if memory.users.contains_key(&account.puuid) {
+            memory.update_user(&account.puuid, |user| {
+                user.game_name = account.game_name.clone();
+                user.tag_line = account.tag_line.clone();
+                if riot_key_id.is_some() {
+                    user.riot_key_id = riot_key_id.map(str::to_string);
+                }
+            });
+        } else {
+            memory.users.insert(
+                account.puuid.clone(),
+                User {
+                    puuid: account.puuid.clone(),
+                    game_name: account.game_name.clone(),
+                    tag_line: account.tag_line.clone(),
+                    profile_icon_id: None,
+                    summoner_level: None,
+                    platform: None,
+                    tracked_at: None,
+                    next_refresh_at: None,
+                    update_requested_at: None,
+                    refreshed_at: None,
+                    created_at: now,
+                    updated_at: now,
+                    riot_key_id: riot_key_id.map(str::to_string),
+                },
+            );
+        }
+        let history = memory.riot_ids.entry(account.puuid.clone()).or_default();
+        match history.iter_mut().find(|entry| {
+            entry.game_name == account.game_name && entry.tag_line == account.tag_line
+        }) {
+            Some(entry) => entry.last_seen_at = now,
+            None => history.push(RiotIdHistoryEntry {
+                game_name: account.game_name.clone(),
+                tag_line: account.tag_line.clone(),
+                first_seen_at: now,
+                last_seen_at: now,
+            }),
+        }
+        Ok(())
+    }
+
+    async fn upsert_summoner(
+        &self,
+        region: &Region,
+        summoner: &SummonerV4,
+    ) -> Result<(), sqlx::Error> {
+        self.memory().update_user(&summoner.puuid, |user| {
+            user.profile_icon_id = Some(summoner.profile_icon_id as i32);
+            user.summoner_level = Some(summoner.summoner_level as i64);
+            user.platform = Some(region.to_string());
+        });
+        Ok(())
+    }
+
+    async fn former_riot_ids(
+        &self,
+        account: &AccountV1,
+    ) -> Result<Vec<RiotIdHistoryEntry>, sqlx::Error> {
+        let memory = self.memory();
+        let mut former: Vec<RiotIdHistoryEntry> = memory
+            .riot_ids
+            .get(&account.puuid)
+            .into_iter()
+            .flatten()
+            .filter(|entry| {
+                !(entry.game_name == account.game_name && entry.tag_line == account.tag_line)
+            })
+            .cloned()
+            .collect();
+        former.sort_by_key(|entry| Reverse(entry.last_seen_at));
+        Ok(former)
+    }
+
+    async fn user_by_former_riot_id(
+        &self,
+        game_name: &str,
+        tag_line: &str,
+    ) -> Result<Option<User>, sqlx::Error> {
+        let memory = self.memory();
+        let latest = memory
+            .riot_ids
+            .iter()
+            .flat_map(|(puuid, history)| history.iter().map(move |entry| (puuid, entry)))
+            .filter(|(_, entry)| {
+                entry.game_name.to_lowercase() == game_name.to_lowercase()
+                    && entry.tag_line.to_lowercase() == tag_line.to_lowercase()
+            })
+            .max_by_key(|(_, entry)| entry.last_seen_at);
+        Ok(latest.and_then(|(puuid, _)| memory.users.get(puuid).cloned()))
+    }
+
+    async fn user_by_riot_id(
+        &self,
+        game_name: &str,
+        tag_line: &str,
+    ) -> Result<Option<User>, sqlx::Error> {
+        Ok(self
+            .memory()
+            .users
+            .values()
+            .filter(|user| {
+                user.game_name.to_lowercase() == game_name.to_lowercase()
+                    && user.tag_line.to_lowercase() == tag_line.to_lowercase()
+            })
+            .max_by_key(|user| user.refreshed_at)
+            .cloned())
+    }
+
+    async fn user_by_puuid(&self, puuid: &str) -> Result<Option<User>, sqlx::Error> {
+        Ok(self.memory().users.get(puuid).cloned())
+    }
+
+    async fn mark_refreshed(&self, puuid: &str) -> Result<(), sqlx::Error> {
+        self.memory()
+            .update_user(puuid, |user| user.refreshed_at = Some(Utc::now()));
+        Ok(())
+    }
+
+    async fn set_tracked(&self, puuid: &str, tracked: bool) -> Result<(), sqlx::Error> {
+        let now = Utc::now();
+        self.memory().update_user(puuid, |user| {
+            if tracked {
+                user.tracked_at = user.tracked_at.or(Some(now));
+                user.next_refresh_at = user.next_refresh_at.or(Some(now));
+            } else {
+                user.tracked_at = None;
+                user.next_refresh_at = None;
+            }
+        });
+        Ok(())
+    }
+
+    async fn request_update(&self, puuid: &str, cooldown: TimeDelta) -> Result<bool, sqlx::Error> {
+        let now = Utc::now();
+        let mut memory = self.memory();
+        let allowed = memory.users.get(puuid).is_some_and(|user| {
+            user.update_requested_at
+                .is_none_or(|requested_at| requested_at <= now - cooldown)
+        });
+        if allowed {
+            memory.update_user(puuid, |user| user.update_requested_at = Some(now));
+        }
+        Ok(allowed)
+    }
+
+    async fn due_refreshes(&self, limit: i64) -> Result<Vec<User>, sqlx::Error> {
+        let now = Utc::now();
+        let memory = self.memory();
+        let mut due: Vec<User> = memory
+            .users
+            .values()
+            .filter(|user| {
+                user.tracked_at.is_some() && user.next_refresh_at.is_some_and(|at| at <= now)
+            })
+            .cloned()
+            .collect();
+        due.sort_by_key(|user| user.next_refresh_at);
+        due.truncate(limit as usize);
+        Ok(due)
+    }
+
+    async fn schedule_refresh(&self, puuid: &str, at: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        self.memory().update_user(puuid, |user| {
+            if user.tracked_at.is_some() {
+                user.next_refresh_at = Some(at);
+            }
+        });
+        Ok(())
+    }
+
+    async fn reserve_refresh_quota(
+        &self,
+        day: NaiveDate,
+        requests: i32,
+        quota: i32,
+    ) -> Result<bool, sqlx::Error> {
+        let mut memory = self.memory();
+        let used = memory.refresh_quota.entry(day).or_default();
+        if *used + requests > quota {
+            return Ok(false);
+        }
+        *used += requests;
+        Ok(true)
+    }
+
+    async fn record_masteries(
+        &self,
+        puuid: &str,
+        masteries: &[ChampionMasteryV4],
+    ) -> Result<(), sqlx::Error> {
+        let now = Utc::now();
+        let mut memory = self.memory();
+        for mastery in masteries {
+            let key = (puuid.to_string(), mastery.champion_id);
+            if memory.masteries.get(&key) == Some(&mastery.champion_points) {
+                continue;
+            }
+            memory.masteries.insert(key, mastery.champion_points);
+            memory.mastery_snapshots.push(MasterySnapshot {
+                puuid: puuid.to_string(),
+                champion_id: mastery.champion_id,
+                points: mastery.champion_points,
+                taken_at: now,
+            });
+        }
+        Ok(())
+    }
+
+    async fn mastery_gains(
+        &self,
+        puuid: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<MasteryGain>, sqlx::Error> {
+        let memory = self.memory();
+        let mut previous: HashMap<i32, i32> = HashMap::new();
+        let mut gains = Vec::new();
+        // Snapshots are pushed in the order they are taken
+        for snapshot in memory
+            .mastery_snapshots
+            .iter()
+            .filter(|snapshot| snapshot.puuid == puuid)
+        {
+            if let Some(points) = previous.insert(snapshot.champion_id, snapshot.points) {
+                if snapshot.taken_at >= since {
+                    gains.push(MasteryGain {
+                        champion_id: snapshot.champion_id,
+                        taken_at: snapshot.taken_at,
+                        points: snapshot.points - points,
+                    });
+                }
+            }
+        }
+        Ok(gains)
+    }
+
+    async fn delete_user(&self, puuid: &str) -> Result<Reclaimed, sqlx::Error> {
+        let mut memory = self.memory();
+        let mut reclaimed = Reclaimed::default();
+        for stored in memory.matches.values_mut() {
+            let mut played = false;
+            for participant in stored
+                .participants
+                .iter_mut()
+                .filter(|participant| participant.puuid == puuid)
+            {
+                participant.puuid = String::new();
+                participant.riot_id_game_name = String::new();
+                participant.riot_id_tagline = String::new();
+                played = true;
+                reclaimed.rows += 1;
+            }
+            if let Some(raw) = stored.raw.take_if(|_| played) {
+                reclaimed.rows += 1;
+                reclaimed.bytes = reclaimed
+                    .bytes
+                    .map(|bytes| bytes + raw.to_string().len() as i64);
+            }
+        }
+        let before = memory.rows_of_users();
+        memory.jobs.retain(|queued| {
+            queued
+                .job
+                .payload
+                .get("puuid")
+                .and_then(|owner| owner.as_str())
+                != Some(puuid)
+        });
+        memory.users.remove(puuid);
+        memory.riot_ids.remove(puuid);
+        memory.backfills.remove(puuid);
+        memory.rank_snapshots.retain(|(owner, _)| owner != puuid);
+        memory.ranks_fetched_at.remove(puuid);
+        memory.sync_states.retain(|(owner, _), _| owner != puuid);
+        memory.masteries.retain(|(owner, _), _| owner != puuid);
+        memory
+            .mastery_snapshots
+            .retain(|snapshot| snapshot.puuid != puuid);
+        reclaimed.rows += (before - memory.rows_of_users()) as u64;
+        memory.deleted_users.insert(puuid.to_string());
+        Ok(reclaimed)
+    }
+
+    async fn is_deleted(&self, puuid: &str) -> Result<bool, sqlx::Error> {
+        Ok(self.memory().deleted_users.contains(puuid))
+    }
+}
+
+#[async_trait::async_trait]
+impl RankRepository for MemoryStore {
+    async fn record_ranks(&self, puuid: &str, ranks: &[LeagueV4]) -> Result<(), sqlx::Error> {
+        let now = Utc::now();
+        let mut memory = self.memory();
+        for rank in ranks {
+            let latest = memory
+                .rank_snapshots
+                .iter_mut()
+                .filter(|(puuid, snapshot)| {
+                    *puuid == rank.puuid && snapshot.queue_type == rank.queue_type
+                })
+                .max_by_key(|(_, snapshot)| snapshot.first_seen_at);
+            match latest {
+                Some((_, snapshot)) if snapshot.same_rank(rank) => snapshot.last_seen_at = now,
+                _ => {
+                    let id = memory.next_id();
+                    memory.rank_snapshots.push((
+                        rank.puuid.clone(),
+                        RankSnapshot {
+                            id,
+                            queue_type: rank.queue_type.clone(),
+                            tier: rank.tier.clone(),
+                            division: rank.rank.clone(),
+                            league_points: rank.league_points,
+                            wins: rank.wins,
+                            losses: rank.losses,
+                            first_seen_at: now,
+                            last_seen_at: now,
+                        },
+                    ));
+                }
+            }
+        }
+        memory.ranks_fetched_at.insert(puuid.to_string(), now);
+        Ok(())
+    }
+
+    async fn rank_history(&self, puuid: &str, days: i64) -> Result<Vec<RankSnapshot>, sqlx::Error> {
+        let since = Utc::now() - TimeDelta::days(days);
+        let memory = self.memory();
+        let mut history: Vec<RankSnapshot> = memory
+            .rank_snapshots
+            .iter()
+            .filter(|(owner, snapshot)| owner == puuid && snapshot.last_seen_at >= since)
+            .map(|(_, snapshot)| snapshot.clone())
+            .collect();
+        history.sort_by_key(|snapshot| snapshot.first_seen_at);
+        Ok(history)
+    }
+
+    async fn current_ranks(&self, puuid: &str) -> Result<Vec<RankSnapshot>, sqlx::Error> {
+        let memory = self.memory();
+        let Some(fetched_at) = memory.ranks_fetched_at.get(puuid) else {
+            return Ok(Vec::new());
+        };
+        let mut current: Vec<RankSnapshot> = memory
+            .rank_snapshots
+            .iter()
+            .filter(|(owner, snapshot)| owner == puuid && snapshot.last_seen_at == *fetched_at)
+            .map(|(_, snapshot)| snapshot.clone())
+            .collect();
+        current.sort_by(|a, b| a.queue_type.cmp(&b.queue_type));
+        Ok(current)
+    }
+}
+
+#[async_trait::async_trait]
+impl MatchRepository for MemoryStore {
+    async fn ingest_match(
+        &self,
+        lol_match: &MatchV5Match,
+        raw: &serde_json::Value,
+    ) -> Result<(), sqlx::Error> {
+        let info = &lol_match.info;
+        let match_id = &lol_match.metadata.match_id;
+        let mut memory = self.memory();
+        let deleted = info
+            .participants
+            .iter()
+            .any(|participant| memory.deleted_users.contains(&participant.puuid));
+        let identity = |puuid: &String, value: &String| {
+            if memory.deleted_users.contains(puuid) {
+                String::new()
+            } else {
+                value.clone()
+            }
+        };
+        let participants: Vec<ParticipantRow> = info
+            .participants
+            .iter()
+            .map(|participant| ParticipantRow {
+                match_id: match_id.clone(),
+                platform_id: info.platform_id.clone(),
+                queue_id: info.queue_id,
+                game_version: info.game_version.clone(),
+                game_creation: info.game_creation,
+                participant_id: participant.participant_id,
+                puuid: identity(&participant.puuid, &participant.puuid),
+                riot_id_game_name: identity(&participant.puuid, &participant.riot_id_game_name),
+                riot_id_tagline: identity(&participant.puuid, &participant.riot_id_tagline),
+                team_id: participant.team_id,
+                champion_id: participant.champion_id,
+                champion_name: participant.champion_name.clone(),
+                champ_level: participant.champ_level,
+                team_position: participant.team_position.clone(),
+                individual_position: participant.individual_position.clone(),
+                win: participant.win,
+                kills: participant.kills,
+                deaths: participant.deaths,
+                assists: participant.assists,
+                total_minions_killed: participant.total_minions_killed,
+                neutral_minions_killed: participant.neutral_minions_killed,
+                gold_earned: participant.gold_earned,
+                total_damage_dealt_to_champions: participant.total_damage_dealt_to_champions,
+                total_damage_taken: participant.total_damage_taken,
+                vision_score: participant.vision_score,
+                wards_placed: participant.wards_placed,
+                wards_killed: participant.wards_killed,
+                item0: participant.item0,
+                item1: participant.item1,
+                item2: participant.item2,
+                item3: participant.item3,
+                item4: participant.item4,
+                item5: participant.item5,
+                item6: participant.item6,
+                summoner1_id: participant.summoner1_id,
+                summoner2_id: participant.summoner2_id,
+                time_played: participant.time_played,
+            })
+            .collect();
+        let row = MatchRow {
+            match_id: match_id.clone(),
+            platform_id: info.platform_id.clone(),
+            queue_id: info.queue_id,
+            map_id: info.map_id,
+            game_mode: info.game_mode.clone(),
+            game_type: info.game_type.clone(),
+            game_version: info.game_version.clone(),
+            game_creation: info.game_creation,
+            game_start_timestamp: info.game_start_timestamp,
+            game_end_timestamp: info.game_end_timestamp,
+            game_duration: info.game_duration,
+            end_of_game_result: info.end_of_game_result.clone(),
+        };
+        memory.matches.insert(
+            match_id.clone(),
+            StoredMatch {
+                raw: (!deleted).then(|| raw.clone()),
+                ingested_at: Utc::now(),
+                row,
+                participants,
+            },
+        );
+        Ok(())
+    }
+
+    async fn stored_match(&self, match_id: &str) -> Result<Option<serde_json::Value>, sqlx::Error> {
+        Ok(self
+            .memory()
+            .matches
+            .get(match_id)
+            .and_then(|stored| stored.raw.clone()))
+    }
+
+    async fn has_timeline(&self, match_id: &str) -> Result<bool, sqlx::Error> {
+        Ok(self.memory().timelines.contains_key(match_id))
+    }
+
+    async fn ingest_timeline(&self, timeline: &MatchV5Timeline) -> Result<(), sqlx::Error> {
+        let mut at_15 = HashMap::new();
+        for frame in &timeline.info.frames {
+            if models::frame_minute(frame.timestamp, timeline.info.frame_interval) != 15 {
+                continue;
+            }
+            for participant_frame in frame.participant_frames.values() {
+                at_15.entry(participant_frame.participant_id).or_insert((
+                    participant_frame.total_gold,
+                    participant_frame.minions_killed + participant_frame.jungle_minions_killed,
+                ));
+            }
+        }
+        self.memory().timelines.insert(
+            timeline.metadata.match_id.clone(),
+            StoredTimeline {
+                ingested_at: Utc::now(),
+                at_15,
+            },
+        );
+        Ok(())
+    }
+
+    async fn queue_stats(&self, puuid: &str) -> Result<Vec<QueueStats>, sqlx::Error> {
+        let memory = self.memory();
+        let mut stats: HashMap<i32, QueueStats> = HashMap::new();
+        let mut at_15: HashMap<i32, Vec<(i32, i32)>> = HashMap::new();
+        for (match_id, stored) in memory.matches_of(puuid) {
+            let Some(participant) = stored
+                .participants
+                .iter()
+                .find(|participant| participant.puuid == puuid)
+            else {
+                continue;
+            };
+            let queue = stats.entry(stored.row.queue_id).or_insert(QueueStats {
+                queue_id: stored.row.queue_id,
+                games: 0,
+                wins: 0,
+                kills: 0,
+                deaths: 0,
+                assists: 0,
+                gold_at_15: None,
+                cs_at_15: None,
+            });
+            queue.games += 1;
+            queue.wins += participant.win as i64;
+            queue.kills += participant.kills as i64;
+            queue.deaths += participant.deaths as i64;
+            queue.assists += participant.assists as i64;
+            if let Some(frame) = memory
+                .timelines
+                .get(match_id)
+                .and_then(|timeline| timeline.at_15.get(&participant.participant_id))
+            {
+                at_15.entry(stored.row.queue_id).or_default().push(*frame);
+            }
+        }
+        let mut stats: Vec<QueueStats> = stats.into_values().collect();
+        for queue in &mut stats {
+            if let Some(frames) = at_15.get(&queue.queue_id) {
+                let count = frames.len() as f64;
+                queue.gold_at_15 =
+                    Some(frames.iter().map(|(gold, _)| *gold as f64).sum::<f64>() / count);
+                queue.cs_at_15 = Some(frames.iter().map(|(_, cs)| *cs as f64).sum::<f64>() / count);
+            }
+        }
+        stats.sort_by_key(|queue| Reverse(queue.games));
+        Ok(stats)
+    }
+
+    async fn recent_match_ids(
+        &self,
+        puuid: &str,
+        queue_id: Option<i32>,
+        limit: i64,
+    ) -> Result<Vec<String>, sqlx::Error> {
+        let memory = self.memory();
+        let mut recent: Vec<(&String, &StoredMatch)> = memory
+            .matches_of(puuid)
+            .filter(|(_, stored)| queue_id.is_none_or(|queue_id| stored.row.queue_id == queue_id))
+            .collect();
+        recent.sort_by_key(|(_, stored)| Reverse(stored.row.game_start_timestamp));
+        Ok(recent
+            .into_iter()
+            .take(limit as usize)
+            .map(|(match_id, _)| match_id.clone())
+            .collect())
+    }
+
+    async fn latest_stored_game_start(
+        &self,
+        puuid: &str,
+        queue_id: Option<i32>,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        Ok(self
+            .memory()
+            .matches_of(puuid)
+            .filter(|(_, stored)| queue_id.is_none_or(|queue_id| stored.row.queue_id == queue_id))
+            .map(|(_, stored)| stored.row.game_start_timestamp)
+            .max())
+    }
+
+    async fn game_start_timestamp(&self, match_id: &str) -> Result<Option<i64>, sqlx::Error> {
+        Ok(self
+            .memory()
+            .matches
+            .get(match_id)
+            .map(|stored| stored.row.game_start_timestamp))
+    }
+
+    async fn unknown_match_ids(&self, match_ids: &[String]) -> Result<Vec<String>, sqlx::Error> {
+        let memory = self.memory();
+        Ok(match_ids
+            .iter()
+            .filter(|match_id| !memory.matches.contains_key(*match_id))
+            .cloned()
+            .collect())
+    }
+
+    async fn sync_state(&self, puuid: &str, queue_id: i32) -> Result<Option<i64>, sqlx::Error> {
+        Ok(self
+            .memory()
+            .sync_states
+            .get(&(puuid.to_string(), queue_id))
+            .copied()
+            .flatten())
+    }
+
+    async fn record_sync_state(
+        &self,
+        puuid: &str,
+        queue_id: i32,
+        latest_game_start: Option<i64>,
+    ) -> Result<(), sqlx::Error> {
+        self.memory()
+            .sync_states
+            .insert((puuid.to_string(), queue_id), latest_game_start);
+        Ok(())
+    }
+
+    async fn create_backfill(
+        &self,
+        puuid: &str,
+        large_region: &LargeRegion,
+        horizon: i64,
+    ) -> Result<(), sqlx::Error> {
+        let now = Utc::now();
+        self.memory()
+            .backfills
+            .entry(puuid.to_string())
+            .or_insert_with(|| Backfill {
+                puuid: puuid.to_string(),
+                large_region: large_region.to_string(),
+                horizon,
+                end_time: now.timestamp(),
+                cursor: 0,
+                matches_stored: 0,
+                covered_until: None,
+                status: "running".to_string(),
+                error: None,
+                created_at: now,
+                updated_at: now,
+            });
+        Ok(())
+    }
+
+    async fn backfill(&self, puuid: &str) -> Result<Option<Backfill>, sqlx::Error> {
+        Ok(self.memory().backfills.get(puuid).cloned())
+    }
+
+    async fn running_backfills(&self) -> Result<Vec<Backfill>, sqlx::Error> {
+        Ok(self
+            .memory()
+            .backfills
+            .values()
+            .filter(|backfill| backfill.status == "running")
+            .cloned()
+            .collect())
+    }
+
+    async fn record_backfill_progress(&self, backfill: &Backfill) -> Result<(), sqlx::Error> {
+        if let Some(stored) = self.memory().backfills.get_mut(&backfill.puuid) {
+            stored.cursor = backfill.cursor;
+            stored.matches_stored = backfill.matches_stored;
+            stored.covered_until = backfill.covered_until;
+            stored.status = backfill.status.clone();
+            stored.error = backfill.error.clone();
+            stored.updated_at = Utc::now();
+        }
+        Ok(())
+    }
+
+    async fn prune_raw_matches(&self, before: DateTime<Utc>) -> Result<Reclaimed, sqlx::Error> {
+        let mut memory = self.memory();
+        let pruned: Vec<String> = memory
+            .matches
+            .iter()
+            .filter(|(_, stored)| stored.raw.is_some() && stored.ingested_at < before)
+            .map(|(match_id, _)| match_id.clone())
+            .filter(|match_id| !memory.tracked_played(match_id))
+            .collect();
+        let mut reclaimed = Reclaimed::default();
+        for match_id in pruned {
+            if let Some(raw) = memory
+                .matches
+                .get_mut(&match_id)
+                .and_then(|stored| stored.raw.take())
+            {
+                reclaimed.rows += 1;
+                reclaimed.bytes = reclaimed
+                    .bytes
+                    .map(|bytes| bytes + raw.to_string().len() as i64);
+            }
+        }
+        Ok(reclaimed)
+    }
+
+    async fn prune_timelines(&self, before: DateTime<Utc>) -> Result<Reclaimed, sqlx::Error> {
+        let mut memory = self.memory();
+        let pruned: Vec<String> = memory
+            .timelines
+            .iter()
+            .filter(|(_, timeline)| timeline.ingested_at < before)
+            .map(|(match_id, _)| match_id.clone())
+            .filter(|match_id| !memory.tracked_played(match_id))
+            .collect();
+        // Nothing here is stored the way a database would, so no size
+        let mut reclaimed = Reclaimed {
+            rows: 0,
+            bytes: None,
+        };
+        for match_id in pruned {
+            // Only the frames at 15 minutes are kept in memory
+            if let Some(timeline) = memory.timelines.remove(&match_id) {
+                reclaimed.rows += 1 + timeline.at_15.len() as u64;
+            }
+        }
+        Ok(reclaimed)
+    }
+
+    fn export_matches<'a>(
+        &'a self,
+        filter: &ExportFilter,
+    ) -> BoxStream<'a, Result<MatchRow, sqlx::Error>> {
+        let memory = self.memory();
+        let mut rows: Vec<MatchRow> = memory
+            .matches
+            .values()
+            .filter(|stored| filter.covers(&stored.row))
+            .map(|stored| stored.row.clone())
+            .collect();
+        rows.sort_by(|a, b| (a.game_creation, &a.match_id).cmp(&(b.game_creation, &b.match_id)));
+        stream::iter(rows.into_iter().map(Ok)).boxed()
+    }
+
+    fn export_participants<'a>(
+        &'a self,
+        filter: &ExportFilter,
+    ) -> BoxStream<'a, Result<ParticipantRow, sqlx::Error>> {
+        let memory = self.memory();
+        let mut rows: Vec<ParticipantRow> = memory
+            .matches
+            .values()
+            .filter(|stored| filter.covers(&stored.row))
+            .flat_map(|stored| stored.participants.iter().cloned())
+            .collect();
+        rows.sort_by(|a, b| {
+            (a.game_creation, &a.match_id, a.participant_id).cmp(&(
+                b.game_creation,
+                &b.match_id,
+                b.participant_id,
+            ))
+        });
+        stream::iter(rows.into_iter().map(Ok)).boxed()
+    }
+}
+
+#[async_trait::async_trait]
+impl JobRepository for MemoryStore {
+    async fn enqueue_job(
+        &self,
+        kind: &str,
+        payload: &serde_json::Value,
+        priority: i32,
+    ) -> Result<bool, sqlx::Error> {
+        let mut memory = self.memory();
+        let pending = memory.jobs.iter().any(|queued| {
+            !queued.failed && queued.job.kind == kind && queued.job.payload == *payload
+        });
+        if pending {
+            return Ok(false);
+        }
+        let id = memory.next_id();
+        memory.jobs.push(QueuedJob {
+            job: Job {
+                id,
+                kind: kind.to_string(),
+                payload: payload.clone(),
+                attempts: 0,
+            },
+            priority,
+            run_after: Utc::now(),
+            locked_at: None,
+            failed: false,
+        });
+        Ok(true)
+    }
+
+    async fn claim_job(&self, locked_before: DateTime<Utc>) -> Result<Option<Job>, sqlx::Error> {
+        let now = Utc::now();
+        let mut memory = self.memory();
+        let next = memory
+            .jobs
+            .iter_mut()
+            .filter(|queued| {
+                queued
+                    .locked_at
+                    .is_none_or(|locked_at| locked_at < locked_before)
+                    && !queued.failed
+                    && queued.run_after <= now
+            })
+            .min_by_key(|queued| (Reverse(queued.priority), queued.run_after));
+        Ok(next.map(|queued| {
+            queued.locked_at = Some(now);
+            queued.job.attempts += 1;
+            queued.job.clone()
+        }))
+    }
+
+    async fn heartbeat_job(&self, id: i64) -> Result<(), sqlx::Error> {
+        if let Some(queued) = self
+            .memory()
+            .jobs
+            .iter_mut()
+            .find(|queued| queued.job.id == id && queued.locked_at.is_some())
+        {
+            queued.locked_at = Some(Utc::now());
+        }
+        Ok(())
+    }
+
+    async fn complete_job(&self, id: i64) -> Result<(), sqlx::Error> {
+        self.memory().jobs.retain(|queued| queued.job.id != id);
+        Ok(())
+    }
+
+    async fn requeue_job(&self, id: i64) -> Result<(), sqlx::Error> {
+        if let Some(queued) = self
+            .memory()
+            .jobs
+            .iter_mut()
+            .find(|queued| queued.job.id == id)
+        {
+            queued.locked_at = None;
+            queued.job.attempts = 0;
+            queued.run_after = Utc::now();
+        }
+        Ok(())
+    }
+
+    async fn fail_job(
+        &self,
+        id: i64,
+        _error: &str,
+        run_after: Option<DateTime<Utc>>,
+    ) -> Result<(), sqlx::Error> {
+        if let Some(queued) = self
+            .memory()
+            .jobs
+            .iter_mut()
+            .find(|queued| queued.job.id == id)
+        {
+            queued.locked_at = None;
+            match run_after {
+                Some(run_after) => queued.run_after = run_after,
+                None => queued.failed = true,
+            }
+        }
+        Ok(())
+    }
+
+    async fn has_pending_jobs(&self, puuid: &str, kinds: &[&str]) -> Result<bool, sqlx::Error> {
+        let now = Utc::now();
+        Ok(self.memory().jobs.iter().any(|queued| {
+            !queued.failed
+                && (queued.locked_at.is_some() || queued.run_after <= now)
+                && kinds.contains(&queued.job.kind.as_str())
+                && queued
+                    .job
+                    .payload
+                    .get("puuid")
+                    .and_then(|puuid| puuid.as_str())
+                    == Some(puuid)
+        }))
+    }
+}
@@ -0,0 +1,1699 @@
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeDelta, Utc};
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream},
+    FutureExt, StreamExt, TryStreamExt,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, QueryBuilder};
+use std::collections::HashSet;
+
+use crate::db::{with_pool, Db};
+use crate::riot_api::{
+    AccountV1, ChampionMasteryV4, LargeRegion, LeagueV4, MatchV5Match, MatchV5Timeline, Region,
+    SummonerV4,
+};
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct User {
+    pub puuid: String,
+    pub game_name: String,
+    pub tag_line: String,
+    pub profile_icon_id: Option<i32>,
+    pub summoner_level: Option<i64>,
+    /// Region the summoner was last seen on, as used in profile urls
+    pub platform: Option<String>,
+    /// Set while the user is refreshed on a schedule
+    pub tracked_at: Option<DateTime<Utc>>,
+    pub next_refresh_at: Option<DateTime<Utc>>,
+    pub update_requested_at: Option<DateTime<Utc>>,
+    /// Last time the summoner and ranks were fetched from Riot
+    pub refreshed_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// Id of the api key the PUUID was encrypted for, PUUIDs differ per key
+    pub riot_key_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct RiotIdHistoryEntry {
+    pub game_name: String,
+    pub tag_line: String,
+    pub first_seen_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+}
+
+/// Saves the current riot id of an account and records it in its history,
+/// along with the api key that fetched it when known.
+pub async fn upsert_account(
+    db: &Db,
+    account: &AccountV1,
+    riot_key_id: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    with_pool!(db, |pool| {
+        let mut transaction = pool.begin().await?;
+        sqlx::query(
+            "insert into users (puuid, game_name, tag_line, riot_key_id) values ($1, $2, $3, $4)
+             on conflict (puuid) do update
+             set game_name = excluded.game_name, tag_line = excluded.tag_line,
+                 riot_key_id = coalesce(excluded.riot_key_id, users.riot_key_id)",
+        )
+        .bind(&account.puuid)
+        .bind(&account.game_name)
+        .bind(&account.tag_line)
+        .bind(riot_key_id)
+        .execute(&mut *transaction)
+        .await?;
+        sqlx::query(
+            "insert into riot_id_history (puuid, game_name, tag_line, first_seen_at, last_seen_at)
+             values ($1, $2, $3, $4, $4)
+             on conflict (puuid, game_name, tag_line) do update
+             set last_seen_at = excluded.last_seen_at",
+        )
+        .bind(&account.puuid)
+        .bind(&account.game_name)
+        .bind(&account.tag_line)
+        .bind(now)
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await
+    })
+}
+
+/// Saves profile icon, level and platform. The user must already exist.
+pub async fn upsert_summoner(
+    db: &Db,
+    region: &Region,
+    summoner: &SummonerV4,
+) -> Result<(), sqlx::Error> {
+    with_pool!(db, |pool| {
+        sqlx::query(
+            "update users set profile_icon_id = $2, summoner_level = $3, platform = $4
+             where puuid = $1",
+        )
+        .bind(&summoner.puuid)
+        .bind(summoner.profile_icon_id as i32)
+        .bind(summoner.summoner_level as i64)
+        .bind(region.to_string())
+        .execute(pool)
+        .await?;
+    });
+    Ok(())
+}
+
+/// Riot ids a PUUID was seen with before its current one, latest first.
+pub async fn former_riot_ids(
+    db: &Db,
+    account: &AccountV1,
+) -> Result<Vec<RiotIdHistoryEntry>, sqlx::Error> {
+    with_pool!(db, |pool| {
+        sqlx::query_as(
+            "select game_name, tag_line, first_seen_at, last_seen_at from riot_id_history
+             where puuid = $1 and not (game_name = $2 and tag_line = $3)
+             order by last_seen_at desc",
+        )
+        .bind(&account.puuid)
+        .bind(&account.game_name)
+        .bind(&account.tag_line)
+        .fetch_all(pool)
+        .await
+    })
+}
+
+/// The user that went by `game_name#tag_line` most recently. Riot ids are
+/// case insensitive.
+pub async fn user_by_former_riot_id(
+    db: &Db,
+    game_name: &str,
+    tag_line: &str,
+) -> Result<Option<User>, sqlx::Error> {
+    with_pool!(db, |pool| {
+        sqlx::query_as(
+            "select users.* from riot_id_history
+             join users on users.puuid = riot_id_history.puuid
+             where lower(riot_id_history.game_name) = lower($1)
+             and lower(riot_id_history.tag_line) = lower($2)
+             order by riot_id_history.last_seen_at desc
+             limit 1",
+        )
+        .bind(game_name)
+        .bind(tag_line)
+        .fetch_optional(pool)
+        .await
+    })
+}
+
+/// The user currently going by `game_name#tag_line`, case insensitive. When a
+/// riot id was taken over, the most recently refreshed user wins.
+pub async fn user_by_riot_id(
+    db: &Db,
+    game_name: &str,
+    tag_line: &str,
+) -> Result<Option<User>, sqlx::Error> {
+    with_pool!(db, |pool| {
+        sqlx::query_as(
+            "select * from users
+             where lower(game_name) = lower($1) and lower(tag_line) = lower($2)
+             order by refreshed_at desc nulls last
+             limit 1",
+        )
+        .bind(game_name)
+        .bind(tag_line)
+        .fetch_optional(pool)
+        .await
+    })
+}
+
+pub async fn mark_refreshed(db: &Db, puuid: &str) -> Result<(), sqlx::Error> {
+    with_pool!(db, |pool| {
+        sqlx::query("update users set refreshed_at = $2 where puuid = $1")
+            .bind(puuid)
+            .bind(Utc::now())
+            .execute(pool)
+            .await?;
+    });
+    Ok(())
+}
+
+pub async fn user_by_puuid(db: &Db, puuid: &str) -> Result<Option<User>, sqlx::Error> {
+    with_pool!(db, |pool| {
+        sqlx::query_as("select * from users where puuid = $1")
+            .bind(puuid)
+            .fetch_optional(pool)
+            .await
+    })
+}
+
+/// Whether the player was deleted with [`delete_user`]. Nothing is fetched or
+/// stored about them again.
+pub async fn is_deleted(db: &Db, puuid: &str) -> Result<bool, sqlx::Error> {
+    let deleted: Option<(String,)> = with_pool!(db, |pool| {
+        sqlx::query_as("select puuid from deleted_users where puuid = $1")
+            .bind(puuid)
+            .fetch_optional(pool)
+            .await?
+    });
+    Ok(deleted.is_some())
+}
+
+/// Starts or stops refreshing a user on a schedule, a newly tracked user is
+/// refreshed right away.
+pub async fn set_tracked(db: &Db, puuid: &str, tracked: bool) -> Result<(), sqlx::Error> {
+    let query = if tracked {
+        "update users set tracked_at = coalesce(tracked_at, $2),
+                          next_refresh_at = coalesce(next_refresh_at, $2)
+         where puuid = $1"
+    } else {
+        "update users set tracked_at = null, next_refresh_at = null where puuid = $1"
+    };
+    with_pool!(db, |pool| {
+        sqlx::query(query)
+            .bind(puuid)
+            .bind(Utc::now())
+            .execute(pool)
+            .await?;
+    });
+    Ok(())
+}
+
+/// Records an update asked for from the profile, unless the previous one was
+/// less than `cooldown` ago. Returns whether it was recorded.
+pub async fn request_update(
+    db: &Db,
+    puuid: &str,
+    cooldown: TimeDelta,
+) -> Result<bool, sqlx::Error> {
+    let now = Utc::now();
+    let requested = with_pool!(db, |pool| {
+        sqlx::query(
+            "update users set update_requested_at = $2
+             where puuid = $1 and (update_requested_at is null or update_requested_at <= $3)",
+        )
+        .bind(puuid)
+        .bind(now)
+        .bind(now - cooldown)
+        .execute(pool)
+        .await?
+        .rows_affected()
+    });
+    Ok(requested > 0)
+}
+
+/// Tracked users whose refresh is due, most overdue first.
+pub async fn due_refreshes(db: &Db, limit: i64) -> Result<Vec<User>, sqlx::Error> {
+    with_pool!(db, |pool| {
+        sqlx::query_as(
+            "select * from users
+             where tracked_at is not null and next_refresh_at <= $1
+             order by next_refresh_at
+             limit $2",
+        )
+        .bind(Utc::now())
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+    })
+}
+
+pub async fn schedule_refresh(db: &Db, puuid: &str, at: DateTime<Utc>) -> Result<(), sqlx::Error> {
+    with_pool!(db, |pool| {
+        sqlx::query(
+            "update users set next_refresh_at = $2 where puuid = $1 and tracked_at is not null",
+        )
+        .bind(puuid)
+        .bind(at)
+        .execute(pool)
+        .await?;
+    });
+    Ok(())
+}
+
+/// Takes `requests` from the scheduled refresh budget of `day`. Returns false,
+/// taking nothing, when that would go over `quota`.
+pub async fn reserve_refresh_quota(
+    db: &Db,
+    day: NaiveDate,
+    requests: i32,
+    quota: i32,
+) -> Result<bool, sqlx::Error> {
+    if requests > quota {
+        return Ok(false);
+    }
+    let reserved = with_pool!(db, |pool| {
+        sqlx::query(
+            "insert into refresh_quota (day, requests) values ($1, $2)
+             on conflict (day) do update set requests = refresh_quota.requests + $2
+             where refresh_quota.requests + $2 <= $3",
+        )
+        .bind(day)
+        .bind(requests)
+        .bind(quota)
+        .execute(pool)
+        .await?
+        .rows_affected()
+    });
+    Ok(reserved > 0)
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct RankSnapshot {
+    pub id: i64,
+    pub queue_type: String,
+    pub tier: String,
+    pub division: String,
+    pub league_points: i32,
+    pub wins: i32,
+    pub losses: i32,
+    pub first_seen_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+}
+
+impl RankSnapshot {
+    pub fn same_rank(&self, rank: &LeagueV4) -> bool {
+        self.tier == rank.tier
+            && self.division == rank.rank
+            && self.league_points == rank.league_points
+            && self.wins == rank.wins
+            && self.losses == rank.losses
+    }
+}
+
+/// Stores a league-v4 fetch of `puuid`. A rank identical to the latest
+/// snapshot of its queue only extends that snapshot. A queue missing from the
+/// fetch is unranked: its snapshots stop being current.
+pub async fn record_ranks(db: &Db, puuid: &str, ranks: &[LeagueV4]) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    with_pool!(db, |pool| {
+        let mut transaction = pool.begin().await?;
+        for rank in ranks {
+            let latest: Option<RankSnapshot> = sqlx::query_as(
+                "select id, queue_type, tier, division, league_points, wins, losses,
+                        first_seen_at, last_seen_at
+                 from rank_snapshots where puuid = $1 and queue_type = $2
+                 order by first_seen_at desc limit 1",
+            )
+            .bind(&rank.puuid)
+            .bind(&rank.queue_type)
+            .fetch_optional(&mut *transaction)
+            .await?;
+            match latest {
+                Some(snapshot) if snapshot.same_rank(rank) => {
+                    sqlx::query("update rank_snapshots set last_seen_at = $2 where id = $1")
+                        .bind(snapshot.id)
+                        .bind(now)
+                        .execute(&mut *transaction)
+                        .await?;
+                }
+                _ => {
+                    sqlx::query(
+                    "insert into rank_snapshots (puuid, queue_type, tier, division, league_points,
+                                                 wins, losses, first_seen_at, last_seen_at)
+                     values ($1, $2, $3, $4, $5, $6, $7, $8, $8)",
+                )
+                .bind(&rank.puuid)
+                .bind(&rank.queue_type)
+                .bind(&rank.tier)
+                .bind(&rank.rank)
+                .bind(rank.league_points)
+                .bind(rank.wins)
+                .bind(rank.losses)
+                .bind(now)
+                .execute(&mut *transaction)
+                .await?;
+                }
+            }
+        }
+        sqlx::query("update users set ranks_fetched_at = $2 where puuid = $1")
+            .bind(puuid)
+            .bind(now)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await
+    })
+}
+
+/// Snapshots of the last `days` days, oldest first.
+pub async fn rank_history(
+    db: &Db,
+    puuid: &str,
+    days: i64,
+) -> Result<Vec<RankSnapshot>, sqlx::Error> {
+    with_pool!(db, |pool| {
+        sqlx::query_as(
+            "select id, queue_type, tier, division, league_points, wins, losses,
+                    first_seen_at, last_seen_at
+             from rank_snapshots where puuid = $1 and last_seen_at >= $2
+             order by first_seen_at",
+        )
+        .bind(puuid)
+        .bind(Utc::now() - chrono::Duration::days(days))
+        .fetch_all(pool)
+        .await
+    })
+}
+
+/// Ranks of the latest league-v4 fetch of a PUUID, one per ranked queue. None
+/// when it found the player unranked.
+pub async fn current_ranks(db: &Db, puuid: &str) -> Result<Vec<RankSnapshot>, sqlx::Error> {
+    with_pool!(db, |pool| {
+        sqlx::query_as(
+            "select id, queue_type, tier, division, league_points, wins, losses,
+                    first_seen_at, last_seen_at
+             from rank_snapshots
+             where puuid = $1
+             and last_seen_at = (select ranks_fetched_at from users where puuid = $1)
+             order by queue_type",
+        )
+        .bind(puuid)
+        .fetch_all(pool)
+        .await
+    })
+}
+
+/// Stores a champion-mastery-v4 fetch. Champions whose points changed since
+/// the last fetch get a snapshot.
+pub async fn record_masteries(
+    db: &Db,
+    puuid: &str,
+    masteries: &[ChampionMasteryV4],
+) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    with_pool!(db, |pool| {
+        let mut transaction = pool.begin().await?;
+        for mastery in masteries {
+            let stored: Option<(i32,)> = sqlx::query_as(
+                "select champion_points from champion_masteries
+                 where puuid = $1 and champion_id = $2",
+            )
+            .bind(puuid)
+            .bind(mastery.champion_id)
+            .fetch_optional(&mut *transaction)
+            .await?;
+            if stored.is_some_and(|(points,)| points == mastery.champion_points) {
+                continue;
+            }
+            sqlx::query(
+            "insert into champion_masteries (puuid, champion_id, champion_level, champion_points,
+                                             last_play_time, updated_at)
+             values ($1, $2, $3, $4, $5, $6)
+             on conflict (puuid, champion_id) do update
+             set champion_level = excluded.champion_level,
+                 champion_points = excluded.champion_points,
+                 last_play_time = excluded.last_play_time,
+                 updated_at = excluded.updated_at",
+        )
+        .bind(puuid)
+        .bind(mastery.champion_id)
+        .bind(mastery.champion_level)
+        .bind(mastery.champion_points)
+        .bind(mastery.last_play_time)
+        .bind(now)
+        .execute(&mut *transaction)
+        .await?;
+            sqlx::query(
+                "insert into champion_mastery_snapshots (puuid, champion_id, champion_level,
+                                                         champion_points, taken_at)
+                 values ($1, $2, $3, $4, $5)",
+            )
+            .bind(puuid)
+            .bind(mastery.champion_id)
+            .bind(mastery.champion_level)
+            .bind(mastery.champion_points)
+            .bind(now)
+            .execute(&mut *transaction)
+            .await?;
+        }
+        transaction.commit().await
+    })
+}
+
+/// Timeline events stored in `match_events`, the others are dropped.
+const STORED_EVENT_TYPES: [&str; 7] = [
+    "CHAMPION_KILL",
+    "ELITE_MONSTER_KILL",
+    "BUILDING_KILL",
+    "TURRET_PLATE_DESTROYED",
+    "ITEM_PURCHASED",
+    "ITEM_SOLD",
+    "SKILL_LEVEL_UP",
+];
+
+/// Rows per insert statement or ids per lookup, well below the bind parameter limit.
+const INSERT_CHUNK: usize = 500;
+
+/// Minute of a timeline frame. Frames are a few ms past each minute, the last
+/// one is at the end of the game.
+pub fn frame_minute(timestamp: i64, frame_interval: i64) -> i32 {
+    let interval = frame_interval.max(1);
+    ((timestamp + interval / 2) / interval) as i32
+}
+
+pub async fn has_timeline(db: &Db, match_id: &str) -> Result<bool, sqlx::Error> {
+    let (stored,): (bool,) = with_pool!(db, |pool| {
+        sqlx::query_as("select exists (select 1 from match_timelines where match_id = $1)")
+            .bind(match_id)
+            .fetch_one(pool)
+            .await
+    })?;
+    Ok(stored)
+}
+
+/// Stores the frames and events of a timeline, replacing what is stored. Its
+/// match must be stored first.
+pub async fn ingest_timeline(db: &Db, timeline: &MatchV5Timeline) -> Result<(), sqlx::Error> {
+    let match_id = &timeline.metadata.match_id;
+    with_pool!(db, |pool| {
+        let mut transaction = pool.begin().await?;
+        sqlx::query(
+            "insert into match_timelines (match_id, frame_interval, ingested_at)
+             values ($1, $2, $3)
+             on conflict (match_id) do update
+             set frame_interval = excluded.frame_interval, ingested_at = excluded.ingested_at",
+        )
+        .bind(match_id)
+        .bind(timeline.info.frame_interval)
+        .bind(Utc::now())
+        .execute(&mut *transaction)
+        .await?;
+        for table in ["match_frames", "match_events"] {
+            sqlx::query(&format!("delete from {} where match_id = $1", table))
+                .bind(match_id)
+                .execute(&mut *transaction)
+                .await?;
+        }
+
+        let mut frames = Vec::new();
+        let mut events = Vec::new();
+        for frame in &timeline.info.frames {
+            let minute = frame_minute(frame.timestamp, timeline.info.frame_interval);
+            frames.extend(frame.participant_frames.values().map(|pf| (minute, pf)));
+            events.extend(
+                frame
+                    .events
+                    .iter()
+                    .filter(|event| STORED_EVENT_TYPES.contains(&event.event_type.as_str())),
+            );
+        }
+        // Two frames can round to the same minute at the end of a game
+        frames.sort_by_key(|(minute, pf)| (pf.participant_id, *minute));
+        frames.dedup_by_key(|(minute, pf)| (pf.participant_id, *minute));
+
+        for chunk in frames.chunks(INSERT_CHUNK) {
+            let mut insert = QueryBuilder::new(
+                "insert into match_frames (match_id, participant_id, minute, current_gold,
+                                           total_gold, xp, level, minions_killed,
+                                           jungle_minions_killed, position_x, position_y) ",
+            );
+            insert.push_values(chunk, |mut row, (minute, pf)| {
+                row.push_bind(match_id)
+                    .push_bind(pf.participant_id)
+                    .push_bind(*minute)
+                    .push_bind(pf.current_gold)
+                    .push_bind(pf.total_gold)
+                    .push_bind(pf.xp)
+                    .push_bind(pf.level)
+                    .push_bind(pf.minions_killed)
+                    .push_bind(pf.jungle_minions_killed)
+                    .push_bind(pf.position.as_ref().map(|position| position.x))
+                    .push_bind(pf.position.as_ref().map(|position| position.y));
+            });
+            insert.build().execute(&mut *transaction).await?;
+        }
+        for chunk in events.chunks(INSERT_CHUNK) {
+            let mut insert = QueryBuilder::new(
+                "insert into match_events (match_id, game_time, event_type, participant_id,
+                                           victim_id, assisting_participant_ids, team_id,
+                                           killer_team_id, item_id, skill_slot, monster_type,
+                                           monster_sub_type, building_type, lane_type, tower_type,
+                                           position_x, position_y) ",
+            );
+            insert.push_values(chunk, |mut row, event| {
+                row.push_bind(match_id)
+                    .push_bind(event.timestamp)
+                    .push_bind(&event.event_type)
+                    .push_bind(event.participant_id.or(event.killer_id))
+                    .push_bind(event.victim_id)
+                    .push_bind(event.assisting_participant_ids.as_ref().map(Json))
+                    .push_bind(event.team_id)
+                    .push_bind(event.killer_team_id)
+                    .push_bind(event.item_id)
+                    .push_bind(event.skill_slot)
+                    .push_bind(&event.monster_type)
+                    .push_bind(&event.monster_sub_type)
+                    .push_bind(&event.building_type)
+                    .push_bind(&event.lane_type)
+                    .push_bind(&event.tower_type)
+                    .push_bind(event.position.as_ref().map(|position| position.x))
+                    .push_bind(event.position.as_ref().map(|position| position.y));
+            });
+            insert.build().execute(&mut *transaction).await?;
+        }
+        transaction.commit().await
+    })
+}
+
+/// Mastery points a champion gained between a snapshot and the one before.
+#[derive(Debug, sqlx::FromRow)]
+pub struct MasteryGain {
+    pub champion_id: i32,
+    pub taken_at: DateTime<Utc>,
+    pub points: i32,
+}
+
+/// Gains of every champion of a PUUID snapshotted since `since`, oldest first.
+pub async fn mastery_gains(
+    db: &Db,
+    puuid: &str,
+    since: DateTime<Utc>,
+) -> Result<Vec<MasteryGain>, sqlx::Error> {
+    with_pool!(db, |pool| {
+        sqlx::query_as(
+            "select champion_id, taken_at, points from (
+               select champion_id, taken_at,
+                      champion_points - lag(champion_points)
+                        over (partition by champion_id order by taken_at) as points
+               from champion_mastery_snapshots where puuid = $1
+             ) gains
+             where points is not null and taken_at >= $2
+             order by taken_at",
+        )
+        .bind(puuid)
+        .bind(since)
+        .fetch_all(pool)
+        .await
+    })
+}
+
+/// Stores a match with its teams, bans and participants. Ingesting a match
+/// again replaces what is stored, so it can be reprocessed from `raw`. A match
+/// of a deleted player is stored without their PUUID and riot id, and without
+/// its raw json which holds them too.
+pub async fn ingest_match(
+    db: &Db,
+    lol_match: &MatchV5Match,
+    raw: &serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    let info = &lol_match.info;
+    let match_id = &lol_match.metadata.match_id;
+    let now = Utc::now();
+    with_pool!(db, |pool| {
+        let mut transaction = pool.begin().await?;
+        let mut query = QueryBuilder::new("select puuid from deleted_users where puuid in (");
+        let mut puuids = query.separated(", ");
+        for participant in &info.participants {
+            puuids.push_bind(&participant.puuid);
+        }
+        query.push(")");
+        let deleted: HashSet<String> = query
+            .build_query_as::<(String,)>()
+            .fetch_all(&mut *transaction)
+            .await?
+            .into_iter()
+            .map(|(puuid,)| puuid)
+            .collect();
+        let (raw, raw_pruned_at) = if deleted.is_empty() {
+            (raw, None)
+        } else {
+            (&serde_json::Value::Null, Some(now))
+        };
+        sqlx::query(
+            "insert into matches (match_id, platform_id, queue_id, map_id, game_mode, game_type,
+                                  game_version, game_creation, game_start_timestamp,
+                                  game_end_timestamp, game_duration, end_of_game_result, raw,
+                                  raw_pruned_at, ingested_at)
+             values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)
+             on conflict (match_id) do update
+             set platform_id = excluded.platform_id, queue_id = excluded.queue_id,
+                 map_id = excluded.map_id, game_mode = excluded.game_mode,
+                 game_type = excluded.game_type, game_version = excluded.game_version,
+                 game_creation = excluded.game_creation,
+                 game_start_timestamp = excluded.game_start_timestamp,
+                 game_end_timestamp = excluded.game_end_timestamp,
+                 game_duration = excluded.game_duration,
+                 end_of_game_result = excluded.end_of_game_result, raw = excluded.raw,
+                 raw_pruned_at = excluded.raw_pruned_at, ingested_at = excluded.ingested_at",
+        )
+        .bind(match_id)
+        .bind(&info.platform_id)
+        .bind(info.queue_id)
+        .bind(info.map_id)
+        .bind(&info.game_mode)
+        .bind(&info.game_type)
+        .bind(&info.game_version)
+        .bind(info.game_creation)
+        .bind(info.game_start_timestamp)
+        .bind(info.game_end_timestamp)
+        .bind(info.game_duration)
+        .bind(&info.end_of_game_result)
+        .bind(Json(raw))
+        .bind(raw_pruned_at)
+        .bind(now)
+        .execute(&mut *transaction)
+        .await?;
+        for table in ["match_teams", "match_bans", "match_participants"] {
+            sqlx::query(&format!("delete from {} where match_id = $1", table))
+                .bind(match_id)
+                .execute(&mut *transaction)
+                .await?;
+        }
+
+        for team in &info.teams {
+            let objectives = &team.objectives;
+            sqlx::query(
+                "insert into match_teams (match_id, team_id, win, baron_kills, champion_kills,
+                                          dragon_kills, horde_kills, inhibitor_kills,
+                                          rift_herald_kills, tower_kills, first_baron, first_blood,
+                                          first_dragon, first_inhibitor, first_rift_herald,
+                                          first_tower)
+                 values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)",
+            )
+            .bind(match_id)
+            .bind(team.team_id)
+            .bind(team.win)
+            .bind(objectives.baron.kills)
+            .bind(objectives.champion.kills)
+            .bind(objectives.dragon.kills)
+            .bind(objectives.horde.kills)
+            .bind(objectives.inhibitor.kills)
+            .bind(objectives.rift_herald.kills)
+            .bind(objectives.tower.kills)
+            .bind(objectives.baron.first)
+            .bind(objectives.champion.first)
+            .bind(objectives.dragon.first)
+            .bind(objectives.inhibitor.first)
+            .bind(objectives.rift_herald.first)
+            .bind(objectives.tower.first)
+            .execute(&mut *transaction)
+            .await?;
+            for ban in &team.bans {
+                sqlx::query(
+                    "insert into match_bans (match_id, team_id, pick_turn, champion_id)
+                     values ($1, $2, $3, $4)",
+                )
+                .bind(match_id)
+                .bind(team.team_id)
+                .bind(ban.pick_turn)
+                .bind(ban.champion_id)
+                .execute(&mut *transaction)
+                .await?;
+            }
+        }
+
+        for participant in &info.participants {
+            let blank = deleted.contains(&participant.puuid);
+            let identity = |value: &String| if blank { String::new() } else { value.clone() };
+            sqlx::query(
+                "insert into match_participants (
+                     match_id, participant_id, puuid, team_id, riot_id_game_name,
+                     riot_id_tagline, champion_id, champion_name, champ_level,
+                     team_position, individual_position, win, kills, deaths,
+                     assists, total_minions_killed, neutral_minions_killed,
+                     gold_earned, total_damage_dealt_to_champions,
+                     total_damage_taken, vision_score, wards_placed, wards_killed,
+                     item0, item1, item2, item3, item4, item5, item6, summoner1_id,
+                     summoner2_id, double_kills, triple_kills, quadra_kills,
+                     penta_kills, time_played, all_in_pings, assist_me_pings,
+                     command_pings, enemy_missing_pings, enemy_vision_pings,
+                     get_back_pings, hold_pings, need_vision_pings, on_my_way_pings,
+                     push_pings, vision_cleared_pings)
+                 values (
+                     $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14,
+                     $15, $16, $17, $18, $19, $20, $21, $22, $23, $24, $25, $26,
+                     $27, $28, $29, $30, $31, $32, $33, $34, $35, $36, $37, $38,
+                     $39, $40, $41, $42, $43, $44, $45, $46, $47, $48)",
+            )
+            .bind(match_id)
+            .bind(participant.participant_id)
+            .bind(identity(&participant.puuid))
+            .bind(participant.team_id)
+            .bind(identity(&participant.riot_id_game_name))
+            .bind(identity(&participant.riot_id_tagline))
+            .bind(participant.champion_id)
+            .bind(&participant.champion_name)
+            .bind(participant.champ_level)
+            .bind(&participant.team_position)
+            .bind(&participant.individual_position)
+            .bind(participant.win)
+            .bind(participant.kills)
+            .bind(participant.deaths)
+            .bind(participant.assists)
+            .bind(participant.total_minions_killed)
+            .bind(participant.neutral_minions_killed)
+            .bind(participant.gold_earned)
+            .bind(participant.total_damage_dealt_to_champions)
+            .bind(participant.total_damage_taken)
+            .bind(participant.vision_score)
+            .bind(participant.wards_placed)
+            .bind(participant.wards_killed)
+            .bind(participant.item0)
+            .bind(participant.item1)
+            .bind(participant.item2)
+            .bind(participant.item3)
+            .bind(participant.item4)
+            .bind(participant.item5)
+            .bind(participant.item6)
+            .bind(participant.summoner1_id)
+            .bind(participant.summoner2_id)
+            .bind(participant.double_kills)
+            .bind(participant.triple_kills)
+            .bind(participant.quadra_kills)
+            .bind(participant.penta_kills)
+            .bind(participant.time_played)
+            .bind(participant.all_in_pings)
+            .bind(participant.assist_me_pings)
+            .bind(participant.command_pings)
+            .bind(participant.enemy_missing_pings)
+            .bind(participant.enemy_vision_pings)
+            .bind(participant.get_back_pings)
+            .bind(participant.hold_pings)
+            .bind(participant.need_vision_pings)
+            .bind(participant.on_my_way_pings)
+            .bind(participant.push_pings)
+            .bind(participant.vision_cleared_pings)
+            .execute(&mut *transaction)
+            .await?;
+        }
+        transaction.commit().await
+    })
+}
+
+/// A stored match as it was sent by Riot, unless its raw json was pruned.
+pub async fn stored_match(
+    db: &Db,
+    match_id: &str,
+) -> Result<Option<serde_json::Value>, sqlx::Error> {
+    let raw: Option<(Json<serde_json::Value>,)> = with_pool!(db, |pool| {
+        sqlx::query_as("select raw from matches where match_id = $1 and raw_pruned_at is null")
+            .bind(match_id)
+            .fetch_optional(pool)
+            .await
+    })?;
+    Ok(raw.map(|(raw,)| raw.0))
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct QueueStats {
+    pub queue_id: i32,
+    pub games: i64,
+    pub wins: i64,
+    pub kills: i64,
+    pub deaths: i64,
+    pub assists: i64,
+    /// Averages over the matches with a stored timeline
+    pub gold_at_15: Option<f64>,
+    pub cs_at_15: Option<f64>,
+}
+
+/// Totals of the stored matches of a PUUID per queue, most played first.
+pub async fn queue_stats(db: &Db, puuid: &str) -> Result<Vec<QueueStats>, sqlx::Error> {
+    with_pool!(db, |pool| {
+        sqlx::query_as(
+            "select matches.queue_id, count(*) as games,
+                    sum(case when match_participants.win then 1 else 0 end) as wins,
+                    sum(match_participants.kills) as kills,
+                    sum(match_participants.deaths) as deaths,
+                    sum(match_participants.assists) as assists,
+                    cast(avg(match_frames.total_gold) as double precision) as gold_at_15,
+                    cast(avg(match_frames.minions_killed + match_frames.jungle_minions_killed)
+                      as double precision) as cs_at_15
+             from match_participants
+             join matches on matches.match_id = match_participants.match_id
+             left join match_frames on match_frames.match_id = match_participants.match_id
+               and match_frames.participant_id = match_participants.participant_id
+               and match_frames.minute = 15
+             where match_participants.puuid = $1
+             group by matches.queue_id
+             order by games desc",
+        )
+        .bind(puuid)
+        .fetch_all(pool)
+        .await
+    })
+}
+
+/// Newest `game_start_timestamp` synced for a PUUID and queue.
+pub async fn sync_state(db: &Db, puuid: &str, queue_id: i32) -> Result<Option<i64>, sqlx::Error> {
+    let state: Option<(Option<i64>,)> = with_pool!(db, |pool| {
+        sqlx::query_as(
+            "select latest_game_start from match_sync_state where puuid = $1 and queue_id = $2",
+        )
+        .bind(puuid)
+        .bind(queue_id)
+        .fetch_optional(pool)
+        .await
+    })?;
+    Ok(state.and_then(|(latest_game_start,)| latest_game_start))
+}
+
+pub async fn record_sync_state(
+    db: &Db,
+    puuid: &str,
+    queue_id: i32,
+    latest_game_start: Option<i64>,
+) -> Result<(), sqlx::Error> {
+    with_pool!(db, |pool| {
+        sqlx::query(
+            "insert into match_sync_state (puuid, queue_id, latest_game_start, last_synced_at)
+             values ($1, $2, $3, $4)
+             on conflict (puuid, queue_id) do update
+             set latest_game_start = excluded.latest_game_start,
+                 last_synced_at = excluded.last_synced_at",
+        )
+        .bind(puuid)
+        .bind(queue_id)
+        .bind(latest_game_start)
+        .bind(Utc::now())
+        .execute(pool)
+        .await?;
+    });
+    Ok(())
+}
+
+/// Newest `game_start_timestamp` among the stored matches of a PUUID,
+/// optionally in one queue.
+pub async fn latest_stored_game_start(
+    db: &Db,
+    puuid: &str,
+    queue_id: Option<i32>,
+) -> Result<Option<i64>, sqlx::Error> {
+    let (latest,): (Option<i64>,) = with_pool!(db, |pool| {
+        sqlx::query_as(
+            "select max(matches.game_start_timestamp) from matches
+             join match_participants on match_participants.match_id = matches.match_id
+             where match_participants.puuid = $1 and ($2 is null or matches.queue_id = $2)",
+        )
+        .bind(puuid)
+        .bind(queue_id)
+        .fetch_one(pool)
+        .await
+    })?;
+    Ok(latest)
+}
+
+/// Ids of the latest stored matches of a PUUID, newest first.
+pub async fn recent_match_ids(
+    db: &Db,
+    puuid: &str,
+    queue_id: Option<i32>,
+    limit: i64,
+) -> Result<Vec<String>, sqlx::Error> {
+    let match_ids: Vec<(String,)> = with_pool!(db, |pool| {
+        sqlx::query_as(
+            "select matches.match_id from matches
+             join match_participants on match_participants.match_id = matches.match_id
+             where match_participants.puuid = $1 and ($2 is null or matches.queue_id = $2)
+             order by matches.game_start_timestamp desc
+             limit $3",
+        )
+        .bind(puuid)
+        .bind(queue_id)
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+    })?;
+    Ok(match_ids.into_iter().map(|(match_id,)| match_id).collect())
+}
+
+pub async fn game_start_timestamp(db: &Db, match_id: &str) -> Result<Option<i64>, sqlx::Error> {
+    let game_start: Option<(i64,)> = with_pool!(db, |pool| {
+        sqlx::query_as("select game_start_timestamp from matches where match_id = $1")
+            .bind(match_id)
+            .fetch_optional(pool)
+            .await
+    })?;
+    Ok(game_start.map(|(game_start,)| game_start))
+}
+
+/// The ids in `match_ids` that are not stored yet, in the same order.
+pub async fn unknown_match_ids(db: &Db, match_ids: &[String]) -> Result<Vec<String>, sqlx::Error> {
+    let mut stored = HashSet::new();
+    for chunk in match_ids.chunks(INSERT_CHUNK) {
+        let rows: Vec<(String,)> = with_pool!(db, |pool| {
+            let mut query = QueryBuilder::new("select match_id from matches where match_id in (");
+            let mut ids = query.separated(", ");
+            for match_id in chunk {
+                ids.push_bind(match_id);
+            }
+            query.push(")");
+            query.build_query_as().fetch_all(pool).await
+        })?;
+        stored.extend(rows.into_iter().map(|(match_id,)| match_id));
+    }
+    Ok(match_ids
+        .iter()
+        .filter(|match_id| !stored.contains(*match_id))
+        .cloned()
+        .collect())
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Backfill {
+    pub puuid: String,
+    pub large_region: String,
+    pub horizon: i64,
+    pub end_time: i64,
+    pub cursor: i32,
+    pub matches_stored: i32,
+    pub covered_until: Option<i64>,
+    /// "running", "done" or "failed"
+    pub status: String,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Backfill {
+    /// Share of the time between horizon and end time gone through, 0 to 100.
+    pub fn percent(&self) -> i64 {
+        if self.status == "done" {
+            return 100;
+        }
+        let Some(covered_until) = self.covered_until else {
+            return 0;
+        };
+        let span = (self.end_time - self.horizon).max(1);
+        ((self.end_time - covered_until / 1000) * 100 / span).clamp(0, 99)
+    }
+}
+
+/// Creates a backfill unless the PUUID already has one.
+pub async fn create_backfill(
+    db: &Db,
+    puuid: &str,
+    large_region: &LargeRegion,
+    horizon: i64,
+) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    with_pool!(db, |pool| {
+        sqlx::query(
+            "insert into backfills (puuid, large_region, horizon, end_time, status, created_at,
+                                    updated_at)
+             values ($1, $2, $3, $4, 'running', $5, $5)
+             on conflict (puuid) do nothing",
+        )
+        .bind(puuid)
+        .bind(large_region.to_string())
+        .bind(horizon)
+        .bind(now.timestamp())
+        .bind(now)
+        .execute(pool)
+        .await?;
+    });
+    Ok(())
+}
+
+pub async fn backfill(db: &Db, puuid: &str) -> Result<Option<Backfill>, sqlx::Error> {
+    with_pool!(db, |pool| {
+        sqlx::query_as("select * from backfills where puuid = $1")
+            .bind(puuid)
+            .fetch_optional(pool)
+            .await
+    })
+}
+
+/// Backfills that were running when the server stopped.
+pub async fn running_backfills(db: &Db) -> Result<Vec<Backfill>, sqlx::Error> {
+    with_pool!(db, |pool| {
+        sqlx::query_as("select * from backfills where status = 'running'")
+            .fetch_all(pool)
+            .await
+    })
+}
+
+pub async fn record_backfill_progress(db: &Db, backfill: &Backfill) -> Result<(), sqlx::Error> {
+    with_pool!(db, |pool| {
+        sqlx::query(
+            "update backfills
+             set cursor = $2, matches_stored = $3, covered_until = $4, status = $5, error = $6,
+                 updated_at = $7
+             where puuid = $1",
+        )
+        .bind(&backfill.puuid)
+        .bind(backfill.cursor)
+        .bind(backfill.matches_stored)
+        .bind(backfill.covered_until)
+        .bind(&backfill.status)
+        .bind(&backfill.error)
+        .bind(Utc::now())
+        .execute(pool)
+        .await?;
+    });
+    Ok(())
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Job {
+    pub id: i64,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    /// Including the current run
+    pub attempts: i32,
+}
+
+/// Queues a job to run now, unless the same one is already queued or running.
+/// Returns whether it was queued.
+pub async fn enqueue_job(
+    db: &Db,
+    kind: &str,
+    payload: &serde_json::Value,
+    priority: i32,
+) -> Result<bool, sqlx::Error> {
+    let now = Utc::now();
+    let queued = with_pool!(db, |pool| {
+        sqlx::query(
+            "insert into jobs (kind, payload, priority, run_after, created_at)
+             values ($1, $2, $3, $4, $4)
+             on conflict (kind, payload) where failed_at is null do nothing",
+        )
+        .bind(kind)
+        .bind(payload)
+        .bind(priority)
+        .bind(now)
+        .execute(pool)
+        .await?
+        .rows_affected()
+    });
+    Ok(queued > 0)
+}
+
+/// Locks the most urgent job that is due. A lock older than `locked_before` was
+/// left by a worker that is gone, its job is taken over. On Postgres, jobs
+/// locked by other workers are skipped rather than waited for. SQLite has a
+/// single writer, so the update alone is enough there.
+pub async fn claim_job(db: &Db, locked_before: DateTime<Utc>) -> Result<Option<Job>, sqlx::Error> {
+    let lock = match db {
+        Db::Postgres(_) => "for update skip locked",
+        Db::Sqlite(_) => "",
+    };
+    let query = format!(
+        "update jobs set locked_at = $1, attempts = attempts + 1
+         where id = (
+           select id from jobs
+           where (locked_at is null or locked_at < $2) and failed_at is null and run_after <= $1
+           order by priority desc, run_after
+           limit 1
+           {}
+         )
+         returning *",
+        lock
+    );
+    with_pool!(db, |pool| {
+        sqlx::query_as(&query)
+            .bind(Utc::now())
+            .bind(locked_before)
+            .fetch_optional(pool)
+            .await
+    })
+}
+
+/// Renews the lock of a running job, so it is not taken over as abandoned.
+pub async fn heartbeat_job(db: &Db, id: i64) -> Result<(), sqlx::Error> {
+    with_pool!(db, |pool| {
+        sqlx::query("update jobs set locked_at = $2 where id = $1 and locked_at is not null")
+            .bind(id)
+            .bind(Utc::now())
+            .execute(pool)
+            .await?;
+    });
+    Ok(())
+}
+
+pub async fn complete_job(db: &Db, id: i64) -> Result<(), sqlx::Error> {
+    with_pool!(db, |pool| {
+        sqlx::query("delete from jobs where id = $1")
+            .bind(id)
+            .execute(pool)
+            .await?;
+    });
+    Ok(())
+}
+
+/// Unlocks a job that made progress so it runs again, its attempts start over.
+pub async fn requeue_job(db: &Db, id: i64) -> Result<(), sqlx::Error> {
+    with_pool!(db, |pool| {
+        sqlx::query(
+            "update jobs set locked_at = null, attempts = 0, last_error = null, run_after = $2
+             where id = $1",
+        )
+        .bind(id)
+        .bind(Utc::now())
+        .execute(pool)
+        .await?;
+    });
+    Ok(())
+}
+
+/// Unlocks a failed job to run again at `run_after`, or marks it failed for
+/// good when `run_after` is None.
+pub async fn fail_job(
+    db: &Db,
+    id: i64,
+    error: &str,
+    run_after: Option<DateTime<Utc>>,
+) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    with_pool!(db, |pool| {
+        sqlx::query(
+            "update jobs
+             set locked_at = null, last_error = $2, run_after = coalesce($3, run_after),
+                 failed_at = case when $3 is null then $4 end
+             where id = $1",
+        )
+        .bind(id)
+        .bind(error)
+        .bind(run_after)
+        .bind(now)
+        .execute(pool)
+        .await?;
+    });
+    Ok(())
+}
+
+/// Whether jobs of one of `kinds` are running or due for `puuid`. Jobs waiting
+/// to be retried after a failure are not counted.
+pub async fn has_pending_jobs(db: &Db, puuid: &str, kinds: &[&str]) -> Result<bool, sqlx::Error> {
+    let (pending,): (bool,) = with_pool!(db, |pool| {
+        // SQLite has no arrays, so no `kind = any($2)`
+        let mut query = QueryBuilder::new(
+            "select exists (
+               select 1 from jobs
+               where failed_at is null and payload ->> 'puuid' = ",
+        );
+        query
+            .push_bind(puuid)
+            .push(" and (locked_at is not null or run_after <= ")
+            .push_bind(Utc::now())
+            .push(") and kind in (");
+        let mut separated = query.separated(", ");
+        for kind in kinds {
+            separated.push_bind(*kind);
+        }
+        query.push("))");
+        query.build_query_as().fetch_one(pool).await
+    })?;
+    Ok(pending)
+}
+
+/// What a retention step reclaimed. Bytes are the size the database stored
+/// the rows with, None when it cannot tell: SQLite has no per row size, so
+/// only its raw json is measured.
+#[derive(Debug, Clone, Copy)]
+pub struct Reclaimed {
+    pub rows: u64,
+    pub bytes: Option<i64>,
+}
+
+impl Default for Reclaimed {
+    fn default() -> Self {
+        Reclaimed {
+            rows: 0,
+            bytes: Some(0),
+        }
+    }
+}
+
+impl Reclaimed {
+    pub fn add(&mut self, other: Reclaimed) {
+        self.rows += other.rows;
+        self.bytes = self
+            .bytes
+            .zip(other.bytes)
+            .map(|(bytes, more)| bytes + more);
+    }
+}
+
+/// Matches pruned per statement.
+const PRUNE_BATCH: i64 = 500;
+
+/// Size in bytes of `raw` as stored, after compression on Postgres. SQLite
+/// stores it as plain text.
+fn raw_size(db: &Db) -> &'static str {
+    match db {
+        Db::Postgres(_) => "cast(pg_column_size(raw) as bigint)",
+        Db::Sqlite(_) => "length(cast(raw as blob))",
+    }
+}
+
+/// Replaces the raw json of up to [`PRUNE_BATCH`] matches ingested before
+/// `before` by json null, skipping matches a tracked player played in. The
+/// normalized rows are kept. Nothing left to prune once it reclaims no rows.
+pub async fn prune_raw_matches(db: &Db, before: DateTime<Utc>) -> Result<Reclaimed, sqlx::Error> {
+    let query = format!(
+        "select match_id, {} from matches m
+         where raw_pruned_at is null and ingested_at < $1
+           and not exists (
+             select 1 from match_participants p join users u on u.puuid = p.puuid
+             where p.match_id = m.match_id and u.tracked_at is not null
+           )
+         limit $2",
+        raw_size(db)
+    );
+    let batch: Vec<(String, i64)> = with_pool!(db, |pool| {
+        let mut transaction = pool.begin().await?;
+        let batch: Vec<(String, i64)> = sqlx::query_as(&query)
+            .bind(before)
+            .bind(PRUNE_BATCH)
+            .fetch_all(&mut *transaction)
+            .await?;
+        if !batch.is_empty() {
+            let mut update = QueryBuilder::new("update matches set raw = ");
+            update
+                .push_bind(Json(serde_json::Value::Null))
+                .push(", raw_pruned_at = ")
+                .push_bind(Utc::now())
+                .push(" where match_id in (");
+            let mut separated = update.separated(", ");
+            for (match_id, _) in &batch {
+                separated.push_bind(match_id);
+            }
+            update.push(")");
+            update.build().execute(&mut *transaction).await?;
+        }
+        transaction.commit().await?;
+        batch
+    });
+    Ok(Reclaimed {
+        rows: batch.len() as u64,
+        bytes: Some(batch.iter().map(|(_, bytes)| bytes).sum()),
+    })
+}
+
+/// Deletes up to [`PRUNE_BATCH`] timelines ingested before `before` with their
+/// frames and events, skipping matches a tracked player played in. Their size
+/// is only measured on Postgres.
+pub async fn prune_timelines(db: &Db, before: DateTime<Utc>) -> Result<Reclaimed, sqlx::Error> {
+    let measured = matches!(db, Db::Postgres(_));
+    let reclaimed = with_pool!(db, |pool| {
+        let mut transaction = pool.begin().await?;
+        let batch: Vec<(String,)> = sqlx::query_as(
+            "select match_id from match_timelines t
+             where ingested_at < $1
+               and not exists (
+                 select 1 from match_participants p join users u on u.puuid = p.puuid
+                 where p.match_id = t.match_id and u.tracked_at is not null
+               )
+             limit $2",
+        )
+        .bind(before)
+        .bind(PRUNE_BATCH)
+        .fetch_all(&mut *transaction)
+        .await?;
+        let mut reclaimed = Reclaimed {
+            rows: 0,
+            bytes: measured.then_some(0),
+        };
+        if !batch.is_empty() {
+            // Frames and events go with their timeline, deleted first to be counted
+            for table in ["match_frames", "match_events", "match_timelines"] {
+                if measured {
+                    let mut size = QueryBuilder::new(format!(
+                        "select cast(coalesce(sum(pg_column_size(t.*)), 0) as bigint)
+                         from {} t where match_id in (",
+                        table
+                    ));
+                    let mut separated = size.separated(", ");
+                    for (match_id,) in &batch {
+                        separated.push_bind(match_id);
+                    }
+                    size.push(")");
+                    let (bytes,): (i64,) =
+                        size.build_query_as().fetch_one(&mut *transaction).await?;
+                    reclaimed.bytes = reclaimed.bytes.map(|total| total + bytes);
+                }
+                let mut delete =
+                    QueryBuilder::new(format!("delete from {} where match_id in (", table));
+                let mut separated = delete.separated(", ");
+                for (match_id,) in &batch {
+                    separated.push_bind(match_id);
+                }
+                delete.push(")");
+                reclaimed.rows += delete
+                    .build()
+                    .execute(&mut *transaction)
+                    .await?
+                    .rows_affected();
+            }
+        }
+        transaction.commit().await?;
+        reclaimed
+    });
+    Ok(reclaimed)
+}
+
+/// Deletes everything stored about a player. Matches stay for the other
+/// participants, but lose their raw json and the player's PUUID and riot id.
+pub async fn delete_user(db: &Db, puuid: &str) -> Result<Reclaimed, sqlx::Error> {
+    let played = "match_id in (select match_id from match_participants where puuid = $1)";
+    let size_query = format!(
+        "select cast(coalesce(sum({}), 0) as bigint) from matches
+         where raw_pruned_at is null and {}",
+        raw_size(db),
+        played
+    );
+    let prune_query = format!(
+        "update matches set raw = $2, raw_pruned_at = $3 where raw_pruned_at is null and {}",
+        played
+    );
+    let reclaimed = with_pool!(db, |pool| {
+        let mut transaction = pool.begin().await?;
+        let (bytes,): (i64,) = sqlx::query_as(&size_query)
+            .bind(puuid)
+            .fetch_one(&mut *transaction)
+            .await?;
+        let mut rows = sqlx::query(&prune_query)
+            .bind(puuid)
+            .bind(Json(serde_json::Value::Null))
+            .bind(Utc::now())
+            .execute(&mut *transaction)
+            .await?
+            .rows_affected();
+        rows += sqlx::query(
+            "update match_participants set puuid = '', riot_id_game_name = '', riot_id_tagline = ''
+             where puuid = $1",
+        )
+        .bind(puuid)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+        rows += sqlx::query("delete from jobs where payload ->> 'puuid' = $1")
+            .bind(puuid)
+            .execute(&mut *transaction)
+            .await?
+            .rows_affected();
+        // These go with the user, deleted first to be counted
+        for table in [
+            "riot_id_history",
+            "rank_snapshots",
+            "match_sync_state",
+            "backfills",
+            "champion_masteries",
+            "champion_mastery_snapshots",
+            "users",
+        ] {
+            rows += sqlx::query(&format!("delete from {} where puuid = $1", table))
+                .bind(puuid)
+                .execute(&mut *transaction)
+                .await?
+                .rows_affected();
+        }
+        // Kept so refetching one of their matches does not store them again
+        sqlx::query(
+            "insert into deleted_users (puuid, deleted_at) values ($1, $2)
+             on conflict (puuid) do nothing",
+        )
+        .bind(puuid)
+        .bind(Utc::now())
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        Reclaimed {
+            rows,
+            bytes: Some(bytes),
+        }
+    });
+    Ok(reclaimed)
+}
+
+/// Which stored matches an export covers, every filter is optional.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct ExportFilter {
+    /// Platform id like EUW1
+    pub region: Option<String>,
+    pub queue: Option<i32>,
+    /// Major and minor version like 15.7
+    pub patch: Option<String>,
+    /// First day of games created, UTC
+    pub from: Option<NaiveDate>,
+    /// Last day of games created, UTC
+    pub to: Option<NaiveDate>,
+}
+
+impl ExportFilter {
+    fn platform_id(&self) -> Option<String> {
+        self.region.as_ref().map(|region| region.to_uppercase())
+    }
+
+    fn version_prefix(&self) -> Option<String> {
+        self.patch.as_ref().map(|patch| format!("{}.", patch))
+    }
+
+    /// `game_creation` range in ms, `to` included.
+    fn created_from(&self) -> Option<i64> {
+        self.from
+            .map(|day| day.and_time(NaiveTime::MIN).and_utc().timestamp_millis())
+    }
+
+    fn created_before(&self) -> Option<i64> {
+        self.to
+            .and_then(|day| day.succ_opt())
+            .map(|day| day.and_time(NaiveTime::MIN).and_utc().timestamp_millis())
+    }
+
+    /// Whether the filter covers a match, for exports not done in SQL.
+    pub fn covers(&self, row: &MatchRow) -> bool {
+        self.platform_id()
+            .is_none_or(|platform_id| row.platform_id == platform_id)
+            && self.queue.is_none_or(|queue| row.queue_id == queue)
+            && self
+                .version_prefix()
+                .is_none_or(|prefix| row.game_version.starts_with(&prefix))
+            && self
+                .created_from()
+                .is_none_or(|from| row.game_creation >= from)
+            && self
+                .created_before()
+                .is_none_or(|before| row.game_creation < before)
+    }
+}
+
+/// A row of the matches export, documented in the readme. Fields are the
+/// columns in order, new ones are only ever appended.
+#[derive(Debug, Default, Clone, Serialize, sqlx::FromRow)]
+pub struct MatchRow {
+    pub match_id: String,
+    pub platform_id: String,
+    pub queue_id: i32,
+    pub map_id: i32,
+    pub game_mode: String,
+    pub game_type: String,
+    pub game_version: String,
+    pub game_creation: i64,
+    pub game_start_timestamp: i64,
+    pub game_end_timestamp: Option<i64>,
+    pub game_duration: i64,
+    pub end_of_game_result: String,
+}
+
+/// A row of the participants export, with the match columns filters apply to.
+/// Fields are the columns in order, new ones are only ever appended.
+#[derive(Debug, Default, Clone, Serialize, sqlx::FromRow)]
+pub struct ParticipantRow {
+    pub match_id: String,
+    pub platform_id: String,
+    pub queue_id: i32,
+    pub game_version: String,
+    pub game_creation: i64,
+    pub participant_id: i32,
+    pub puuid: String,
+    pub riot_id_game_name: String,
+    pub riot_id_tagline: String,
+    pub team_id: i32,
+    pub champion_id: i32,
+    pub champion_name: String,
+    pub champ_level: i32,
+    pub team_position: String,
+    pub individual_position: String,
+    pub win: bool,
+    pub kills: i32,
+    pub deaths: i32,
+    pub assists: i32,
+    pub total_minions_killed: i32,
+    pub neutral_minions_killed: i32,
+    pub gold_earned: i32,
+    pub total_damage_dealt_to_champions: i32,
+    pub total_damage_taken: i32,
+    pub vision_score: i32,
+    pub wards_placed: i32,
+    pub wards_killed: i32,
+    pub item0: i32,
+    pub item1: i32,
+    pub item2: i32,
+    pub item3: i32,
+    pub item4: i32,
+    pub item5: i32,
+    pub item6: i32,
+    pub summoner1_id: i32,
+    pub summoner2_id: i32,
+    pub time_played: i32,
+}
+
+/// Rows per page of an export read from SQLite. Its only connection is
+/// released between pages, so a slow client does not hold up page views and
+/// jobs.
+const EXPORT_PAGE: i64 = 1000;
+
+/// Reads an export a page at a time, each page starting after the `key` of
+/// the last row of the previous one.
+fn export_pages<'a, T, K>(
+    page: impl Fn(Option<K>) -> BoxFuture<'a, Result<Vec<T>, sqlx::Error>> + Send + 'a,
+    key: fn(&T) -> K,
+) -> BoxStream<'a, Result<T, sqlx::Error>>
+where
+    T: Send + 'a,
+    K: Send + 'a,
+{
+    // None once the last page was read
+    stream::try_unfold(Some(None), move |after| {
+        let rows = after.map(&page);
+        async move {
+            let Some(rows) = rows else {
+                return Ok(None);
+            };
+            let rows: Vec<T> = rows.await?;
+            let next = (rows.len() as i64 == EXPORT_PAGE).then(|| rows.last().map(key));
+            Ok::<_, sqlx::Error>(Some((stream::iter(rows.into_iter().map(Ok)), next)))
+        }
+    })
+    .try_flatten()
+    .boxed()
+}
+
+/// Streams the matches covered by `filter`, oldest first. Postgres streams
+/// them from a single query, SQLite a page at a time.
+pub fn export_matches<'a>(
+    db: &'a Db,
+    filter: &ExportFilter,
+) -> BoxStream<'a, Result<MatchRow, sqlx::Error>> {
+    let paged = matches!(db, Db::Sqlite(_));
+    let filter = filter.clone();
+    with_pool!(db, |pool| {
+        let page = move |after: Option<(i64, String)>| {
+            sqlx::query_as(
+                "select match_id, platform_id, queue_id, map_id, game_mode, game_type,
+                        game_version, game_creation, game_start_timestamp, game_end_timestamp,
+                        game_duration, end_of_game_result
+                 from matches m
+                 where ($1 is null or m.platform_id = $1)
+                   and ($2 is null or m.queue_id = $2)
+                   and ($3 is null or m.game_version like $3 || '%')
+                   and ($4 is null or m.game_creation >= $4)
+                   and ($5 is null or m.game_creation < $5)
+                   and ($6 is null or (m.game_creation, m.match_id) > ($6, $7))
+                 order by game_creation, match_id
+                 limit $8",
+            )
+            .bind(filter.platform_id())
+            .bind(filter.queue)
+            .bind(filter.version_prefix())
+            .bind(filter.created_from())
+            .bind(filter.created_before())
+            .bind(after.as_ref().map(|(game_creation, _)| *game_creation))
+            .bind(after.map(|(_, match_id)| match_id))
+            .bind(paged.then_some(EXPORT_PAGE))
+        };
+        if paged {
+            export_pages(
+                move |after| page(after).fetch_all(pool).boxed(),
+                |row: &MatchRow| (row.game_creation, row.match_id.clone()),
+            )
+        } else {
+            page(None).fetch(pool)
+        }
+    })
+}
+
+/// Streams the participants of the matches covered by `filter`, oldest match
+/// first. Postgres streams them from a single query, SQLite a page at a time.
+pub fn export_participants<'a>(
+    db: &'a Db,
+    filter: &ExportFilter,
+) -> BoxStream<'a, Result<ParticipantRow, sqlx::Error>> {
+    let paged = matches!(db, Db::Sqlite(_));
+    let filter = filter.clone();
+    with_pool!(db, |pool| {
+        let page = move |after: Option<(i64, String, i32)>| {
+            sqlx::query_as(
+                "select m.match_id, m.platform_id, m.queue_id, m.game_version, m.game_creation,
+                        p.participant_id, p.puuid, p.riot_id_game_name, p.riot_id_tagline,
+                        p.team_id, p.champion_id, p.champion_name, p.champ_level,
+                        p.team_position, p.individual_position, p.win, p.kills, p.deaths,
+                        p.assists, p.total_minions_killed, p.neutral_minions_killed,
+                        p.gold_earned, p.total_damage_dealt_to_champions, p.total_damage_taken,
+                        p.vision_score, p.wards_placed, p.wards_killed, p.item0, p.item1,
+                        p.item2, p.item3, p.item4, p.item5, p.item6, p.summoner1_id,
+                        p.summoner2_id, p.time_played
+                 from matches m
+                 join match_participants p on p.match_id = m.match_id
+                 where ($1 is null or m.platform_id = $1)
+                   and ($2 is null or m.queue_id = $2)
+                   and ($3 is null or m.game_version like $3 || '%')
+                   and ($4 is null or m.game_creation >= $4)
+                   and ($5 is null or m.game_creation < $5)
+                   and ($6 is null
+                        or (m.game_creation, m.match_id, p.participant_id) > ($6, $7, $8))
+                 order by m.game_creation, m.match_id, p.participant_id
+                 limit $9",
+            )
+            .bind(filter.platform_id())
+            .bind(filter.queue)
+            .bind(filter.version_prefix())
+            .bind(filter.created_from())
+            .bind(filter.created_before())
+            .bind(after.as_ref().map(|(game_creation, _, _)| *game_creation))
+            .bind(after.as_ref().map(|(_, match_id, _)| match_id.clone()))
+            .bind(after.map(|(_, _, participant_id)| participant_id))
+            .bind(paged.then_some(EXPORT_PAGE))
+        };
+        if paged {
+            export_pages(
+                move |after| page(after).fetch_all(pool).boxed(),
+                |row: &ParticipantRow| {
+                    (row.game_creation, row.match_id.clone(), row.participant_id)
+                },
+            )
+        } else {
+            page(None).fetch(pool)
+        }
+    })
+}
@@ -0,0 +1,95 @@
+use serde::Serialize;
+
+/// A matchmaking queue, see <https://static.developer.riotgames.com/docs/lol/queues.json>.
+#[derive(Debug, Serialize)]
+pub struct Queue {
+    pub id: i32,
+    pub name: &'static str,
+    pub map: &'static str,
+    pub ranked: bool,
+    /// `queueType` used by league-v4 for the ranked queues.
+    pub league_queue_type: Option<&'static str>,
+}
+
+const SUMMONERS_RIFT: &str = "Summoner's Rift";
+const HOWLING_ABYSS: &str = "Howling Abyss";
+const RINGS_OF_WRATH: &str = "Rings of Wrath";
+
+const fn queue(id: i32, name: &'static str, map: &'static str) -> Queue {
+    Queue {
+        id,
+        name,
+        map,
+        ranked: false,
+        league_queue_type: None,
+    }
+}
+
+const fn ranked_queue(
+    id: i32,
+    name: &'static str,
+    map: &'static str,
+    league_queue_type: &'static str,
+) -> Queue {
+    Queue {
+        id,
+        name,
+        map,
+        ranked: true,
+        league_queue_type: Some(league_queue_type),
+    }
+}
+
+pub static QUEUES: &[Queue] = &[
+    queue(0, "Custom", "Custom games"),
+    queue(400, "Normal Draft", SUMMONERS_RIFT),
+    ranked_queue(420, "Ranked Solo/Duo", SUMMONERS_RIFT, "RANKED_SOLO_5x5"),
+    queue(430, "Normal Blind", SUMMONERS_RIFT),
+    ranked_queue(440, "Ranked Flex", SUMMONERS_RIFT, "RANKED_FLEX_SR"),
+    queue(450, "ARAM", HOWLING_ABYSS),
+    queue(480, "Swiftplay", SUMMONERS_RIFT),
+    queue(490, "Quickplay", SUMMONERS_RIFT),
+    queue(700, "Clash", SUMMONERS_RIFT),
+    queue(720, "ARAM Clash", HOWLING_ABYSS),
+    queue(830, "Co-op vs. AI Intro", SUMMONERS_RIFT),
+    queue(840, "Co-op vs. AI Beginner", SUMMONERS_RIFT),
+    queue(850, "Co-op vs. AI Intermediate", SUMMONERS_RIFT),
+    queue(870, "Co-op vs. AI Intro", SUMMONERS_RIFT),
+    queue(880, "Co-op vs. AI Beginner", SUMMONERS_RIFT),
+    queue(890, "Co-op vs. AI Intermediate", SUMMONERS_RIFT),
+    queue(900, "ARURF", SUMMONERS_RIFT),
+    queue(1020, "One for All", SUMMONERS_RIFT),
+    queue(1300, "Nexus Blitz", "Nexus Blitz"),
+    queue(1400, "Ultimate Spellbook", SUMMONERS_RIFT),
+    queue(1700, "Arena", RINGS_OF_WRATH),
+    queue(1710, "Arena", RINGS_OF_WRATH),
+    queue(1900, "Pick URF", SUMMONERS_RIFT),
+    queue(2000, "Tutorial 1", SUMMONERS_RIFT),
+    queue(2010, "Tutorial 2", SUMMONERS_RIFT),
+    queue(2020, "Tutorial 3", SUMMONERS_RIFT),
+];
+
+static UNKNOWN_QUEUE: Queue = queue(-1, "Unknown queue", "Unknown map");
+
+/// Looks up a match `queueId`, unknown ids resolve to a placeholder queue.
+pub fn by_id(id: i32) -> &'static Queue {
+    QUEUES
+        .iter()
+        .find(|queue| queue.id == id)
+        .unwrap_or(&UNKNOWN_QUEUE)
+}
+
+/// Looks up a league-v4 `queueType` such as "RANKED_SOLO_5x5".
+pub fn by_league_queue_type(queue_type: &str) -> Option<&'static Queue> {
+    QUEUES
+        .iter()
+        .find(|queue| queue.league_queue_type == Some(queue_type))
+}
+
+/// Queues worth offering as a matchlist filter, ranked queues first.
+pub fn filterable() -> Vec<&'static Queue> {
+    [420, 440, 400, 430, 490, 450, 700, 1700, 900]
+        .iter()
+        .map(|id| by_id(*id))
+        .collect()
+}
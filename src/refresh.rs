@@ -0,0 +1,82 @@
+use actix_web::rt;
+use chrono::{DateTime, TimeDelta, Utc};
+use std::{env, str::FromStr, time::Duration};
+
+use crate::jobs;
+use crate::repository::Store;
+use crate::riot_api::Region;
+
+/// How often the scheduler looks for tracked players due for a refresh.
+const TICK: Duration = Duration::from_secs(60);
+/// Players refreshed per tick at most.
+const REFRESHES_PER_TICK: i64 = 100;
+/// Riot requests a refresh is assumed to cost: account, summoner, league,
+/// mastery, matchlist and a few new matches.
+const REFRESH_REQUESTS: i32 = 8;
+
+/// Time until the next refresh, the more recently a player played the sooner.
+fn refresh_interval(latest_game_start: Option<i64>, now: DateTime<Utc>) -> TimeDelta {
+    let since_last_game = latest_game_start
+        .and_then(DateTime::from_timestamp_millis)
+        .map(|latest| now - latest);
+    match since_last_game {
+        Some(since) if since < TimeDelta::days(1) => TimeDelta::minutes(30),
+        Some(since) if since < TimeDelta::days(7) => TimeDelta::hours(3),
+        Some(since) if since < TimeDelta::days(30) => TimeDelta::hours(12),
+        _ => TimeDelta::days(2),
+    }
+}
+
+/// Riot requests scheduled refreshes may spend per day, REFRESH_DAILY_QUOTA.
+fn daily_quota() -> i32 {
+    env::var("REFRESH_DAILY_QUOTA")
+        .ok()
+        .and_then(|quota| quota.parse().ok())
+        .unwrap_or(50_000)
+}
+
+/// Periodically queues rank and match sync jobs for tracked players.
+pub fn spawn_scheduler(store: Store) {
+    rt::spawn(async move {
+        while !jobs::shutting_down() {
+            if let Err(e) = schedule_due(&store).await {
+                println!("refresh: scheduling failed: {:?}", e);
+            }
+            rt::time::sleep(TICK).await;
+        }
+    });
+}
+
+async fn schedule_due(store: &Store) -> Result<(), sqlx::Error> {
+    let due = store.users.due_refreshes(REFRESHES_PER_TICK).await?;
+    let quota = daily_quota();
+    for tracked in due {
+        let now = Utc::now();
+        let latest_game_start = store
+            .matches
+            .latest_stored_game_start(&tracked.puuid, None)
+            .await?;
+        let next = now + refresh_interval(latest_game_start, now);
+        // The platform is only known once the summoner was looked up
+        let Some(region) = tracked
+            .platform
+            .as_deref()
+            .and_then(|platform| Region::from_str(platform).ok())
+        else {
+            store.users.schedule_refresh(&tracked.puuid, next).await?;
+            continue;
+        };
+        if !store
+            .users
+            .reserve_refresh_quota(now.date_naive(), REFRESH_REQUESTS, quota)
+            .await?
+        {
+            // Due players stay due and go first once the next day's budget opens
+            println!("refresh: daily quota of {} requests used up", quota);
+            return Ok(());
+        }
+        jobs::enqueue_update(store, region, &tracked.puuid).await?;
+        store.users.schedule_refresh(&tracked.puuid, next).await?;
+    }
+    Ok(())
+}
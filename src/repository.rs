@@ -0,0 +1,980 @@
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use futures::stream::BoxStream;
+use std::sync::Arc;
+
+use crate::db::Db;
+use crate::memory::MemoryStore;
+use crate::models::{
+    self, Backfill, ExportFilter, Job, MasteryGain, MatchRow, ParticipantRow, QueueStats,
+    RankSnapshot, Reclaimed, RiotIdHistoryEntry, User,
+};
+use crate::riot_api::{
+    AccountV1, ChampionMasteryV4, LargeRegion, LeagueV4, MatchV5Match, MatchV5Timeline, Region,
+    SummonerV4,
+};
+
+/// Accounts, riot ids, refresh scheduling and champion mastery.
+#[async_trait::async_trait]
+pub trait UserRepository: Send + Sync {
+    async fn upsert_account(
+        &self,
+        account: &AccountV1,
+        riot_key_id: Option<&str>,
+    ) -> Result<(), sqlx::Error>;
+    async fn upsert_summoner(
+        &self,
+        region: &Region,
+        summoner: &SummonerV4,
+    ) -> Result<(), sqlx::Error>;
+    async fn former_riot_ids(
+        &self,
+        account: &AccountV1,
+    ) -> Result<Vec<RiotIdHistoryEntry>, sqlx::Error>;
+    async fn user_by_former_riot_id(
+        &self,
+        game_name: &str,
+        tag_line: &str,
+    ) -> Result<Option<User>, sqlx::Error>;
+    async fn user_by_riot_id(
+        &self,
+        game_name: &str,
+        tag_line: &str,
+    ) -> Result<Option<User>, sqlx::Error>;
+    async fn user_by_puuid(&self, puuid: &str) -> Result<Option<User>, sqlx::Error>;
+    async fn mark_refreshed(&self, puuid: &str) -> Result<(), sqlx::Error>;
+    async fn set_tracked(&self, puuid: &str, tracked: bool) -> Result<(), sqlx::Error>;
+    async fn request_update(&self, puuid: &str, cooldown: TimeDelta) -> Result<bool, sqlx::Error>;
+    async fn due_refreshes(&self, limit: i64) -> Result<Vec<User>, sqlx::Error>;
+    async fn schedule_refresh(&self, puuid: &str, at: DateTime<Utc>) -> Result<(), sqlx::Error>;
+    async fn reserve_refresh_quota(
+        &self,
+        day: NaiveDate,
+        requests: i32,
+        quota: i32,
+    ) -> Result<bool, sqlx::Error>;
+    async fn record_masteries(
+        &self,
+        puuid: &str,
+        masteries: &[ChampionMasteryV4],
+    ) -> Result<(), sqlx::Error>;
+    async fn mastery_gains(
+        &self,
+        puuid: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<MasteryGain>, sqlx::Error>;
+    async fn delete_user(&self, puuid: &str) -> Result<Reclaimed, sqlx::Error>;
+    async fn is_deleted(&self, puuid: &str) -> Result<bool, sqlx::Error>;
+}
+
+/// League-v4 snapshots.
+#[async_trait::async_trait]
+pub trait RankRepository: Send + Sync {
+    async fn record_ranks(&self, puuid: &str, ranks: &[LeagueV4]) -> Result<(), sqlx::Error>;
+    async fn rank_history(&self, puuid: &str, days: i64) -> Result<Vec<RankSnapshot>, sqlx::Error>;
+    async fn current_ranks(&self, puuid: &str) -> Result<Vec<RankSnapshot>, sqlx::Error>;
+}
+
+/// Matches, their timelines, and the sync and backfill progress downloading them.
+#[async_trait::async_trait]
+pub trait MatchRepository: Send + Sync {
+    async fn ingest_match(
+        &self,
+        lol_match: &MatchV5Match,
+        raw: &serde_json::Value,
+    ) -> Result<(), sqlx::Error>;
+    async fn stored_match(&self, match_id: &str) -> Result<Option<serde_json::Value>, sqlx::Error>;
+    async fn has_timeline(&self, match_id: &str) -> Result<bool, sqlx::Error>;
+    async fn ingest_timeline(&self, timeline: &MatchV5Timeline) -> Result<(), sqlx::Error>;
+    async fn queue_stats(&self, puuid: &str) -> Result<Vec<QueueStats>, sqlx::Error>;
+    async fn recent_match_ids(
+        &self,
+        puuid: &str,
+        queue_id: Option<i32>,
+        limit: i64,
+    ) -> Result<Vec<String>, sqlx::Error>;
+    async fn latest_stored_game_start(
+        &self,
+        puuid: &str,
+        queue_id: Option<i32>,
+    ) -> Result<Option<i64>, sqlx::Error>;
+    async fn game_start_timestamp(&self, match_id: &str) -> Result<Option<i64>, sqlx::Error>;
+    async fn unknown_match_ids(&self, match_ids: &[String]) -> Result<Vec<String>, sqlx::Error>;
+    async fn sync_state(&self, puuid: &str, queue_id: i32) -> Result<Option<i64>, sqlx::Error>;
+    async fn record_sync_state(
+        &self,
+        puuid: &str,
+        queue_id: i32,
+        latest_game_start: Option<i64>,
+    ) -> Result<(), sqlx::Error>;
+    async fn create_backfill(
+        &self,
+        puuid: &str,
+        large_region: &LargeRegion,
+        horizon: i64,
+    ) -> Result<(), sqlx::Error>;
+    async fn backfill(&self, puuid: &str) -> Result<Option<Backfill>, sqlx::Error>;
+    async fn running_backfills(&self) -> Result<Vec<Backfill>, sqlx::Error>;
+    async fn record_backfill_progress(&self, backfill: &Backfill) -> Result<(), sqlx::Error>;
+    async fn prune_raw_matches(&self, before: DateTime<Utc>) -> Result<Reclaimed, sqlx::Error>;
+    async fn prune_timelines(&self, before: DateTime<Utc>) -> Result<Reclaimed, sqlx::Error>;
+    fn export_matches<'a>(
+        &'a self,
+        filter: &ExportFilter,
+    ) -> BoxStream<'a, Result<MatchRow, sqlx::Error>>;
+    fn export_participants<'a>(
+        &'a self,
+        filter: &ExportFilter,
+    ) -> BoxStream<'a, Result<ParticipantRow, sqlx::Error>>;
+}
+
+/// The job queue of the background workers.
+#[async_trait::async_trait]
+pub trait JobRepository: Send + Sync {
+    async fn enqueue_job(
+        &self,
+        kind: &str,
+        payload: &serde_json::Value,
+        priority: i32,
+    ) -> Result<bool, sqlx::Error>;
+    async fn claim_job(&self, locked_before: DateTime<Utc>) -> Result<Option<Job>, sqlx::Error>;
+    async fn heartbeat_job(&self, id: i64) -> Result<(), sqlx::Error>;
+    async fn complete_job(&self, id: i64) -> Result<(), sqlx::Error>;
+    async fn requeue_job(&self, id: i64) -> Result<(), sqlx::Error>;
+    async fn fail_job(
+        &self,
+        id: i64,
+        error: &str,
+        run_after: Option<DateTime<Utc>>,
+    ) -> Result<(), sqlx::Error>;
+    async fn has_pending_jobs(&self, puuid: &str, kinds: &[&str]) -> Result<bool, sqlx::Error>;
+}
+
+/// Everything the server stores, behind one repository per kind of data.
+#[derive(Clone)]
+pub struct Store {
+    pub users: Arc<dyn UserRepository>,
+    pub ranks: Arc<dyn RankRepository>,
+    pub matches: Arc<dyn MatchRepository>,
+    pub jobs: Arc<dyn JobRepository>,
+}
+
+impl Store {
+    /// Repositories reading and writing the database.
+    pub fn sql(db: Db) -> Store {
+        let db = Arc::new(db);
+        Store {
+            users: db.clone(),
+            ranks: db.clone(),
+            matches: db.clone(),
+            jobs: db,
+        }
+    }
+
+    /// Repositories keeping everything in memory until the server stops.
+    pub fn memory() -> Store {
+        let memory = Arc::new(MemoryStore::default());
+        Store {
+            users: memory.clone(),
+            ranks: memory.clone(),
+            matches: memory.clone(),
+            jobs: memory,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl UserRepository for Db {
+    async fn upsert_account(
+        &self,
+        account: &AccountV1,
+        riot_key_id: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        models::upsert_account(self, account, riot_key_id).await
+    }
+
+    async fn upsert_summoner(
+        &self,
+        region: &Region,
+        summoner: &SummonerV4,
+    ) -> Result<(), sqlx::Error> {
+        models::upsert_summoner(self, region, summoner).await
+    }
+
+    async fn former_riot_ids(
+        &self,
+        account: &AccountV1,
+    ) -> Result<Vec<RiotIdHistoryEntry>, sqlx::Error> {
+        models::former_riot_ids(self, account).await
+    }
+
+    async fn user_by_former_riot_id(
+        &self,
+        game_name: &str,
+        tag_line: &str,
+    ) -> Result<Option<User>, sqlx::Error> {
+        models::user_by_former_riot_id(self, game_name, tag_line).await
+    }
+
+    async fn user_by_riot_id(
+        &self,
+        game_name: &str,
+        tag_line: &str,
+    ) -> Result<Option<User>, sqlx::Error> {
+        models::user_by_riot_id(self, game_name, tag_line).await
+    }
+
+    async fn user_by_puuid(&self, puuid: &str) -> Result<Option<User>, sqlx::Error> {
+        models::user_by_puuid(self, puuid).await
+    }
+
+    async fn mark_refreshed(&self, puuid: &str) -> Result<(), sqlx::Error> {
+        models::mark_refreshed(self, puuid).await
+    }
+
+    async fn set_tracked(&self, puuid: &str, tracked: bool) -> Result<(), sqlx::Error> {
+        models::set_tracked(self, puuid, tracked).await
+    }
+
+    async fn request_update(&self, puuid: &str, cooldown: TimeDelta) -> Result<bool, sqlx::Error> {
+        models::request_update(self, puuid, cooldown).await
+    }
+
+    async fn due_refreshes(&self, limit: i64) -> Result<Vec<User>, sqlx::Error> {
+        models::due_refreshes(self, limit).await
+    }
+
+    async fn schedule_refresh(&self, puuid: &str, at: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        models::schedule_refresh(self, puuid, at).await
+    }
+
+    async fn reserve_refresh_quota(
+        &self,
+        day: NaiveDate,
+        requests: i32,
+        quota: i32,
+    ) -> Result<bool, sqlx::Error> {
+        models::reserve_refresh_quota(self, day, requests, quota).await
+    }
+
+    async fn record_masteries(
+        &self,
+        puuid: &str,
+        masteries: &[ChampionMasteryV4],
+    ) -> Result<(), sqlx::Error> {
+        models::record_masteries(self, puuid, masteries).await
+    }
+
+    async fn mastery_gains(
+        &self,
+        puuid: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<MasteryGain>, sqlx::Error> {
+        models::mastery_gains(self, puuid, since).await
+    }
+
+    async fn delete_user(&self, puuid: &str) -> Result<Reclaimed, sqlx::Error> {
+        models::delete_user(self, puuid).await
+    }
+
+    async fn is_deleted(&self, puuid: &str) -> Result<bool, sqlx::Error> {
+        models::is_deleted(self, puuid).await
+    }
+}
+
+#[async_trait::async_trait]
+impl RankRepository for Db {
+    async fn record_ranks(&self, puuid: &str, ranks: &[LeagueV4]) -> Result<(), sqlx::Error> {
+        models::record_ranks(self, puuid, ranks).await
+    }
+
+    async fn rank_history(&self, puuid: &str, days: i64) -> Result<Vec<RankSnapshot>, sqlx::Error> {
+        models::rank_history(self, puuid, days).await
+    }
+
+    async fn current_ranks(&self, puuid: &str) -> Result<Vec<RankSnapshot>, sqlx::Error> {
+        models::current_ranks(self, puuid).await
+    }
+}
+
+#[async_trait::async_trait]
+impl MatchRepository for Db {
+    async fn ingest_match(
+        &self,
+        lol_match: &MatchV5Match,
+        raw: &serde_json::Value,
+    ) -> Result<(), sqlx::Error> {
+        models::ingest_match(self, lol_match, raw).await
+    }
+
+    async fn stored_match(&self, match_id: &str) -> Result<Option<serde_json::Value>, sqlx::Error> {
+        models::stored_match(self, match_id).await
+    }
+
+    async fn has_timeline(&self, match_id: &str) -> Result<bool, sqlx::Error> {
+        models::has_timeline(self, match_id).await
+    }
+
+    async fn ingest_timeline(&self, timeline: &MatchV5Timeline) -> Result<(), sqlx::Error> {
+        models::ingest_timeline(self, timeline).await
+    }
+
+    async fn queue_stats(&self, puuid: &str) -> Result<Vec<QueueStats>, sqlx::Error> {
+        models::queue_stats(self, puuid).await
+    }
+
+    async fn recent_match_ids(
+        &self,
+        puuid: &str,
+        queue_id: Option<i32>,
+        limit: i64,
+    ) -> Result<Vec<String>, sqlx::Error> {
+        models::recent_match_ids(self, puuid, queue_id, limit).await
+    }
+
+    async fn latest_stored_game_start(
+        &self,
+        puuid: &str,
+        queue_id: Option<i32>,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        models::latest_stored_game_start(self, puuid, queue_id).await
+    }
+
+    async fn game_start_timestamp(&self, match_id: &str) -> Result<Option<i64>, sqlx::Error> {
+        models::game_start_timestamp(self, match_id).await
+    }
+
+    async fn unknown_match_ids(&self, match_ids: &[String]) -> Result<Vec<String>, sqlx::Error> {
+        models::unknown_match_ids(self, match_ids).await
+    }
+
+    async fn sync_state(&self, puuid: &str, queue_id: i32) -> Result<Option<i64>, sqlx::Error> {
+        models::sync_state(self, puuid, queue_id).await
+    }
+
+    async fn record_sync_state(
+        &self,
+        puuid: &str,
+        queue_id: i32,
+        latest_game_start: Option<i64>,
+    ) -> Result<(), sqlx::Error> {
+        models::record_sync_state(self, puuid, queue_id, latest_game_start).await
+    }
+
+    async fn create_backfill(
+        &self,
+        puuid: &str,
+        large_region: &LargeRegion,
+        horizon: i64,
+    ) -> Result<(), sqlx::Error> {
+        models::create_backfill(self, puuid, large_region, horizon).await
+    }
+
+    async fn backfill(&self, puuid: &str) -> Result<Option<Backfill>, sqlx::Error> {
+        models::backfill(self, puuid).await
+    }
+
+    async fn running_backfills(&self) -> Result<Vec<Backfill>, sqlx::Error> {
+        models::running_backfills(self).await
+    }
+
+    async fn record_backfill_progress(&self, backfill: &Backfill) -> Result<(), sqlx::Error> {
+        models::record_backfill_progress(self, backfill).await
+    }
+
+    async fn prune_raw_matches(&self, before: DateTime<Utc>) -> Result<Reclaimed, sqlx::Error> {
+        models::prune_raw_matches(self, before).await
+    }
+
+    async fn prune_timelines(&self, before: DateTime<Utc>) -> Result<Reclaimed, sqlx::Error> {
+        models::prune_timelines(self, before).await
+    }
+
+    fn export_matches<'a>(
+        &'a self,
+        filter: &ExportFilter,
+    ) -> BoxStream<'a, Result<MatchRow, sqlx::Error>> {
+        models::export_matches(self, filter)
+    }
+
+    fn export_participants<'a>(
+        &'a self,
+        filter: &ExportFilter,
+    ) -> BoxStream<'a, Result<ParticipantRow, sqlx::Error>> {
+        models::export_participants(self, filter)
+    }
+}
+
+#[async_trait::async_trait]
+impl JobRepository for Db {
+    async fn enqueue_job(
+        &self,
+        kind: &str,
+        payload: &serde_json::Value,
+        priority: i32,
+    ) -> Result<bool, sqlx::Error> {
+        models::enqueue_job(self, kind, payload, priority).await
+    }
+
+    async fn claim_job(&self, locked_before: DateTime<Utc>) -> Result<Option<Job>, sqlx::Error> {
+        models::claim_job(self, locked_before).await
+    }
+
+    async fn heartbeat_job(&self, id: i64) -> Result<(), sqlx::Error> {
+        models::heartbeat_job(self, id).await
+    }
+
+    async fn complete_job(&self, id: i64) -> Result<(), sqlx::Error> {
+        models::complete_job(self, id).await
+    }
+
+    async fn requeue_job(&self, id: i64) -> Result<(), sqlx::Error> {
+        models::requeue_job(self, id).await
+    }
+
+    async fn fail_job(
+        &self,
+        id: i64,
+        error: &str,
+        run_after: Option<DateTime<Utc>>,
+    ) -> Result<(), sqlx::Error> {
+        models::fail_job(self, id, error, run_after).await
+    }
+
+    async fn has_pending_jobs(&self, puuid: &str, kinds: &[&str]) -> Result<bool, sqlx::Error> {
+        models::has_pending_jobs(self, puuid, kinds).await
+    }
+}
+
+/// Every case runs against the in-memory repositories and SQLite, so both keep
+/// the semantics of the SQL, and against Postgres when TEST_POSTGRES_URL is set.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+    use serde_json::json;
+    use std::env;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::jobs;
+    use crate::mastery;
+    use crate::riot_api::MatchV5Match;
+
+    async fn stores() -> Vec<(&'static str, Store)> {
+        let db = Db::connect("sqlite::memory:").await.unwrap();
+        db.migrate().await.unwrap();
+        let mut stores = vec![("memory", Store::memory()), ("sqlite", Store::sql(db))];
+        if let Some(url) = env::var("TEST_POSTGRES_URL")
+            .ok()
+            .filter(|url| !url.is_empty())
+        {
+            stores.push(("postgres", Store::sql(postgres(&url).await)));
+        }
+        stores
+    }
+
+    /// Migrates a schema of its own for each test, the tests run in parallel.
+    /// It is dropped when the next run starts, to be looked at after a failure.
+    async fn postgres(url: &str) -> Db {
+        static SCHEMAS: AtomicUsize = AtomicUsize::new(0);
+        let schema = format!("lolepic_test_{}", SCHEMAS.fetch_add(1, Ordering::Relaxed));
+        let Db::Postgres(pool) = Db::connect(url).await.unwrap() else {
+            panic!("TEST_POSTGRES_URL is not a postgres:// url");
+        };
+        sqlx::raw_sql(&format!(
+            "drop schema if exists {0} cascade; create schema {0}",
+            schema
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+        pool.close().await;
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let url = format!("{url}{separator}options=-c%20search_path%3D{schema}");
+        let db = Db::connect(&url).await.unwrap();
+        db.migrate().await.unwrap();
+        db
+    }
+
+    /// Stores the account of "puuid0", ranks and masteries belong to a user.
+    async fn seed_player(store: &Store) {
+        let account: AccountV1 = serde_json::from_value(
+            json!({"puuid": "puuid0", "gameName": "Player", "tagLine": "EUW"}),
+        )
+        .unwrap();
+        store.users.upsert_account(&account, None).await.unwrap();
+    }
+
+    fn fresh_locks() -> DateTime<Utc> {
+        Utc::now() - TimeDelta::minutes(5)
+    }
+
+    /// A match of ten players "puuid0" to "puuid9", "puuid0" being the first
+    /// of the blue team.
+    fn canned_match(
+        match_id: &str,
+        queue_id: i32,
+        win: bool,
+        kills: i32,
+    ) -> (MatchV5Match, serde_json::Value) {
+        let mut raw: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/fixtures/match.json")).unwrap();
+        let participant = raw["info"]["participants"][0].clone();
+        raw["metadata"]["matchId"] = json!(match_id);
+        raw["info"]["queueId"] = json!(queue_id);
+        raw["info"]["participants"] = (0..10)
+            .map(|i| {
+                let blue = i < 5;
+                let mut participant = participant.clone();
+                participant["puuid"] = json!(format!("puuid{}", i));
+                participant["participantId"] = json!(i + 1);
+                participant["teamId"] = json!(if blue { 100 } else { 200 });
+                participant["win"] = json!(blue == win);
+                participant["kills"] = json!(if i == 0 { kills } else { 0 });
+                participant
+            })
+            .collect();
+        (serde_json::from_value(raw.clone()).unwrap(), raw)
+    }
+
+    /// A timeline where every participant has `gold` and `cs` at 15 minutes.
+    fn canned_timeline(match_id: &str, gold: i32, cs: i32) -> MatchV5Timeline {
+        let frame = |minute: i64| {
+            let participant_frames: serde_json::Map<String, serde_json::Value> = (1..=10)
+                .map(|id| {
+                    let participant_frame = json!({
+                        "participantId": id,
+                        "currentGold": 0,
+                        "totalGold": gold * minute as i32 / 15,
+                        "xp": 0,
+                        "level": 1,
+                        "minionsKilled": cs * minute as i32 / 15,
+                        "jungleMinionsKilled": 0,
+                    });
+                    (id.to_string(), participant_frame)
+                })
+                .collect();
+            json!({
+                "timestamp": minute * 60_000 + 12,
+                "participantFrames": participant_frames,
+                "events": [],
+            })
+        };
+        serde_json::from_value(json!({
+            "metadata": {"matchId": match_id},
+            "info": {"frameInterval": 60_000, "frames": [frame(14), frame(15), frame(16)]},
+        }))
+        .unwrap()
+    }
+
+    fn rank(queue_type: &str, league_points: i32) -> LeagueV4 {
+        serde_json::from_value(json!({
+            "leagueId": "league",
+            "queueType": queue_type,
+            "tier": "GOLD",
+            "rank": "II",
+            "summonerId": "summoner",
+            "puuid": "puuid0",
+            "leaguePoints": league_points,
+            "wins": 10,
+            "losses": 5,
+            "veteran": false,
+            "inactive": false,
+            "freshBlood": false,
+            "hotStreak": false,
+        }))
+        .unwrap()
+    }
+
+    fn mastery(champion_id: i32, champion_points: i32) -> ChampionMasteryV4 {
+        ChampionMasteryV4 {
+            champion_id,
+            champion_level: 5,
+            champion_points,
+            last_play_time: 0,
+        }
+    }
+
+    #[actix_web::test]
+    async fn jobs_are_deduplicated_until_they_fail_for_good() {
+        for (name, store) in stores().await {
+            let payload = json!({"kind": "backfill", "puuid": "puuid0"});
+            assert!(
+                store
+                    .jobs
+                    .enqueue_job("backfill", &payload, 0)
+                    .await
+                    .unwrap(),
+                "{name}"
+            );
+            assert!(
+                !store
+                    .jobs
+                    .enqueue_job("backfill", &payload, 0)
+                    .await
+                    .unwrap(),
+                "{name}"
+            );
+            let job = store.jobs.claim_job(fresh_locks()).await.unwrap().unwrap();
+            assert!(
+                !store
+                    .jobs
+                    .enqueue_job("backfill", &payload, 0)
+                    .await
+                    .unwrap(),
+                "{name}"
+            );
+            store.jobs.fail_job(job.id, "gone", None).await.unwrap();
+            assert!(
+                store
+                    .jobs
+                    .enqueue_job("backfill", &payload, 0)
+                    .await
+                    .unwrap(),
+                "{name}"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn jobs_are_claimed_by_priority_then_age() {
+        for (name, store) in stores().await {
+            for (puuid, priority) in [("low", 0), ("first", 10), ("second", 10)] {
+                let payload = json!({"kind": "backfill", "puuid": puuid});
+                store
+                    .jobs
+                    .enqueue_job("backfill", &payload, priority)
+                    .await
+                    .unwrap();
+            }
+            let mut claimed = Vec::new();
+            while let Some(job) = store.jobs.claim_job(fresh_locks()).await.unwrap() {
+                claimed.push(job);
+            }
+            let order: Vec<&str> = claimed
+                .iter()
+                .map(|job| job.payload["puuid"].as_str().unwrap())
+                .collect();
+            assert_eq!(order, ["first", "second", "low"], "{name}");
+            assert!(claimed.iter().all(|job| job.attempts == 1), "{name}");
+
+            let (first, second) = (&claimed[0], &claimed[1]);
+            let retry = Utc::now() + TimeDelta::hours(1);
+            store
+                .jobs
+                .fail_job(first.id, "failed", Some(retry))
+                .await
+                .unwrap();
+            assert!(
+                store.jobs.claim_job(fresh_locks()).await.unwrap().is_none(),
+                "{name}"
+            );
+
+            store.jobs.requeue_job(second.id).await.unwrap();
+            let again = store.jobs.claim_job(fresh_locks()).await.unwrap().unwrap();
+            assert_eq!((again.id, again.attempts), (second.id, 1), "{name}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn jobs_whose_lock_timed_out_are_taken_over() {
+        for (name, store) in stores().await {
+            let payload = json!({"kind": "backfill", "puuid": "puuid0"});
+            store
+                .jobs
+                .enqueue_job("backfill", &payload, 0)
+                .await
+                .unwrap();
+            let job = store.jobs.claim_job(fresh_locks()).await.unwrap().unwrap();
+            let before_heartbeat = Utc::now();
+            store.jobs.heartbeat_job(job.id).await.unwrap();
+            assert!(
+                store
+                    .jobs
+                    .claim_job(before_heartbeat)
+                    .await
+                    .unwrap()
+                    .is_none(),
+                "{name}"
+            );
+
+            let timed_out = Utc::now() + TimeDelta::seconds(1);
+            let taken_over = store.jobs.claim_job(timed_out).await.unwrap().unwrap();
+            assert_eq!((taken_over.id, taken_over.attempts), (job.id, 2), "{name}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn updating_ignores_jobs_waiting_for_a_retry() {
+        for (name, store) in stores().await {
+            assert!(!jobs::updating(&store, "puuid0").await.unwrap(), "{name}");
+            jobs::enqueue_update(&store, Region::Euw1, "puuid0")
+                .await
+                .unwrap();
+            assert!(jobs::updating(&store, "puuid0").await.unwrap(), "{name}");
+            assert!(!jobs::updating(&store, "puuid1").await.unwrap(), "{name}");
+
+            let retry = Utc::now() + TimeDelta::hours(1);
+            while let Some(job) = store.jobs.claim_job(fresh_locks()).await.unwrap() {
+                assert!(jobs::updating(&store, "puuid0").await.unwrap(), "{name}");
+                store
+                    .jobs
+                    .fail_job(job.id, "failed", Some(retry))
+                    .await
+                    .unwrap();
+            }
+            assert!(!jobs::updating(&store, "puuid0").await.unwrap(), "{name}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn current_ranks_are_those_of_the_latest_fetch() {
+        for (name, store) in stores().await {
+            seed_player(&store).await;
+            let solo = "RANKED_SOLO_5x5";
+            let flex = "RANKED_FLEX_SR";
+            store
+                .ranks
+                .record_ranks("puuid0", &[rank(solo, 10), rank(flex, 20)])
+                .await
+                .unwrap();
+            // Unranked in flex since, the same solo rank extends its snapshot
+            store
+                .ranks
+                .record_ranks("puuid0", &[rank(solo, 10)])
+                .await
+                .unwrap();
+            let current = store.ranks.current_ranks("puuid0").await.unwrap();
+            let current: Vec<(&str, i32)> = current
+                .iter()
+                .map(|rank| (rank.queue_type.as_str(), rank.league_points))
+                .collect();
+            assert_eq!(current, [(solo, 10)], "{name}");
+
+            store
+                .ranks
+                .record_ranks("puuid0", &[rank(solo, 30)])
+                .await
+                .unwrap();
+            let current = store.ranks.current_ranks("puuid0").await.unwrap();
+            assert_eq!(current.len(), 1, "{name}");
+            assert_eq!(current[0].league_points, 30, "{name}");
+            let history = store.ranks.rank_history("puuid0", 1).await.unwrap();
+            let mut history: Vec<i32> = history.iter().map(|rank| rank.league_points).collect();
+            // Oldest first, the ranks of one fetch are in no particular order
+            assert_eq!(history.pop(), Some(30), "{name}");
+            history.sort();
+            assert_eq!(history, [10, 20], "{name}");
+
+            // Unranked everywhere now
+            store.ranks.record_ranks("puuid0", &[]).await.unwrap();
+            let current = store.ranks.current_ranks("puuid0").await.unwrap();
+            assert!(current.is_empty(), "{name}");
+            let history = store.ranks.rank_history("puuid0", 1).await.unwrap();
+            assert_eq!(history.len(), 3, "{name}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn mastery_gains_compare_with_the_previous_snapshot() {
+        for (name, store) in stores().await {
+            seed_player(&store).await;
+            let users = &store.users;
+            let start = Utc::now();
+            users
+                .record_masteries("puuid0", &[mastery(1, 100), mastery(2, 500)])
+                .await
+                .unwrap();
+            users
+                .record_masteries("puuid0", &[mastery(1, 150), mastery(2, 500)])
+                .await
+                .unwrap();
+            let latest = Utc::now();
+            users
+                .record_masteries("puuid0", &[mastery(1, 180), mastery(2, 600)])
+                .await
+                .unwrap();
+
+            let gains = |since| async move {
+                let mut gains: Vec<(i32, i32)> = users
+                    .mastery_gains("puuid0", since)
+                    .await
+                    .unwrap()
+                    .iter()
+                    .map(|gain| (gain.champion_id, gain.points))
+                    .collect();
+                gains.sort();
+                gains
+            };
+            assert_eq!(gains(start).await, [(1, 30), (1, 50), (2, 100)], "{name}");
+            // Gains of the latest fetch still compare with older snapshots
+            assert_eq!(gains(latest).await, [(1, 30), (2, 100)], "{name}");
+
+            let all = users.mastery_gains("puuid0", start).await.unwrap();
+            let grinds: Vec<(i32, i64)> = mastery::grinding(&all, start, 5)
+                .iter()
+                .map(|grind| (grind.champion_id, grind.points))
+                .collect();
+            assert_eq!(grinds, [(2, 100), (1, 80)], "{name}");
+            let weeks = mastery::weekly_gains(&all, Utc::now(), 2);
+            let points: Vec<i64> = weeks.iter().map(|week| week.points).collect();
+            assert_eq!(points, [180, 0], "{name}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn deleted_players_are_not_stored_again_with_their_matches() {
+        for (name, store) in stores().await {
+            seed_player(&store).await;
+            let (lol_match, raw) = canned_match("EUW1_1", 420, true, 5);
+            store.matches.ingest_match(&lol_match, &raw).await.unwrap();
+            store.users.delete_user("puuid0").await.unwrap();
+            assert!(
+                store
+                    .matches
+                    .stored_match("EUW1_1")
+                    .await
+                    .unwrap()
+                    .is_none(),
+                "{name}"
+            );
+
+            // Opening the match downloads it and ingests it again
+            store.matches.ingest_match(&lol_match, &raw).await.unwrap();
+            assert!(
+                store
+                    .matches
+                    .stored_match("EUW1_1")
+                    .await
+                    .unwrap()
+                    .is_none(),
+                "{name}"
+            );
+            assert!(
+                store
+                    .matches
+                    .queue_stats("puuid0")
+                    .await
+                    .unwrap()
+                    .is_empty(),
+                "{name}"
+            );
+            let participants: Vec<ParticipantRow> = store
+                .matches
+                .export_participants(&ExportFilter::default())
+                .try_collect()
+                .await
+                .unwrap();
+            let blanked: Vec<(i32, &str, &str)> = participants
+                .iter()
+                .filter(|participant| !participant.puuid.starts_with("puuid"))
+                .map(|participant| {
+                    (
+                        participant.participant_id,
+                        participant.puuid.as_str(),
+                        participant.riot_id_game_name.as_str(),
+                    )
+                })
+                .collect();
+            assert_eq!(blanked, [(1, "", "")], "{name}");
+            assert_eq!(participants.len(), 10, "{name}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn deleted_players_are_not_looked_up_again() {
+        for (name, store) in stores().await {
+            seed_player(&store).await;
+            assert!(!store.users.is_deleted("puuid0").await.unwrap(), "{name}");
+            store.users.delete_user("puuid0").await.unwrap();
+            assert!(store.users.is_deleted("puuid0").await.unwrap(), "{name}");
+            assert!(!store.users.is_deleted("puuid1").await.unwrap(), "{name}");
+
+            // Their profile page or the refresh schedule asks for an update
+            jobs::enqueue_update(&store, Region::Euw1, "puuid0")
+                .await
+                .unwrap();
+            assert!(!jobs::updating(&store, "puuid0").await.unwrap(), "{name}");
+            assert!(
+                store.jobs.claim_job(fresh_locks()).await.unwrap().is_none(),
+                "{name}"
+            );
+            assert!(
+                store.users.user_by_puuid("puuid0").await.unwrap().is_none(),
+                "{name}"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn exports_do_not_hold_the_database_while_the_client_reads() {
+        for (name, store) in stores().await {
+            seed_player(&store).await;
+            // Participants of more matches than a SQLite export page
+            for i in 0..101 {
+                let (lol_match, raw) = canned_match(&format!("EUW1_{:03}", i), 420, true, 1);
+                store.matches.ingest_match(&lol_match, &raw).await.unwrap();
+            }
+            let mut export = store.matches.export_participants(&ExportFilter::default());
+            let first = export.try_next().await.unwrap().unwrap();
+            assert_eq!(
+                (first.match_id.as_str(), first.participant_id),
+                ("EUW1_000", 1)
+            );
+
+            // A page view while the client has not read the rest yet
+            let user = actix_web::rt::time::timeout(
+                std::time::Duration::from_secs(1),
+                store.users.user_by_puuid("puuid0"),
+            )
+            .await
+            .expect(name)
+            .unwrap();
+            assert!(user.is_some(), "{name}");
+
+            let rest: Vec<ParticipantRow> = export.try_collect().await.unwrap();
+            let keys: Vec<(String, i32)> = rest
+                .iter()
+                .map(|row| (row.match_id.clone(), row.participant_id))
+                .collect();
+            let expected: Vec<(String, i32)> = (0..101)
+                .flat_map(|i| (1..=10).map(move |id| (format!("EUW1_{:03}", i), id)))
+                .skip(1)
+                .collect();
+            assert_eq!(keys, expected, "{name}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn queue_stats_add_up_the_matches_of_a_player() {
+        for (name, store) in stores().await {
+            for (match_id, queue_id, win, kills) in [
+                ("EUW1_1", 420, true, 5),
+                ("EUW1_2", 420, false, 1),
+                ("EUW1_3", 440, true, 2),
+            ] {
+                let (lol_match, raw) = canned_match(match_id, queue_id, win, kills);
+                store.matches.ingest_match(&lol_match, &raw).await.unwrap();
+            }
+            let timeline = canned_timeline("EUW1_1", 6000, 120);
+            store.matches.ingest_timeline(&timeline).await.unwrap();
+
+            let stats = store.matches.queue_stats("puuid0").await.unwrap();
+            let totals: Vec<(i32, i64, i64, i64)> = stats
+                .iter()
+                .map(|queue| (queue.queue_id, queue.games, queue.wins, queue.kills))
+                .collect();
+            assert_eq!(totals, [(420, 2, 1, 6), (440, 1, 1, 2)], "{name}");
+            // Averaged over the matches with a timeline only
+            assert_eq!(stats[0].gold_at_15, Some(6000.0), "{name}");
+            assert_eq!(stats[0].cs_at_15, Some(120.0), "{name}");
+            assert_eq!(stats[1].gold_at_15, None, "{name}");
+
+            let stats = store.matches.queue_stats("puuid5").await.unwrap();
+            let wins: Vec<(i32, i64)> = stats
+                .iter()
+                .map(|queue| (queue.queue_id, queue.wins))
+                .collect();
+            assert_eq!(wins, [(420, 1), (440, 0)], "{name}");
+        }
+    }
+}
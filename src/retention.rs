@@ -0,0 +1,110 @@
+use actix_web::rt;
+use chrono::{DateTime, TimeDelta, Utc};
+use std::{env, time::Duration};
+
+use crate::jobs::{self, JobKind};
+use crate::models::Reclaimed;
+use crate::repository::Store;
+
+/// How often the retention policy is applied.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const USAGE: &str = "lolepic retention prune
+       lolepic retention delete-user <puuid>";
+
+#[derive(Debug, thiserror::Error)]
+pub enum RetentionError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Usage: {0}")]
+    Usage(&'static str),
+}
+
+/// Days `name` keeps data for, forever when unset.
+fn retention_days(name: &str) -> Option<i64> {
+    env::var(name).ok().and_then(|days| days.parse().ok())
+}
+
+fn cutoff(name: &str) -> Option<DateTime<Utc>> {
+    retention_days(name).map(|days| Utc::now() - TimeDelta::days(days))
+}
+
+/// Bytes as logged, only Postgres can tell the size of any row.
+fn size(bytes: Option<i64>) -> String {
+    match bytes {
+        Some(bytes) => format!("{} bytes as stored", bytes),
+        None => "size not measured".to_string(),
+    }
+}
+
+/// Periodically queues a prune job, when a retention is configured.
+pub fn spawn_scheduler(store: Store) {
+    if retention_days("RAW_RETENTION_DAYS").is_none()
+        && retention_days("TIMELINE_RETENTION_DAYS").is_none()
+    {
+        return;
+    }
+    rt::spawn(async move {
+        while !jobs::shutting_down() {
+            if let Err(e) = jobs::enqueue(&store, &JobKind::Prune).await {
+                println!("retention: could not queue pruning: {:?}", e);
+            }
+            rt::time::sleep(PRUNE_INTERVAL).await;
+        }
+    });
+}
+
+/// Drops the raw json of matches older than RAW_RETENTION_DAYS and the
+/// timelines older than TIMELINE_RETENTION_DAYS, a batch at a time. Matches
+/// a tracked player played in keep both.
+pub async fn prune(store: &Store) -> Result<(), sqlx::Error> {
+    if let Some(before) = cutoff("RAW_RETENTION_DAYS") {
+        let mut total = Reclaimed::default();
+        while !jobs::shutting_down() {
+            let reclaimed = store.matches.prune_raw_matches(before).await?;
+            if reclaimed.rows == 0 {
+                break;
+            }
+            total.add(reclaimed);
+        }
+        println!(
+            "retention: dropped the raw json of {} match(es), {}",
+            total.rows,
+            size(total.bytes)
+        );
+    }
+    if let Some(before) = cutoff("TIMELINE_RETENTION_DAYS") {
+        let mut total = Reclaimed::default();
+        while !jobs::shutting_down() {
+            let reclaimed = store.matches.prune_timelines(before).await?;
+            if reclaimed.rows == 0 {
+                break;
+            }
+            total.add(reclaimed);
+        }
+        println!(
+            "retention: dropped {} timeline row(s), {}",
+            total.rows,
+            size(total.bytes)
+        );
+    }
+    Ok(())
+}
+
+/// `lolepic retention ...`, applies the policy now or deletes a player.
+pub async fn run_cli(store: &Store, args: &[String]) -> Result<(), RetentionError> {
+    match (args.first().map(String::as_str), args.get(1)) {
+        (Some("prune"), None) => Ok(prune(store).await?),
+        (Some("delete-user"), Some(puuid)) => {
+            let reclaimed = store.users.delete_user(puuid).await?;
+            println!(
+                "retention: deleted {}, {} row(s) and raw json of {}",
+                puuid,
+                reclaimed.rows,
+                size(reclaimed.bytes)
+            );
+            Ok(())
+        }
+        _ => Err(RetentionError::Usage(USAGE)),
+    }
+}
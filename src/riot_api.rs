@@ -1,20 +1,10 @@
-use governor::Jitter;
-use reqwest::header::USER_AGENT;
-use serde::{
-    de::{self},
-    Deserialize, Serialize,
-};
-use std::sync::Arc;
-use std::{
-    env::{self, VarError},
-    fmt,
-    time::Duration,
-};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, sync::Arc};
 use strum::{EnumIter, EnumString};
 
-use crate::RiotRatelimiters;
+use crate::riot_client::RiotClient;
 
-#[derive(Debug, EnumString, Serialize, EnumIter)]
+#[derive(Debug, Clone, Copy, EnumString, Serialize, Deserialize, EnumIter)]
 pub enum Region {
     Br1,
     Eun1,
@@ -33,13 +23,36 @@ pub enum Region {
     Tw2,
     Vn2,
 }
+impl Region {
+    /// Routing region of the regional apis (account, match) for this platform.
+    pub fn large_region(&self) -> LargeRegion {
+        match self {
+            Region::Br1 => LargeRegion::Americas,
+            Region::Eun1 => LargeRegion::Europe,
+            Region::Euw1 => LargeRegion::Europe,
+            Region::Jp1 => LargeRegion::Asia,
+            Region::Kr => LargeRegion::Asia,
+            Region::La1 => LargeRegion::Americas,
+            Region::La2 => LargeRegion::Americas,
+            Region::Na1 => LargeRegion::Americas,
+            Region::Oc1 => LargeRegion::Sea,
+            Region::Tr1 => LargeRegion::Europe,
+            Region::Ru => LargeRegion::Europe,
+            Region::Ph2 => LargeRegion::Sea,
+            Region::Sg2 => LargeRegion::Sea,
+            Region::Th2 => LargeRegion::Sea,
+            Region::Tw2 => LargeRegion::Asia,
+            Region::Vn2 => LargeRegion::Asia,
+        }
+    }
+}
 impl fmt::Display for Region {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
     }
 }
 
-#[derive(Debug, EnumString, Serialize)]
+#[derive(Debug, Clone, Copy, EnumString, Serialize, Deserialize)]
 pub enum LargeRegion {
     Americas,
     Asia,
@@ -53,42 +66,16 @@ impl fmt::Display for LargeRegion {
 }
 
 #[derive(Debug, thiserror::Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum RiotApiError {
-    #[error("Enviromental variable RIOT_API_KEY")]
-    EnviromentalVariableError(#[from] VarError),
     #[error("Riot api error")]
-    RiotApiError(#[from] reqwest::Error),
-}
-
-async fn riot_request<T: de::DeserializeOwned>(
-    reqwest_client: Arc<reqwest::Client>,
-    riot_ratelimiters: RiotRatelimiters,
-    request_url: &str,
-) -> Result<T, reqwest::Error> {
-    while riot_ratelimiters.long_ratelimit.check().is_err() {
-        riot_ratelimiters
-            .long_ratelimit
-            .until_ready_with_jitter(Jitter::new(Duration::from_secs(1), Duration::from_secs(1)))
-            .await;
-    }
-    while riot_ratelimiters.short_ratelimit.check().is_err() {
-        riot_ratelimiters
-            .short_ratelimit
-            .until_ready_with_jitter(Jitter::new(Duration::from_secs(1), Duration::from_secs(1)))
-            .await;
-    }
-    return reqwest_client
-        .get(request_url)
-        .header(USER_AGENT, "rust-web-api-client") // gh api requires a user-agent header
-        .send()
-        .await?
-        .json()
-        .await;
+    RiotApiError(#[from] Arc<reqwest_middleware::Error>),
+    #[error("Riot api response could not be decoded")]
+    InvalidResponse(#[from] serde_json::Error),
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-#[allow(dead_code)]
 pub struct AccountV1 {
     pub puuid: String,
     pub game_name: String,
@@ -96,26 +83,60 @@ pub struct AccountV1 {
 }
 
 pub async fn account_v1(
-    reqwest_client: Arc<reqwest::Client>,
-    riot_ratelimiters: RiotRatelimiters,
+    riot_client: &RiotClient,
     large_region: &LargeRegion,
     gamename: &str,
     tagline: &str,
 ) -> Result<AccountV1, RiotApiError> {
-    let _riot_api_key = env::var("RIOT_API_KEY")?;
     let request_url = format!(
-        "https://{}.api.riotgames.com/riot/account/v1/accounts/by-riot-id/{}/{}?api_key={}",
-        large_region, gamename, tagline, _riot_api_key
+        "https://{}.api.riotgames.com/riot/account/v1/accounts/by-riot-id/{}/{}",
+        large_region, gamename, tagline
     );
     //println!("account_v1 request_url: {}", request_url);
-    let account_v1 =
-        riot_request::<AccountV1>(reqwest_client, riot_ratelimiters, &request_url).await;
+    riot_client
+        .request_puuid::<AccountV1>(&request_url, |account| &account.puuid)
+        .await
+}
 
-    //println!("{:?}", account_v1);
-    Ok(account_v1?)
+/// Current riot id of a PUUID, used to follow renamed players.
+pub async fn account_v1_by_puuid(
+    riot_client: &RiotClient,
+    large_region: &LargeRegion,
+    puuid: &str,
+) -> Result<AccountV1, RiotApiError> {
+    let request_url = format!(
+        "https://{}.api.riotgames.com/riot/account/v1/accounts/by-puuid/{}",
+        large_region, puuid
+    );
+    riot_client
+        .request_for_puuid::<AccountV1>(&request_url, puuid)
+        .await
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ChampionMasteryV4 {
+    pub champion_id: i32,
+    pub champion_level: i32,
+    pub champion_points: i32,
+    /// Milliseconds since the epoch
+    pub last_play_time: i64,
 }
 
-//pub async fn champion_mastery_v4_puuid(puuid: &str) {}
+/// Mastery of every champion a PUUID played.
+pub async fn champion_mastery_v4(
+    riot_client: &RiotClient,
+    region: &Region,
+    puuid: &str,
+) -> Result<Vec<ChampionMasteryV4>, RiotApiError> {
+    let request_url = format!(
+        "https://{}.api.riotgames.com/lol/champion-mastery/v4/champion-masteries/by-puuid/{}",
+        region, puuid
+    );
+    riot_client
+        .request_for_puuid::<Vec<ChampionMasteryV4>>(&request_url, puuid)
+        .await
+}
 
 //pub async fn champion_v3() {}
 
@@ -172,22 +193,18 @@ pub struct LeagueV4 {
 }
 
 pub async fn league_v4(
-    reqwest_client: Arc<reqwest::Client>,
-    riot_ratelimiters: RiotRatelimiters,
+    riot_client: &RiotClient,
     region: &Region,
     puuid: &str,
 ) -> Result<Vec<LeagueV4>, RiotApiError> {
-    let _riot_api_key = env::var("RIOT_API_KEY")?;
     let request_url = format!(
-        "https://{}.api.riotgames.com/lol/league/v4/entries/by-puuid/{}?api_key={}",
-        region, puuid, _riot_api_key
+        "https://{}.api.riotgames.com/lol/league/v4/entries/by-puuid/{}",
+        region, puuid
     );
     //println!("account_v1 request_url: {}", request_url);
-    let league_v4s =
-        riot_request::<Vec<LeagueV4>>(reqwest_client, riot_ratelimiters, &request_url).await;
-
-    //println!("{:?}", league_v4s);
-    Ok(league_v4s?)
+    riot_client
+        .request_for_puuid::<Vec<LeagueV4>>(&request_url, puuid)
+        .await
 }
 
 //pub async fn league_v4_challengerleagues(queue: &Queue) {}
@@ -196,23 +213,54 @@ pub async fn league_v4(
 
 //pub async fn lol_status_v4() {}
 
+/// Filters and paging of a matchlist request.
+pub struct MatchlistQuery {
+    pub queue: Option<i32>,
+    /// Only matches started at or after this, in seconds since the epoch
+    pub start_time: Option<i64>,
+    /// Only matches started before this, in seconds since the epoch
+    pub end_time: Option<i64>,
+    pub start: u32,
+    /// At most 100
+    pub count: u32,
+}
+
+impl Default for MatchlistQuery {
+    fn default() -> Self {
+        MatchlistQuery {
+            queue: None,
+            start_time: None,
+            end_time: None,
+            start: 0,
+            count: 20,
+        }
+    }
+}
+
+/// Match ids of a PUUID, newest first.
 pub async fn match_v5_matchlist(
-    reqwest_client: Arc<reqwest::Client>,
-    riot_ratelimiters: RiotRatelimiters,
+    riot_client: &RiotClient,
     large_region: &LargeRegion,
     puuid: &str,
+    query: &MatchlistQuery,
 ) -> Result<Vec<String>, RiotApiError> {
-    let _riot_api_key = env::var("RIOT_API_KEY")?;
-    let request_url = format!(
-        "https://{}.api.riotgames.com/lol/match/v5/matches/by-puuid/{}/ids?start=0&count=5&api_key={}",
-        large_region, puuid, _riot_api_key
+    let mut request_url = format!(
+        "https://{}.api.riotgames.com/lol/match/v5/matches/by-puuid/{}/ids?start={}&count={}",
+        large_region, puuid, query.start, query.count
     );
+    if let Some(queue) = query.queue {
+        request_url.push_str(&format!("&queue={}", queue));
+    }
+    if let Some(start_time) = query.start_time {
+        request_url.push_str(&format!("&startTime={}", start_time));
+    }
+    if let Some(end_time) = query.end_time {
+        request_url.push_str(&format!("&endTime={}", end_time));
+    }
     //println!("account_v1 request_url: {}", request_url);
-    let match_v5_matchlist =
-        riot_request::<Vec<String>>(reqwest_client, riot_ratelimiters, &request_url).await;
-
-    //println!("{:?}", match_v5_matchlist);
-    Ok(match_v5_matchlist?)
+    riot_client
+        .request_for_puuid::<Vec<String>>(&request_url, puuid)
+        .await
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -463,25 +511,106 @@ pub struct ObjectiveDto {
 }
 
 pub async fn match_v5_match(
-    reqwest_client: Arc<reqwest::Client>,
-    riot_ratelimiters: RiotRatelimiters,
+    riot_client: &RiotClient,
     large_region: &LargeRegion,
     match_id: &str,
-) -> Result<MatchV5Match, RiotApiError> {
-    let _riot_api_key = env::var("RIOT_API_KEY")?;
+) -> Result<(MatchV5Match, serde_json::Value), RiotApiError> {
     let request_url = format!(
-        "https://{}.api.riotgames.com/lol/match/v5/matches/{}?api_key={}",
-        large_region, match_id, _riot_api_key
+        "https://{}.api.riotgames.com/lol/match/v5/matches/{}",
+        large_region, match_id
     );
     //println!("account_v1 request_url: {}", request_url);
-    let match_v5_match =
-        riot_request::<MatchV5Match>(reqwest_client, riot_ratelimiters, &request_url).await;
+    // Keep the response as sent, it has more than the DTOs read
+    let raw = riot_client
+        .request::<serde_json::Value>(&request_url)
+        .await?;
+    Ok((MatchV5Match::deserialize(&raw)?, raw))
+}
 
-    //println!("{:?}", match_v5_match);
-    Ok(match_v5_match?)
+// Only the parts of a timeline that are stored are read
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchV5Timeline {
+    pub metadata: TimelineMetadataDto,
+    pub info: TimelineInfoDto,
 }
 
-//pub async fn match_v5_timeline(match_id: &str) {}
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimelineMetadataDto {
+    pub match_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimelineInfoDto {
+    /// Milliseconds between two frames, a minute in practice
+    pub frame_interval: i64,
+    pub frames: Vec<FrameDto>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FrameDto {
+    pub timestamp: i64,
+    /// Keyed by participant id as a string
+    pub participant_frames: HashMap<String, ParticipantFrameDto>,
+    pub events: Vec<EventDto>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParticipantFrameDto {
+    pub participant_id: i32,
+    pub current_gold: i32,
+    pub total_gold: i32,
+    pub xp: i32,
+    pub level: i32,
+    pub minions_killed: i32,
+    pub jungle_minions_killed: i32,
+    pub position: Option<PositionDto>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PositionDto {
+    pub x: i32,
+    pub y: i32,
+}
+
+/// Every event type shares this shape, fields an event does not have are None.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EventDto {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub timestamp: i64,
+    pub participant_id: Option<i32>,
+    pub killer_id: Option<i32>,
+    pub victim_id: Option<i32>,
+    pub assisting_participant_ids: Option<Vec<i32>>,
+    pub team_id: Option<i32>,
+    pub killer_team_id: Option<i32>,
+    pub item_id: Option<i32>,
+    pub skill_slot: Option<i32>,
+    pub monster_type: Option<String>,
+    pub monster_sub_type: Option<String>,
+    pub building_type: Option<String>,
+    pub lane_type: Option<String>,
+    pub tower_type: Option<String>,
+    pub position: Option<PositionDto>,
+}
+
+pub async fn match_v5_timeline(
+    riot_client: &RiotClient,
+    large_region: &LargeRegion,
+    match_id: &str,
+) -> Result<MatchV5Timeline, RiotApiError> {
+    let request_url = format!(
+        "https://{}.api.riotgames.com/lol/match/v5/matches/{}/timeline",
+        large_region, match_id
+    );
+    riot_client.request::<MatchV5Timeline>(&request_url).await
+}
 
 //pub async fn spectator_v5(puuid: &str) {}
 
@@ -497,22 +626,18 @@ pub struct SummonerV4 {
     pub summoner_level: u64,
 }
 pub async fn summoner_v4(
-    reqwest_client: Arc<reqwest::Client>,
-    riot_ratelimiters: RiotRatelimiters,
+    riot_client: &RiotClient,
     region: &Region,
     puuid: &str,
 ) -> Result<SummonerV4, RiotApiError> {
-    let _riot_api_key = env::var("RIOT_API_KEY")?;
     let request_url = format!(
-        "https://{}.api.riotgames.com/lol/summoner/v4/summoners/by-puuid/{}?api_key={}",
-        region, puuid, _riot_api_key
+        "https://{}.api.riotgames.com/lol/summoner/v4/summoners/by-puuid/{}",
+        region, puuid
     );
     //println!("summoner_v4 request_url: {}", request_url);
-    let summoner_v4 =
-        riot_request::<SummonerV4>(reqwest_client, riot_ratelimiters, &request_url).await;
-
-    //println!("{:?}", summoner_v4);
-    Ok(summoner_v4?)
+    riot_client
+        .request_for_puuid::<SummonerV4>(&request_url, puuid)
+        .await
 }
 
 //pub async fn summoner_v4(summoner_id: &str) {}
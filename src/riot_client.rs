@@ -0,0 +1,638 @@
+use actix_web::rt;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use http::Extensions;
+use http_cache_reqwest::{CACacheManager, Cache, CacheMode, HttpCache, HttpCacheOptions};
+use reqwest::{
+    header::{HeaderValue, CACHE_CONTROL, RETRY_AFTER, USER_AGENT},
+    Request, Response, StatusCode, Url,
+};
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware, Middleware, Next};
+use serde::de;
+use std::{
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::circuit_breaker::{CircuitBreaker, CircuitOpen};
+use crate::repository::UserRepository;
+use crate::riot_api::RiotApiError;
+use crate::riot_scheduler::{Priority, PriorityCell, RiotScheduler};
+
+/// Response header recording which api key fetched a response. It is stored
+/// with cached responses, so PUUIDs read from the cache can be pinned too.
+const KEY_ID_HEADER: &str = "x-lolepic-riot-key";
+
+/// Times a request answered 429 is sent again, once its key's Retry-After
+/// passed.
+const RATELIMITED_RETRIES: u32 = 3;
+/// Wait after a 429 without a Retry-After header.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Restricts a request to one api key, see [`RiotClient::request_for_puuid`].
+#[derive(Clone)]
+struct PinnedKey(String);
+
+/// Waits for the scheduler to hand out ratelimit and an api key. Sits behind
+/// the cache so cache hits are free.
+struct RatelimitMiddleware(Arc<RiotScheduler>);
+
+#[async_trait::async_trait]
+impl Middleware for RatelimitMiddleware {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let priority = extensions
+            .get::<PriorityCell>()
+            .cloned()
+            .unwrap_or(PriorityCell::new(Priority::Interactive));
+        let pinned = extensions.get::<PinnedKey>().map(|pinned| pinned.0.clone());
+        let mut ratelimited = 0;
+        loop {
+            let key = self
+                .0
+                .acquire(&priority, pinned.as_deref())
+                .await
+                .map_err(reqwest_middleware::Error::middleware)?;
+            let mut attempt = req.try_clone().expect("Riot requests have no body");
+            let token = HeaderValue::from_str(key.token())
+                .map_err(reqwest_middleware::Error::middleware)?;
+            attempt.headers_mut().insert("X-Riot-Token", token);
+
+            let mut res = next.clone().run(attempt, extensions).await?;
+            if res.status() == StatusCode::UNAUTHORIZED || res.status() == StatusCode::FORBIDDEN {
+                self.0.disable(key);
+                if pinned.is_none() {
+                    continue;
+                }
+            }
+            if res.status() == StatusCode::TOO_MANY_REQUESTS && ratelimited < RATELIMITED_RETRIES {
+                let retry_after = res
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|retry_after| retry_after.to_str().ok())
+                    .and_then(|retry_after| retry_after.parse().ok())
+                    .map_or(DEFAULT_RETRY_AFTER, Duration::from_secs);
+                self.0.back_off(key, retry_after);
+                ratelimited += 1;
+                continue;
+            }
+            if let Ok(key_id) = HeaderValue::from_str(&key.id) {
+                res.headers_mut().insert(KEY_ID_HEADER, key_id);
+            }
+            return Ok(res);
+        }
+    }
+}
+
+/// Fails fast while a host is down, so the cache above answers with what it
+/// has instead of every page waiting on Riot. Server errors and transport
+/// errors count as failures, the cache then serves its stale copy.
+struct CircuitBreakerMiddleware(Arc<CircuitBreaker>);
+
+#[async_trait::async_trait]
+impl Middleware for CircuitBreakerMiddleware {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let host = req.url().host_str().unwrap_or_default().to_string();
+        if !self.0.allow(&host) {
+            return Err(reqwest_middleware::Error::middleware(CircuitOpen(host)));
+        }
+        match next.run(req, extensions).await {
+            Ok(res) if res.status().is_server_error() => {
+                self.0.record_failure(&host);
+                Err(reqwest_middleware::Error::Reqwest(
+                    res.error_for_status().unwrap_err(),
+                ))
+            }
+            Ok(res) => {
+                self.0.record_success(&host);
+                Ok(res)
+            }
+            Err(e) => {
+                self.0.record_failure(&host);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// How long a response may be served from the cache, by endpoint.
+fn cache_ttl(path: &str) -> Option<Duration> {
+    const MINUTE: u64 = 60;
+    const HOUR: u64 = 60 * MINUTE;
+    const DAY: u64 = 24 * HOUR;
+    let seconds = if path.starts_with("/lol/match/v5/matches/by-puuid/") {
+        2 * MINUTE
+    } else if path.starts_with("/lol/match/v5/matches/") {
+        // Finished matches and their timelines never change
+        30 * DAY
+    } else if path.starts_with("/riot/account/v1/") {
+        6 * HOUR
+    } else if path.starts_with("/lol/summoner/v4/") {
+        HOUR
+    } else if path.starts_with("/lol/champion-mastery/v4/") {
+        10 * MINUTE
+    } else if path.starts_with("/lol/league/v4/") || path.starts_with("/lol/status/v4/") {
+        MINUTE
+    } else if path.starts_with("/lol/spectator/v5/") {
+        15
+    } else {
+        return None;
+    };
+    Some(Duration::from_secs(seconds))
+}
+
+/// Riot does not send usable cache headers, so set our own per endpoint
+/// before the response reaches the cache.
+struct CacheTtlMiddleware;
+
+#[async_trait::async_trait]
+impl Middleware for CacheTtlMiddleware {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let ttl = cache_ttl(req.url().path());
+        let mut res = next.run(req, extensions).await?;
+        if let (Some(ttl), true) = (ttl, res.status().is_success()) {
+            let cache_control = format!("public, max-age={}", ttl.as_secs());
+            if let Ok(cache_control) = HeaderValue::from_str(&cache_control) {
+                res.headers_mut().insert(CACHE_CONTROL, cache_control);
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// Body of a Riot response and the id of the api key that fetched it.
+struct RiotResponse {
+    body: Vec<u8>,
+    key_id: Option<String>,
+}
+
+type InFlightRequest = (
+    Shared<BoxFuture<'static, Result<Arc<RiotResponse>, Arc<reqwest_middleware::Error>>>>,
+    PriorityCell,
+);
+
+/// Shared handle to the Riot api. Cheap to clone, every clone shares the
+/// scheduler, the response cache and the requests currently in flight.
+#[derive(Clone)]
+pub struct RiotClient {
+    reqwest_client: Arc<ClientWithMiddleware>,
+    scheduler: Arc<RiotScheduler>,
+    circuit_breaker: Arc<CircuitBreaker>,
+    priority: Priority,
+    in_flight: Arc<Mutex<HashMap<String, InFlightRequest>>>,
+    /// PUUIDs are encrypted per api key, so remember which key produced each
+    puuid_keys: Arc<Mutex<HashMap<String, String>>>,
+    /// Where the key of a PUUID seen before this process started is looked up
+    key_pins: Option<Arc<dyn UserRepository>>,
+}
+
+/// Identifies a request independent of query parameter order.
+fn normalize_url(request_url: &str) -> String {
+    let Ok(mut url) = Url::parse(request_url) else {
+        return request_url.to_string();
+    };
+    let mut query: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    query.sort();
+    url.set_query(None);
+    if !query.is_empty() {
+        url.query_pairs_mut().extend_pairs(query);
+    }
+    url.to_string()
+}
+
+impl RiotClient {
+    /// `cache_dir` is where responses are cached on disk between restarts.
+    pub fn new(
+        reqwest_client: reqwest::Client,
+        scheduler: RiotScheduler,
+        circuit_breaker: CircuitBreaker,
+        cache_dir: PathBuf,
+    ) -> Self {
+        let scheduler = Arc::new(scheduler);
+        let circuit_breaker = Arc::new(circuit_breaker);
+        let cache = Cache(HttpCache {
+            mode: CacheMode::Default,
+            manager: CACacheManager { path: cache_dir },
+            options: HttpCacheOptions {
+                cache_key: Some(Arc::new(|parts: &http::request::Parts| {
+                    format!("{}:{}", parts.method, normalize_url(&parts.uri.to_string()))
+                })),
+                ..Default::default()
+            },
+        });
+        let reqwest_client = ClientBuilder::new(reqwest_client)
+            .with(cache)
+            .with(CacheTtlMiddleware)
+            .with(CircuitBreakerMiddleware(circuit_breaker.clone()))
+            .with(RatelimitMiddleware(scheduler.clone()))
+            .build();
+        RiotClient {
+            reqwest_client: Arc::new(reqwest_client),
+            scheduler,
+            circuit_breaker,
+            priority: Priority::Interactive,
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            puuid_keys: Arc::new(Mutex::new(HashMap::new())),
+            key_pins: None,
+        }
+    }
+
+    /// Looks up the api key of PUUIDs this process has not seen yet in the
+    /// stored users, see [`RiotClient::request_for_puuid`].
+    pub fn with_key_pins(self, users: Arc<dyn UserRepository>) -> RiotClient {
+        RiotClient {
+            key_pins: Some(users),
+            ..self
+        }
+    }
+
+    /// Id of the api key `puuid` was encrypted for, if this process fetched it.
+    /// Saved with the user so the key is still known after a restart.
+    pub fn key_for_puuid(&self, puuid: &str) -> Option<String> {
+        self.puuid_keys.lock().unwrap().get(puuid).cloned()
+    }
+
+    /// A handle whose requests are scheduled with `priority`.
+    pub fn with_priority(&self, priority: Priority) -> RiotClient {
+        RiotClient {
+            priority,
+            ..self.clone()
+        }
+    }
+
+    pub fn scheduler(&self) -> &RiotScheduler {
+        &self.scheduler
+    }
+
+    pub fn circuit_breaker(&self) -> &CircuitBreaker {
+        &self.circuit_breaker
+    }
+
+    /// Whether Riot is down for a routing value such as "euw1" or "europe",
+    /// meaning anything shown for it comes from the cache.
+    pub fn unavailable(&self, routing: &impl fmt::Display) -> bool {
+        self.circuit_breaker
+            .is_open(&format!("{}.api.riotgames.com", routing))
+    }
+
+    async fn fetch(
+        &self,
+        request_url: &str,
+        priority: PriorityCell,
+        pinned: Option<PinnedKey>,
+    ) -> Result<RiotResponse, reqwest_middleware::Error> {
+        let mut request = self
+            .reqwest_client
+            .get(request_url)
+            .with_extension(priority)
+            .header(USER_AGENT, "rust-web-api-client"); // gh api requires a user-agent header
+        if let Some(pinned) = pinned {
+            request = request.with_extension(pinned);
+        }
+        let response = request.send().await?.error_for_status()?;
+        let key_id = response
+            .headers()
+            .get(KEY_ID_HEADER)
+            .and_then(|key_id| key_id.to_str().ok())
+            .map(str::to_string);
+        let body = response.bytes().await?.to_vec();
+        Ok(RiotResponse { body, key_id })
+    }
+
+    /// GETs `request_url`. Identical requests already in flight share one
+    /// upstream call instead of spending ratelimit on a duplicate, and run at
+    /// the most urgent priority of everyone waiting on them. The upstream call
+    /// runs on its own, so it finishes and leaves `in_flight` even when every
+    /// waiter gave up.
+    async fn shared_request(
+        &self,
+        request_url: &str,
+        pinned: Option<PinnedKey>,
+    ) -> Result<Arc<RiotResponse>, RiotApiError> {
+        let request_key = normalize_url(request_url);
+        let in_flight_request = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&request_key) {
+                Some((in_flight_request, priority)) => {
+                    priority.raise(self.priority);
+                    in_flight_request.clone()
+                }
+                None => {
+                    let riot_client = self.clone();
+                    let request_url = request_url.to_string();
+                    let in_flight_key = request_key.clone();
+                    let priority = PriorityCell::new(self.priority);
+                    let request_priority = priority.clone();
+                    let in_flight_request = async move {
+                        let response = riot_client
+                            .fetch(&request_url, request_priority, pinned)
+                            .await
+                            .map(Arc::new)
+                            .map_err(Arc::new);
+                        riot_client.in_flight.lock().unwrap().remove(&in_flight_key);
+                        response
+                    }
+                    .boxed()
+                    .shared();
+                    in_flight.insert(request_key, (in_flight_request.clone(), priority));
+                    rt::spawn(in_flight_request.clone());
+                    in_flight_request
+                }
+            }
+        };
+        Ok(in_flight_request.await?)
+    }
+
+    /// GETs `request_url` with whichever api key has ratelimit to spare.
+    pub async fn request<T: de::DeserializeOwned>(
+        &self,
+        request_url: &str,
+    ) -> Result<T, RiotApiError> {
+        let response = self.shared_request(request_url, None).await?;
+        Ok(serde_json::from_slice(&response.body)?)
+    }
+
+    /// Like [`RiotClient::request`] for responses carrying a PUUID: the api key
+    /// that answered is remembered for later requests about that PUUID.
+    pub async fn request_puuid<T: de::DeserializeOwned>(
+        &self,
+        request_url: &str,
+        puuid: impl Fn(&T) -> &str,
+    ) -> Result<T, RiotApiError> {
+        let response = self.shared_request(request_url, None).await?;
+        let value: T = serde_json::from_slice(&response.body)?;
+        if let Some(key_id) = &response.key_id {
+            self.puuid_keys
+                .lock()
+                .unwrap()
+                .insert(puuid(&value).to_string(), key_id.clone());
+        }
+        Ok(value)
+    }
+
+    /// GETs `request_url` about `puuid` with the api key the PUUID was
+    /// encrypted for. Unknown PUUIDs go to the primary key.
+    pub async fn request_for_puuid<T: de::DeserializeOwned>(
+        &self,
+        request_url: &str,
+        puuid: &str,
+    ) -> Result<T, RiotApiError> {
+        let key_id = match self.key_for_puuid(puuid) {
+            Some(key_id) => Some(key_id),
+            None => self.stored_key(puuid).await,
+        }
+        .unwrap_or(self.scheduler.primary_key().id.clone());
+        let response = self
+            .shared_request(request_url, Some(PinnedKey(key_id)))
+            .await?;
+        Ok(serde_json::from_slice(&response.body)?)
+    }
+
+    /// Key saved with the user of `puuid`, remembered for the next requests.
+    async fn stored_key(&self, puuid: &str) -> Option<String> {
+        let users = self.key_pins.as_ref()?;
+        let key_id = match users.user_by_puuid(puuid).await {
+            Ok(user) => user?.riot_key_id?,
+            Err(e) => {
+                println!("could not look up the api key of {}: {:?}", puuid, e);
+                return None;
+            }
+        };
+        self.puuid_keys
+            .lock()
+            .unwrap()
+            .insert(puuid.to_string(), key_id.clone());
+        Some(key_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{rt::time, web, App, HttpResponse, HttpServer};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::riot_scheduler::{RiotKey, RiotRatelimits};
+
+    /// Serves `routes` on a free local port and returns its url.
+    fn serve<F>(routes: F) -> String
+    where
+        F: Fn(&mut web::ServiceConfig) + Send + Clone + 'static,
+    {
+        let server = HttpServer::new(move || App::new().configure(routes.clone()))
+            .workers(1)
+            .disable_signals()
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let url = format!("http://{}", server.addrs()[0]);
+        rt::spawn(server.run());
+        url
+    }
+
+    fn client(tokens: &[&str]) -> RiotClient {
+        static CACHES: AtomicUsize = AtomicUsize::new(0);
+        let cache_dir = std::env::temp_dir().join(format!(
+            "lolepic-riot-cache-{}-{}",
+            std::process::id(),
+            CACHES.fetch_add(1, Ordering::Relaxed)
+        ));
+        RiotClient::new(
+            reqwest::Client::new(),
+            RiotScheduler::new(
+                tokens
+                    .iter()
+                    .map(|token| RiotKey::new(token, RiotRatelimits::default()))
+                    .collect(),
+            ),
+            CircuitBreaker::new(5, Duration::from_secs(30)),
+            cache_dir,
+        )
+    }
+
+    #[actix_web::test]
+    async fn requests_finish_when_every_waiter_gave_up() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let url = serve({
+            let hits = hits.clone();
+            move |config| {
+                let hits = hits.clone();
+                config.route(
+                    "/slow",
+                    web::get().to(move || {
+                        let hits = hits.clone();
+                        async move {
+                            time::sleep(Duration::from_millis(200)).await;
+                            hits.fetch_add(1, Ordering::Relaxed);
+                            HttpResponse::Ok().body("1")
+                        }
+                    }),
+                );
+            }
+        });
+        let riot_client = client(&["key"]);
+        let request_url = format!("{}/slow", url);
+
+        // The handler waiting on it timed out
+        let waited = time::timeout(
+            Duration::from_millis(50),
+            riot_client.request::<i32>(&request_url),
+        )
+        .await;
+        assert!(waited.is_err());
+        assert_eq!(riot_client.in_flight.lock().unwrap().len(), 1);
+
+        time::sleep(Duration::from_millis(500)).await;
+        assert!(riot_client.in_flight.lock().unwrap().is_empty());
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+        // A new request is sent again rather than waiting on a dead entry
+        assert_eq!(riot_client.request::<i32>(&request_url).await.unwrap(), 1);
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+    }
+
+    /// Stands in for account-v1 and an endpoint taking an encrypted PUUID:
+    /// PUUIDs are encrypted for the key that asked, and only that key may use
+    /// them. `bad` is refused and `busy` answers 429 once.
+    fn fake_riot(hits: Arc<AtomicUsize>) -> String {
+        serve(move |config| {
+            let hits = hits.clone();
+            config.route(
+                "/{endpoint}/{argument}",
+                web::get().to(
+                    move |request: actix_web::HttpRequest, path: web::Path<(String, String)>| {
+                        let hits = hits.clone();
+                        async move {
+                            let hit = hits.fetch_add(1, Ordering::Relaxed);
+                            let token = request
+                                .headers()
+                                .get("X-Riot-Token")
+                                .unwrap()
+                                .to_str()
+                                .unwrap();
+                            let (endpoint, argument) = path.into_inner();
+                            match (endpoint.as_str(), token) {
+                                (_, "bad") => HttpResponse::Unauthorized().finish(),
+                                (_, "busy") if hit == 0 => HttpResponse::TooManyRequests()
+                                    .insert_header(("Retry-After", "1"))
+                                    .finish(),
+                                ("account", _) => HttpResponse::Ok().json(
+                                    serde_json::json!({"puuid": format!("{token}-{argument}")}),
+                                ),
+                                ("summoner", _) if argument.starts_with(&format!("{token}-")) => {
+                                    HttpResponse::Ok().json(argument)
+                                }
+                                _ => HttpResponse::BadRequest().finish(),
+                            }
+                        }
+                    },
+                ),
+            );
+        })
+    }
+
+    async fn look_up(riot_client: &RiotClient, url: &str, name: &str) -> String {
+        let account: serde_json::Value = riot_client
+            .request_puuid(
+                &format!("{}/account/{}", url, name),
+                |account: &serde_json::Value| account["puuid"].as_str().unwrap(),
+            )
+            .await
+            .unwrap();
+        account["puuid"].as_str().unwrap().to_string()
+    }
+
+    #[actix_web::test]
+    async fn puuid_requests_use_the_key_that_resolved_the_puuid() {
+        let url = fake_riot(Arc::new(AtomicUsize::new(0)));
+        let riot_client = client(&["first", "second"]);
+        let mut puuids = Vec::new();
+        for name in ["a", "b", "c", "d"] {
+            puuids.push(look_up(&riot_client, &url, name).await);
+        }
+        // Spread over both keys
+        assert!(puuids.iter().any(|puuid| puuid.starts_with("first-")));
+        assert!(puuids.iter().any(|puuid| puuid.starts_with("second-")));
+        for puuid in &puuids {
+            let summoner: String = riot_client
+                .request_for_puuid(&format!("{}/summoner/{}", url, puuid), puuid)
+                .await
+                .unwrap();
+            assert_eq!(&summoner, puuid);
+        }
+    }
+
+    #[actix_web::test]
+    async fn puuid_keys_are_read_from_stored_users_after_a_restart() {
+        let url = fake_riot(Arc::new(AtomicUsize::new(0)));
+        let store = crate::repository::Store::memory();
+        let account: crate::riot_api::AccountV1 = serde_json::from_value(
+            serde_json::json!({"puuid": "second-a", "gameName": "A", "tagLine": "EUW"}),
+        )
+        .unwrap();
+        let second = RiotKey::new("second", RiotRatelimits::default()).id;
+        store
+            .users
+            .upsert_account(&account, Some(&second))
+            .await
+            .unwrap();
+
+        let riot_client = client(&["first", "second"]).with_key_pins(store.users.clone());
+        let summoner: String = riot_client
+            .request_for_puuid(&format!("{}/summoner/second-a", url), "second-a")
+            .await
+            .unwrap();
+        assert_eq!(summoner, "second-a");
+        assert_eq!(riot_client.key_for_puuid("second-a"), Some(second));
+        // Unknown PUUIDs go to the primary key
+        let unknown = riot_client
+            .request_for_puuid::<String>(&format!("{}/summoner/second-b", url), "second-b")
+            .await;
+        assert!(unknown.is_err());
+    }
+
+    #[actix_web::test]
+    async fn refused_keys_are_skipped() {
+        let url = fake_riot(Arc::new(AtomicUsize::new(0)));
+        let riot_client = client(&["bad", "good"]);
+        for name in ["a", "b", "c"] {
+            assert_eq!(
+                look_up(&riot_client, &url, name).await,
+                format!("good-{}", name)
+            );
+        }
+        let bad = &riot_client.scheduler().primary_key().id;
+        assert!(riot_client
+            .scheduler()
+            .metrics()
+            .contains(&format!("riot_key_in_rotation{{key=\"{}\"}} 0", bad)));
+    }
+
+    #[actix_web::test]
+    async fn ratelimited_requests_are_sent_again_after_retry_after() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let url = fake_riot(hits.clone());
+        let riot_client = client(&["busy"]);
+        let start = time::Instant::now();
+        assert_eq!(look_up(&riot_client, &url, "a").await, "busy-a");
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+    }
+}
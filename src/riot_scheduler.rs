@@ -0,0 +1,582 @@
+use actix_web::rt::time::{sleep, Instant};
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use nonzero_ext::nonzero;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::VecDeque,
+    env,
+    fmt::Write,
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// How often a waiting request re-checks whether it may go.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Requests one api key may send, per second and per minute.
+#[derive(Clone, Copy, Debug)]
+pub struct RiotRatelimits {
+    pub per_second: NonZeroU32,
+    pub per_minute: NonZeroU32,
+}
+
+impl Default for RiotRatelimits {
+    fn default() -> Self {
+        RiotRatelimits {
+            per_second: nonzero!(20u32),
+            per_minute: nonzero!(120u32),
+        }
+    }
+}
+
+impl RiotRatelimits {
+    /// SHORT_RIOT_RATELIMIT_QUOTA per second and LONG_RIOT_RATELIMIT_QUOTA per
+    /// minute, the limits of a development key by default.
+    pub fn from_env() -> Self {
+        let quota = |name: &str, default: NonZeroU32| {
+            env::var(name)
+                .ok()
+                .and_then(|quota| quota.parse().ok())
+                .unwrap_or(default)
+        };
+        let default = RiotRatelimits::default();
+        RiotRatelimits {
+            per_second: quota("SHORT_RIOT_RATELIMIT_QUOTA", default.per_second),
+            per_minute: quota("LONG_RIOT_RATELIMIT_QUOTA", default.per_minute),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RiotRatelimiters {
+    short_ratelimit: Arc<DefaultDirectRateLimiter>,
+    long_ratelimit: Arc<DefaultDirectRateLimiter>,
+}
+
+impl RiotRatelimiters {
+    fn new(limits: RiotRatelimits) -> Self {
+        RiotRatelimiters {
+            short_ratelimit: Arc::new(RateLimiter::direct(Quota::per_second(limits.per_second))),
+            long_ratelimit: Arc::new(RateLimiter::direct(Quota::per_minute(limits.per_minute))),
+        }
+    }
+
+    /// Takes a slot from both limiters. The short one goes first since a slot
+    /// taken from it by a failed check is back within a second.
+    fn check(&self) -> bool {
+        self.short_ratelimit.check().is_ok() && self.long_ratelimit.check().is_ok()
+    }
+}
+
+/// Sliding window of recent background requests. Unlike a governor limiter it
+/// can be looked at without spending a slot.
+struct BackgroundBudget {
+    limit: usize,
+    window: Duration,
+    sent: Mutex<VecDeque<Instant>>,
+}
+
+impl BackgroundBudget {
+    fn has_room(&self) -> bool {
+        let mut sent = self.sent.lock().unwrap();
+        while sent
+            .front()
+            .is_some_and(|sent_at| sent_at.elapsed() > self.window)
+        {
+            sent.pop_front();
+        }
+        sent.len() < self.limit
+    }
+
+    fn record(&self) {
+        self.sent.lock().unwrap().push_back(Instant::now());
+    }
+}
+
+/// Who is waiting on a Riot request. Lower goes first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Someone is looking at a page
+    Interactive = 0,
+    /// Someone asked for fresh data and waits for it
+    Refresh = 1,
+    /// Crawlers and scheduled jobs
+    Background = 2,
+}
+
+impl Priority {
+    const ALL: [Priority; 3] = [
+        Priority::Interactive,
+        Priority::Refresh,
+        Priority::Background,
+    ];
+
+    fn from_u8(value: u8) -> Priority {
+        Priority::ALL[(value as usize).min(Priority::ALL.len() - 1)]
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Priority::Interactive => "interactive",
+            Priority::Refresh => "refresh",
+            Priority::Background => "background",
+        }
+    }
+}
+
+/// Priority of a request, raised when a more urgent caller starts waiting on
+/// the same in flight request.
+#[derive(Clone)]
+pub struct PriorityCell(Arc<AtomicU8>);
+
+impl PriorityCell {
+    pub fn new(priority: Priority) -> Self {
+        PriorityCell(Arc::new(AtomicU8::new(priority as u8)))
+    }
+
+    pub fn raise(&self, priority: Priority) {
+        self.0.fetch_min(priority as u8, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Priority {
+        Priority::from_u8(self.0.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Default)]
+struct ClassMetrics {
+    waiting: AtomicUsize,
+    requests: AtomicU64,
+    wait_micros: AtomicU64,
+    max_wait_micros: AtomicU64,
+}
+
+/// Name, type, help text and value of one metric.
+type MetricSeries = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&ClassMetrics) -> f64,
+);
+
+/// Counts a request as waiting in its class until dropped, so cancelled
+/// requests leave the queue too.
+struct Waiting<'a> {
+    scheduler: &'a RiotScheduler,
+    priority: Priority,
+}
+
+impl<'a> Waiting<'a> {
+    fn new(scheduler: &'a RiotScheduler, priority: Priority) -> Self {
+        scheduler
+            .class(priority)
+            .waiting
+            .fetch_add(1, Ordering::Relaxed);
+        Waiting {
+            scheduler,
+            priority,
+        }
+    }
+
+    fn move_to(&mut self, priority: Priority) {
+        self.scheduler
+            .class(self.priority)
+            .waiting
+            .fetch_sub(1, Ordering::Relaxed);
+        self.scheduler
+            .class(priority)
+            .waiting
+            .fetch_add(1, Ordering::Relaxed);
+        self.priority = priority;
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.scheduler
+            .class(self.priority)
+            .waiting
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SchedulerError {
+    #[error("No Riot api key left in rotation")]
+    NoUsableKey,
+    #[error("Riot api key {0} is not in rotation")]
+    PinnedKeyUnusable(String),
+}
+
+/// How long a key Riot refused stays out of rotation. Keys are refused while
+/// Riot rotates them or has trouble, so they are tried again after a while.
+const KEY_DISABLED_FOR: Duration = Duration::from_secs(10 * 60);
+
+/// One api key with its own ratelimits. Keys Riot refuses are taken out of
+/// rotation for [`KEY_DISABLED_FOR`].
+pub struct RiotKey {
+    /// Stable name for the key that is safe to log and cache
+    pub id: String,
+    token: String,
+    ratelimiters: RiotRatelimiters,
+    background_budget: BackgroundBudget,
+    disabled_until: Mutex<Option<Instant>>,
+    /// Set when Riot answered 429, nothing is sent with the key until then
+    backed_off_until: Mutex<Option<Instant>>,
+}
+
+impl RiotKey {
+    pub fn new(token: &str, limits: RiotRatelimits) -> Self {
+        // A hash that stays the same across Rust releases, ids end up in the cache
+        let digest = Sha256::digest(token.as_bytes());
+        RiotKey {
+            id: digest[..4]
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            token: token.to_string(),
+            ratelimiters: RiotRatelimiters::new(limits),
+            // 3/4 of the long ratelimit, the rest is reserved for page views
+            background_budget: BackgroundBudget {
+                limit: limits.per_minute.get() as usize * 3 / 4,
+                window: Duration::from_secs(60),
+                sent: Mutex::new(VecDeque::new()),
+            },
+            disabled_until: Mutex::new(None),
+            backed_off_until: Mutex::new(None),
+        }
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    fn usable(&self) -> bool {
+        self.disabled_until
+            .lock()
+            .unwrap()
+            .is_none_or(|until| until <= Instant::now())
+    }
+
+    fn try_take(&self, background: bool) -> bool {
+        if self
+            .backed_off_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| until > Instant::now())
+        {
+            return false;
+        }
+        if background && !self.background_budget.has_room() {
+            return false;
+        }
+        if !self.ratelimiters.check() {
+            return false;
+        }
+        if background {
+            self.background_budget.record();
+        }
+        true
+    }
+}
+
+/// Hands out the ratelimit budget of every api key by priority. A request only
+/// goes when nothing more urgent is waiting, and background work is held to its
+/// own, smaller, limit so there is always headroom left for page views.
+pub struct RiotScheduler {
+    keys: Vec<RiotKey>,
+    next_key: AtomicUsize,
+    classes: [ClassMetrics; 3],
+}
+
+impl RiotScheduler {
+    /// The first key is the primary one, see [`RiotScheduler::primary_key`].
+    pub fn new(keys: Vec<RiotKey>) -> Self {
+        assert!(!keys.is_empty(), "at least one Riot api key is needed");
+        RiotScheduler {
+            keys,
+            next_key: AtomicUsize::new(0),
+            classes: Default::default(),
+        }
+    }
+
+    /// Key for PUUIDs we do not know the origin of.
+    pub fn primary_key(&self) -> &RiotKey {
+        &self.keys[0]
+    }
+
+    /// Takes a key out of rotation for a while after Riot refused it.
+    pub fn disable(&self, key: &RiotKey) {
+        if key.usable() {
+            println!(
+                "riot key {} refused, out of rotation for {} minutes",
+                key.id,
+                KEY_DISABLED_FOR.as_secs() / 60
+            );
+        }
+        *key.disabled_until.lock().unwrap() = Some(Instant::now() + KEY_DISABLED_FOR);
+    }
+
+    /// Holds back every request of a key for `retry_after`, after Riot
+    /// answered that one of its limits was hit.
+    pub fn back_off(&self, key: &RiotKey, retry_after: Duration) {
+        println!(
+            "riot key {} hit a ratelimit, waiting {}s",
+            key.id,
+            retry_after.as_secs_f32()
+        );
+        let until = Instant::now() + retry_after;
+        let mut backed_off_until = key.backed_off_until.lock().unwrap();
+        *backed_off_until = Some(backed_off_until.map_or(until, |current| current.max(until)));
+    }
+
+    fn class(&self, priority: Priority) -> &ClassMetrics {
+        &self.classes[priority as usize]
+    }
+
+    fn more_urgent_waiting(&self, priority: Priority) -> bool {
+        Priority::ALL
+            .iter()
+            .filter(|other| **other < priority)
+            .any(|other| self.class(*other).waiting.load(Ordering::Relaxed) > 0)
+    }
+
+    fn try_acquire(
+        &self,
+        priority: Priority,
+        pinned: Option<&str>,
+    ) -> Result<Option<&RiotKey>, SchedulerError> {
+        let start = self.next_key.fetch_add(1, Ordering::Relaxed);
+        let mut candidates = (0..self.keys.len())
+            .map(|offset| &self.keys[(start + offset) % self.keys.len()])
+            .filter(|key| key.usable())
+            .filter(|key| pinned.is_none_or(|pinned| pinned == key.id))
+            .peekable();
+        if candidates.peek().is_none() {
+            return Err(match pinned {
+                Some(pinned) => SchedulerError::PinnedKeyUnusable(pinned.to_string()),
+                None => SchedulerError::NoUsableKey,
+            });
+        }
+        if self.more_urgent_waiting(priority) {
+            return Ok(None);
+        }
+        let background = priority == Priority::Background;
+        Ok(candidates.find(|key| key.try_take(background)))
+    }
+
+    /// Waits until a request of `priority` may be sent and returns the key to
+    /// send it with. `pinned` restricts the choice to one key.
+    pub async fn acquire(
+        &self,
+        priority: &PriorityCell,
+        pinned: Option<&str>,
+    ) -> Result<&RiotKey, SchedulerError> {
+        let start = Instant::now();
+        let mut waiting = Waiting::new(self, priority.get());
+        let key = loop {
+            let current = priority.get();
+            if current != waiting.priority {
+                waiting.move_to(current);
+            }
+            if let Some(key) = self.try_acquire(current, pinned)? {
+                break key;
+            }
+            sleep(POLL_INTERVAL).await;
+        };
+
+        let class = self.class(waiting.priority);
+        let waited = start.elapsed().as_micros() as u64;
+        class.requests.fetch_add(1, Ordering::Relaxed);
+        class.wait_micros.fetch_add(waited, Ordering::Relaxed);
+        class.max_wait_micros.fetch_max(waited, Ordering::Relaxed);
+        Ok(key)
+    }
+
+    /// Queue depth and wait times per priority in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let mut metrics = String::new();
+        let series: [MetricSeries; 4] = [
+            (
+                "riot_scheduler_queue_depth",
+                "gauge",
+                "Riot requests waiting for ratelimit",
+                |class| class.waiting.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "riot_scheduler_requests_total",
+                "counter",
+                "Riot requests let through",
+                |class| class.requests.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "riot_scheduler_wait_seconds_total",
+                "counter",
+                "Time Riot requests spent waiting",
+                |class| class.wait_micros.load(Ordering::Relaxed) as f64 / 1e6,
+            ),
+            (
+                "riot_scheduler_wait_seconds_max",
+                "gauge",
+                "Longest time a Riot request spent waiting",
+                |class| class.max_wait_micros.load(Ordering::Relaxed) as f64 / 1e6,
+            ),
+        ];
+        for (name, kind, help, value) in series {
+            let _ = writeln!(metrics, "# HELP {} {}", name, help);
+            let _ = writeln!(metrics, "# TYPE {} {}", name, kind);
+            for priority in Priority::ALL {
+                let _ = writeln!(
+                    metrics,
+                    "{}{{class=\"{}\"}} {}",
+                    name,
+                    priority.label(),
+                    value(self.class(priority))
+                );
+            }
+        }
+        let _ = writeln!(
+            metrics,
+            "# HELP riot_key_in_rotation Whether an api key is still used"
+        );
+        let _ = writeln!(metrics, "# TYPE riot_key_in_rotation gauge");
+        for key in &self.keys {
+            let _ = writeln!(
+                metrics,
+                "riot_key_in_rotation{{key=\"{}\"}} {}",
+                key.id,
+                key.usable() as u8
+            );
+        }
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::rt;
+
+    fn scheduler(per_minute: u32) -> RiotScheduler {
+        let limits = RiotRatelimits {
+            per_second: nonzero!(100u32),
+            per_minute: NonZeroU32::new(per_minute).unwrap(),
+        };
+        RiotScheduler::new(vec![RiotKey::new("key", limits)])
+    }
+
+    #[test]
+    fn requests_wait_while_more_urgent_ones_are_waiting() {
+        let scheduler = scheduler(120);
+        let page_view = Waiting::new(&scheduler, Priority::Interactive);
+        assert!(scheduler
+            .try_acquire(Priority::Background, None)
+            .unwrap()
+            .is_none());
+        assert!(scheduler
+            .try_acquire(Priority::Refresh, None)
+            .unwrap()
+            .is_none());
+        assert!(scheduler
+            .try_acquire(Priority::Interactive, None)
+            .unwrap()
+            .is_some());
+        drop(page_view);
+        assert!(scheduler
+            .try_acquire(Priority::Background, None)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn background_work_leaves_a_quarter_of_the_budget_to_page_views() {
+        let scheduler = scheduler(8);
+        let taken = |priority| {
+            (0..10)
+                .take_while(|_| scheduler.try_acquire(priority, None).unwrap().is_some())
+                .count()
+        };
+        assert_eq!(taken(Priority::Background), 6);
+        assert_eq!(taken(Priority::Interactive), 2);
+    }
+
+    #[test]
+    fn refused_keys_are_out_of_rotation_for_a_while() {
+        let scheduler = RiotScheduler::new(vec![
+            RiotKey::new("first", RiotRatelimits::default()),
+            RiotKey::new("second", RiotRatelimits::default()),
+        ]);
+        let first = scheduler.primary_key();
+        scheduler.disable(first);
+        for _ in 0..4 {
+            let key = scheduler.try_acquire(Priority::Interactive, None).unwrap();
+            assert_eq!(key.unwrap().token(), "second");
+        }
+        assert!(matches!(
+            scheduler.try_acquire(Priority::Interactive, Some(&first.id)),
+            Err(SchedulerError::PinnedKeyUnusable(_))
+        ));
+
+        // KEY_DISABLED_FOR later
+        *first.disabled_until.lock().unwrap() = Some(Instant::now());
+        let key = scheduler.try_acquire(Priority::Interactive, Some(&first.id));
+        assert_eq!(key.unwrap().unwrap().token(), "first");
+    }
+
+    #[test]
+    fn pinned_requests_only_use_their_key() {
+        let scheduler = RiotScheduler::new(vec![
+            RiotKey::new("first", RiotRatelimits::default()),
+            RiotKey::new("second", RiotRatelimits::default()),
+        ]);
+        let second = scheduler.keys[1].id.clone();
+        for _ in 0..4 {
+            let key = scheduler.try_acquire(Priority::Interactive, Some(&second));
+            assert_eq!(key.unwrap().unwrap().token(), "second");
+        }
+        assert!(matches!(
+            scheduler.try_acquire(Priority::Interactive, Some("unknown")),
+            Err(SchedulerError::PinnedKeyUnusable(_))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn keys_that_hit_a_ratelimit_wait_for_retry_after() {
+        let scheduler = scheduler(120);
+        scheduler.back_off(scheduler.primary_key(), POLL_INTERVAL * 4);
+        assert!(scheduler
+            .try_acquire(Priority::Interactive, None)
+            .unwrap()
+            .is_none());
+        let start = Instant::now();
+        let priority = PriorityCell::new(Priority::Interactive);
+        scheduler.acquire(&priority, None).await.unwrap();
+        assert!(start.elapsed() >= POLL_INTERVAL * 3);
+    }
+
+    #[actix_web::test]
+    async fn raising_the_priority_of_a_waiting_request_lets_it_go_first() {
+        let scheduler = Arc::new(scheduler(120));
+        let page_view = Waiting::new(&scheduler, Priority::Interactive);
+        let priority = PriorityCell::new(Priority::Background);
+        let request = rt::spawn({
+            let scheduler = scheduler.clone();
+            let priority = priority.clone();
+            async move { scheduler.acquire(&priority, None).await.is_ok() }
+        });
+        sleep(POLL_INTERVAL * 3).await;
+        assert!(!request.is_finished());
+        assert!(scheduler
+            .metrics()
+            .contains("riot_scheduler_queue_depth{class=\"background\"} 1"));
+
+        // A page view started waiting on the same request
+        priority.raise(Priority::Interactive);
+        assert!(request.await.unwrap());
+        drop(page_view);
+    }
+}